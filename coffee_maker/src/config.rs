@@ -0,0 +1,435 @@
+use std::{fs, path::Path, str::FromStr, time::Duration};
+
+use crate::orders::{OrderSource, RetryPolicy, Speedup};
+use serde::Deserialize;
+use tracing::Level;
+
+const DEFAULT_ORDERS: &str = "../assets/orders.csv";
+const DEFAULT_DISPENSERS: usize = 3;
+const DEFAULT_SUCCESS_CHANCE: f64 = 1.0;
+const DEFAULT_ORDER_MILLIS: u64 = 1000;
+const DEFAULT_TAKE_MILLIS: u64 = 1000;
+const DEFAULT_READ_TIMEOUT_MILLIS: u64 = 1000;
+const DEFAULT_LOG_LEVEL: &str = "trace";
+
+/// Settings of a coffee maker, read from a TOML file and overridden by the arguments.
+/// Every setting is optional, missing ones take their default value.
+/// Relative paths of the file are taken from its directory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The local server followed by the backups, tried in order.
+    pub servers: Vec<String>,
+    /// Where the orders are taken from, see `OrderSource::parse`.
+    /// In a store, only the machines without their own orders take them from here.
+    pub orders: Option<String>,
+    pub catalog: Option<String>,
+    pub promotions: Option<String>,
+    /// Path of the inventory model, the stock of the dispensers is unlimited if it is not given.
+    pub inventory: Option<String>,
+    pub dispensers: usize,
+    /// Coffee makers of the store, there is a single one with the settings above if none is given.
+    pub machines: Vec<MachineConfig>,
+    /// Path of the Unix socket where commands are taken, there is none if it is not given.
+    pub control: Option<String>,
+    pub timing: TimingConfig,
+    pub retry: RetryConfig,
+    pub failures: FailureConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    /// Orders of the machine alone, it shares the orders of the store if they are not given.
+    pub orders: Option<String>,
+    /// Dispensers of the machine, as many as the store default if they are not given.
+    pub dispensers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    /// Time it takes to brew an order.
+    pub order_millis: u64,
+    /// Time between orders taken from a file, the orders of other sources are taken as they arrive.
+    pub take_millis: u64,
+    /// Time to wait for each response of the server.
+    pub read_timeout_millis: u64,
+    /// Real time since an order is taken after which it is cancelled, orders have no deadline if it is not given.
+    pub order_deadline_millis: Option<u64>,
+    /// How many times faster than real time orders are taken and brewed, or `max`.
+    pub speedup: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: usize,
+    pub backoff_millis: u64,
+    pub max_backoff_millis: u64,
+    pub deadline_millis: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailureConfig {
+    pub success_chance: f64,
+    /// Seed of the random failures, a new one is drawn if it is not given.
+    pub seed: Option<u64>,
+    /// Path of the failure model.
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of `trace`, `debug`, `info`, `warn` or `error`.
+    pub level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            servers: Vec::new(),
+            orders: None,
+            catalog: None,
+            promotions: None,
+            inventory: None,
+            dispensers: DEFAULT_DISPENSERS,
+            machines: Vec::new(),
+            control: None,
+            timing: TimingConfig::default(),
+            retry: RetryConfig::default(),
+            failures: FailureConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            order_millis: DEFAULT_ORDER_MILLIS,
+            take_millis: DEFAULT_TAKE_MILLIS,
+            read_timeout_millis: DEFAULT_READ_TIMEOUT_MILLIS,
+            order_deadline_millis: None,
+            speedup: None,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig::from(RetryPolicy::default())
+    }
+}
+
+impl Default for FailureConfig {
+    fn default() -> Self {
+        FailureConfig {
+            success_chance: DEFAULT_SUCCESS_CHANCE,
+            seed: None,
+            model: None,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: DEFAULT_LOG_LEVEL.to_string(),
+        }
+    }
+}
+
+impl From<RetryPolicy> for RetryConfig {
+    fn from(policy: RetryPolicy) -> Self {
+        RetryConfig {
+            max_attempts: policy.max_attempts,
+            backoff_millis: policy.backoff.as_millis() as u64,
+            max_backoff_millis: policy.max_backoff.as_millis() as u64,
+            deadline_millis: policy.deadline.as_millis() as u64,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config of a TOML file.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&content, dir).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Parses the config of a TOML file in the given directory.
+    fn parse(content: &str, dir: &Path) -> Result<Self, toml::de::Error> {
+        let mut config: Config = toml::from_str(content)?;
+        config.resolve_paths(dir);
+        Ok(config)
+    }
+
+    /// Takes the relative paths of the config from the given directory.
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut String| *path = dir.join(&*path).to_string_lossy().into_owned();
+        let resolve_orders =
+            |orders: &mut String| *orders = OrderSource::parse(orders).relative_to(dir).to_string();
+
+        self.catalog.iter_mut().for_each(resolve);
+        self.promotions.iter_mut().for_each(resolve);
+        self.inventory.iter_mut().for_each(resolve);
+        self.failures.model.iter_mut().for_each(resolve);
+        self.control.iter_mut().for_each(resolve);
+        self.orders.iter_mut().for_each(resolve_orders);
+        self.machines
+            .iter_mut()
+            .filter_map(|machine| machine.orders.as_mut())
+            .for_each(resolve_orders);
+    }
+
+    /// Checks that every setting has a valid value.
+    ///
+    /// # Returns
+    ///
+    /// The first invalid setting, with the reason.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.servers.is_empty() {
+            return Err(String::from("servers: at least the local server is needed"));
+        }
+        if self.dispensers == 0 {
+            return Err(String::from("dispensers: must be at least 1"));
+        }
+        if let Some(machine) = self
+            .machines
+            .iter()
+            .position(|machine| machine.dispensers == Some(0))
+        {
+            return Err(format!(
+                "machines[{}].dispensers: must be at least 1",
+                machine
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.failures.success_chance) {
+            return Err(format!(
+                "failures.success_chance: must be between 0 and 1, not {}",
+                self.failures.success_chance
+            ));
+        }
+        if self.timing.read_timeout_millis == 0 {
+            return Err(String::from(
+                "timing.read_timeout_millis: must be greater than 0",
+            ));
+        }
+        if self.timing.order_deadline_millis == Some(0) {
+            return Err(String::from(
+                "timing.order_deadline_millis: must be greater than 0",
+            ));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(String::from("retry.max_attempts: must be at least 1"));
+        }
+        if self.retry.max_backoff_millis < self.retry.backoff_millis {
+            return Err(String::from(
+                "retry.max_backoff_millis: must not be less than retry.backoff_millis",
+            ));
+        }
+        self.speedup()
+            .map_err(|e| format!("timing.speedup: {}", e))?;
+        self.log_level()
+            .map_err(|e| format!("logging.level: {}", e))?;
+        Ok(())
+    }
+
+    /// Whether several machines run in the process.
+    pub fn is_store(&self) -> bool {
+        !self.machines.is_empty()
+    }
+
+    /// The machines of the store, with their own orders if they have them and their dispensers.
+    pub fn machines(&self) -> Vec<(Option<String>, usize)> {
+        if !self.is_store() {
+            return vec![(Some(self.orders().to_string()), self.dispensers)];
+        }
+        self.machines
+            .iter()
+            .map(|machine| {
+                (
+                    machine.orders.clone(),
+                    machine.dispensers.unwrap_or(self.dispensers),
+                )
+            })
+            .collect()
+    }
+
+    pub fn orders(&self) -> &str {
+        self.orders.as_deref().unwrap_or(DEFAULT_ORDERS)
+    }
+
+    pub fn speedup(&self) -> Result<Option<Speedup>, String> {
+        self.timing
+            .speedup
+            .as_deref()
+            .map(Speedup::parse)
+            .transpose()
+    }
+
+    pub fn log_level(&self) -> Result<Level, String> {
+        Level::from_str(&self.logging.level)
+            .map_err(|_| format!("Invalid level: {:?}", self.logging.level))
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry.max_attempts,
+            backoff: Duration::from_millis(self.retry.backoff_millis),
+            max_backoff: Duration::from_millis(self.retry.max_backoff_millis),
+            deadline: Duration::from_millis(self.retry.deadline_millis),
+        }
+    }
+
+    pub fn order_time(&self) -> Duration {
+        Duration::from_millis(self.timing.order_millis)
+    }
+
+    pub fn take_time(&self) -> Duration {
+        Duration::from_millis(self.timing.take_millis)
+    }
+
+    pub fn order_deadline(&self) -> Option<Duration> {
+        self.timing.order_deadline_millis.map(Duration::from_millis)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.timing.read_timeout_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        Config::parse(content, Path::new(""))
+    }
+
+    /// Setting that is made invalid, and how.
+    type Invalidation = (&'static str, fn(&mut Config));
+
+    fn valid() -> Config {
+        Config {
+            servers: vec![String::from("localhost:9000")],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn read_example_config() {
+        let config = Config::read("../assets/coffee_maker-example.toml").unwrap();
+        assert_eq!(config.servers, vec!["localhost:9000", "localhost:9001"]);
+        assert_eq!(config.orders(), "../assets/orders.csv");
+        assert_eq!(config.catalog.as_deref(), Some("../assets/catalog.csv"));
+        assert_eq!(
+            config.failures.model.as_deref(),
+            Some("../assets/failures-example.csv")
+        );
+        assert_eq!(config.failures.success_chance, 0.9);
+        assert_eq!(config.retry_policy(), RetryPolicy::default());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn missing_settings_take_their_default() {
+        let config = parse("[timing]\norder_millis = 10").unwrap();
+        assert_eq!(config.order_time(), Duration::from_millis(10));
+        assert_eq!(
+            config.take_time(),
+            Duration::from_millis(DEFAULT_TAKE_MILLIS)
+        );
+        assert_eq!(config.orders(), DEFAULT_ORDERS);
+        assert_eq!(config.dispensers, DEFAULT_DISPENSERS);
+        assert!(!config.is_store());
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(parse("colour = \"black\"").is_err());
+        assert!(parse("[timing]\nspeed = 2").is_err());
+        assert!(parse("[[machines]]\nservers = []").is_err());
+        assert!(parse("dispensers = \"three\"").is_err());
+    }
+
+    #[test]
+    fn paths_are_taken_from_the_config_directory() {
+        let content = r#"
+            orders = "tail:orders.csv"
+            promotions = "/etc/promotions.csv"
+            inventory = "inventory.csv"
+            control = "coffee.sock"
+
+            [[machines]]
+            orders = "tcp:9000"
+
+            [[machines]]
+            orders = "gen:load.toml"
+        "#;
+        let config = Config::parse(content, Path::new("store")).unwrap();
+        assert_eq!(config.orders(), "tail:store/orders.csv");
+        assert_eq!(config.promotions.as_deref(), Some("/etc/promotions.csv"));
+        assert_eq!(config.inventory.as_deref(), Some("store/inventory.csv"));
+        assert_eq!(config.control.as_deref(), Some("store/coffee.sock"));
+        assert_eq!(
+            config.machines(),
+            vec![
+                (Some(String::from("tcp:localhost:9000")), DEFAULT_DISPENSERS),
+                (
+                    Some(String::from("gen:store/load.toml")),
+                    DEFAULT_DISPENSERS
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        let invalid: [Invalidation; 10] = [
+            ("servers", |config| config.servers.clear()),
+            ("dispensers", |config| config.dispensers = 0),
+            ("machines[1].dispensers", |config| {
+                config.machines = vec![
+                    MachineConfig::default(),
+                    MachineConfig {
+                        orders: None,
+                        dispensers: Some(0),
+                    },
+                ]
+            }),
+            ("failures.success_chance", |config| {
+                config.failures.success_chance = 1.5
+            }),
+            ("timing.read_timeout_millis", |config| {
+                config.timing.read_timeout_millis = 0
+            }),
+            ("timing.order_deadline_millis", |config| {
+                config.timing.order_deadline_millis = Some(0)
+            }),
+            ("retry.max_attempts", |config| config.retry.max_attempts = 0),
+            ("retry.max_backoff_millis", |config| {
+                config.retry.max_backoff_millis = config.retry.backoff_millis - 1
+            }),
+            ("timing.speedup", |config| {
+                config.timing.speedup = Some(String::from("fast"))
+            }),
+            ("logging.level", |config| {
+                config.logging.level = String::from("loud")
+            }),
+        ];
+
+        assert_eq!(valid().validate(), Ok(()));
+        for (setting, invalidate) in invalid {
+            let mut config = valid();
+            invalidate(&mut config);
+            let e = config.validate().unwrap_err();
+            assert!(e.starts_with(&format!("{}:", setting)), "{}", e);
+        }
+    }
+}
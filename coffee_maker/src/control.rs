@@ -0,0 +1,199 @@
+use std::{rc::Rc, time::Duration};
+
+use crate::{
+    orders::{remove_stale_socket, Ticket},
+    store::Machine,
+};
+use actix_rt::time::sleep;
+use futures::future::join_all;
+use points::{Fields, ParseError, ParseErrorReason};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{error, info, warn};
+
+/// Command that cancels an order by its ticket.
+const CANCEL_COMMAND: &str = "CANCEL";
+/// Command that fills every dispenser of the store.
+const REFILL_COMMAND: &str = "REFILL";
+/// Reason of the orders cancelled by a command that does not give one.
+const DEFAULT_CANCEL_REASON: &str = "cancelled by the operator";
+/// Wait before accepting connections again after a failure, doubled on each failure in a row.
+const ACCEPT_RETRY_MIN_MILLIS: u64 = 10;
+const ACCEPT_RETRY_MAX_MILLIS: u64 = 1000;
+
+/// A command to the running coffee maker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Cancels the order with the ticket, for the reason given.
+    Cancel(Ticket, String),
+    /// Fills every dispenser up to its capacity.
+    Refill,
+}
+
+impl Command {
+    /// Parses a command with the format `CANCEL,<ticket>[,<reason>]` or `REFILL`.
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut fields = Fields::new(line);
+        let (column, command) = fields.next_or_missing("command")?;
+        match command {
+            CANCEL_COMMAND => {
+                let (column, ticket) = fields.next_or_missing("ticket")?;
+                let ticket = ticket.parse::<Ticket>().map_err(|_| {
+                    ParseError::new(column, ParseErrorReason::InvalidTicket(ticket.to_string()))
+                })?;
+                let reason = match fields.next() {
+                    Some((_, reason)) if !reason.is_empty() => reason.to_string(),
+                    _ => DEFAULT_CANCEL_REASON.to_string(),
+                };
+                fields.expect_end()?;
+                Ok(Command::Cancel(ticket, reason))
+            }
+            REFILL_COMMAND => {
+                fields.expect_end()?;
+                Ok(Command::Refill)
+            }
+            _ => Err(ParseError::new(
+                column,
+                ParseErrorReason::UnknownCommand(command.to_string()),
+            )),
+        }
+    }
+
+    /// Runs the command on the machines of the store.
+    ///
+    /// # Returns
+    ///
+    /// The answer to whoever sent the command.
+    async fn run(self, machines: &[Machine]) -> String {
+        match self {
+            Command::Cancel(ticket, reason) => {
+                let cancelling = machines
+                    .iter()
+                    .map(|machine| machine.cancel(ticket, &reason));
+                match join_all(cancelling).await.into_iter().sum::<usize>() {
+                    0 => format!("Order #{} is not queued nor brewing", ticket),
+                    _ => format!("Order #{} cancelled", ticket),
+                }
+            }
+            Command::Refill => {
+                machines.iter().for_each(Machine::refill);
+                String::from("Dispensers refilled")
+            }
+        }
+    }
+}
+
+/// Takes commands on a Unix socket, a line per command, answering each one with a line.
+/// Connections are handled by their own tasks until the process exits.
+pub fn listen(path: &str, machines: Vec<Machine>) -> Result<(), String> {
+    remove_stale_socket(path);
+    let listener =
+        UnixListener::bind(path).map_err(|e| format!("Could not listen on {}: {}", path, e))?;
+    info!("Taking commands on {}", path);
+
+    let machines = Rc::new(machines);
+    actix_rt::spawn(async move {
+        let mut delay = Duration::from_millis(ACCEPT_RETRY_MIN_MILLIS);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    delay = Duration::from_millis(ACCEPT_RETRY_MIN_MILLIS);
+                    actix_rt::spawn(serve(stream, machines.clone()));
+                }
+                Err(e) => {
+                    error!(
+                        "Could not accept connection, trying again in {:?}: {}",
+                        delay, e
+                    );
+                    sleep(delay).await;
+                    delay = (delay * 2).min(Duration::from_millis(ACCEPT_RETRY_MAX_MILLIS));
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Runs the commands of a connection until it is closed.
+async fn serve(stream: UnixStream, machines: Rc<Vec<Machine>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match Command::parse(&line) {
+            Ok(command) => {
+                info!("Running {:?}", command);
+                command.run(&machines).await
+            }
+            Err(e) => {
+                warn!("Invalid command {:?}: {}", line, e);
+                e.to_string()
+            }
+        };
+        if writer
+            .write_all(format!("{}\n", answer).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(
+            Command::parse("CANCEL, 12, client left"),
+            Ok(Command::Cancel(12, "client left".to_string()))
+        );
+        assert_eq!(
+            Command::parse("CANCEL,12"),
+            Ok(Command::Cancel(12, DEFAULT_CANCEL_REASON.to_string()))
+        );
+        assert_eq!(Command::parse(" REFILL "), Ok(Command::Refill));
+    }
+
+    #[test]
+    fn invalid_command() {
+        assert_eq!(
+            Command::parse("BREW,12"),
+            Err(ParseError::new(
+                1,
+                ParseErrorReason::UnknownCommand("BREW".to_string())
+            ))
+        );
+        assert_eq!(
+            Command::parse("CANCEL,-1"),
+            Err(ParseError::new(
+                8,
+                ParseErrorReason::InvalidTicket("-1".to_string())
+            ))
+        );
+        assert_eq!(
+            Command::parse("CANCEL"),
+            Err(ParseError::new(7, ParseErrorReason::MissingField("ticket")))
+        );
+        assert_eq!(
+            Command::parse("REFILL,all"),
+            Err(ParseError::new(
+                8,
+                ParseErrorReason::UnexpectedField("all".to_string())
+            ))
+        );
+        assert_eq!(
+            Command::parse("CANCEL,1,late,again"),
+            Err(ParseError::new(
+                15,
+                ParseErrorReason::UnexpectedField("again".to_string())
+            ))
+        );
+    }
+}
@@ -1,15 +1,56 @@
+mod config;
+mod control;
 mod orders;
-use std::sync::{Arc, Barrier};
+mod store;
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
 
-use actix::prelude::*;
+use actix_rt::signal;
+use config::{Config, MachineConfig, RetryConfig};
+use futures::future::{join_all, select, Either};
+use load_generator::args::{parse_number, replay_message, take_flag};
 use orders::*;
-use points::parse_addr;
+use points::{parse_addr, Catalog, Promotions};
 use std::process::exit;
-use tracing::{error, trace, Level};
+use store::Store;
+use tracing::{error, info, trace, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-const DISPENSERS: usize = 3;
-const DEFAULT_ORDERS: &str = "../assets/orders.csv";
+const DEFAULT_CATALOG: &str = "../assets/catalog.csv";
+const DEAD_LETTER_SUFFIX: &str = ".rejected";
+/// Suffix of the file where the summary of the run is written, next to the orders.
+const REPORT_SUFFIX: &str = ".report.json";
+const EXIT_ORDERS_FAILED: i32 = 1;
+const EXIT_POINTS_LEFT_LOCKED: i32 = 2;
+/// Flag followed by the path of the TOML config, the arguments override its settings.
+const CONFIG_FLAG: &str = "--config";
+/// Flag followed by the path of the product catalog.
+const CATALOG_FLAG: &str = "--catalog";
+/// Flag followed by the path of the promotion rules, there are none if it is not given.
+const PROMOTIONS_FLAG: &str = "--promotions";
+/// Flag followed by how many times faster than real time orders are taken and brewed, or `max`.
+const SPEEDUP_FLAG: &str = "--speedup";
+/// Flag followed by the seed of the random failures, a new one is drawn and logged if it is not given.
+const SEED_FLAG: &str = "--seed";
+/// Flag followed by the servers to fail over to when the local one is unreachable, separated by commas.
+const BACKUPS_FLAG: &str = "--backups";
+/// Flag followed by the retry policy of the requests that fail for transient reasons.
+const RETRY_FLAG: &str = "--retry";
+/// Flag followed by the path of the failure model, orders only fail by the success chance if it is not given.
+const FAILURES_FLAG: &str = "--failures";
+/// Flag followed by the path of the inventory model, the stock of the dispensers is unlimited if it is not given.
+const INVENTORY_FLAG: &str = "--inventory";
+/// Reason of the orders cancelled by a stop signal while they finish.
+const STOP_REASON: &str = "coffee maker stopped";
+/// Flag followed by the milliseconds since an order is taken after which it is cancelled.
+const ORDER_DEADLINE_FLAG: &str = "--order-deadline";
+/// Flag followed by the amount of dispensers.
+const DISPENSERS_FLAG: &str = "--dispensers";
+/// Flag followed by the amount of coffee makers of the store, the ones not configured share the orders.
+const MACHINES_FLAG: &str = "--machines";
+/// Flag followed by the most verbose level that is logged.
+const LOG_LEVEL_FLAG: &str = "--log-level";
+/// Flag followed by the path of the Unix socket where commands are taken, like cancelling an order.
+const CONTROL_FLAG: &str = "--control";
 
 enum Arguments {
     LocalServer = 1,
@@ -20,81 +61,374 @@ enum Arguments {
 // Result with any error
 type Res = Result<(), Box<dyn std::error::Error>>;
 
-fn parse_args() -> (String, String, f64) {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 2 {
-        return (
-            parse_addr(args[Arguments::LocalServer as usize].clone()),
-            DEFAULT_ORDERS.to_string(),
-            DEFAULT_SUCCESS_CHANCE,
-        );
+/// Loads the file at the given path, or at the default one if it exists.
+/// Files that are not given and do not exist are loaded as empty.
+fn load_file<T: Default, E: ToString>(
+    path: Option<String>,
+    default_path: Option<&str>,
+    read: fn(BufReader<File>) -> Result<T, E>,
+) -> T {
+    let path = match (path, default_path) {
+        (Some(path), _) => path,
+        (None, Some(default_path)) if Path::new(default_path).exists() => default_path.to_string(),
+        _ => return T::default(),
+    };
+
+    let loaded = File::open(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| read(BufReader::new(file)).map_err(|e| e.to_string()));
+    match loaded {
+        Ok(loaded) => {
+            info!("Loaded {}", path);
+            loaded
+        }
+        Err(e) => {
+            error!("Invalid file {}: {}", path, e);
+            exit(-1);
+        }
     }
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 3 {
-        return (
-            parse_addr(args[Arguments::LocalServer as usize].clone()),
-            args[Arguments::Orders as usize].clone(),
-            DEFAULT_SUCCESS_CHANCE,
-        );
+}
+
+/// Reads the config, if one is given, and overrides it with the rest of the arguments.
+fn parse_args() -> Result<Config, String> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut config = match take_flag(&mut args, CONFIG_FLAG)? {
+        Some(path) => Config::read(&path)?,
+        None => Config::default(),
+    };
+
+    if let Some(catalog) = take_flag(&mut args, CATALOG_FLAG)? {
+        config.catalog = Some(catalog);
+    }
+    if let Some(promotions) = take_flag(&mut args, PROMOTIONS_FLAG)? {
+        config.promotions = Some(promotions);
+    }
+    if let Some(speedup) = take_flag(&mut args, SPEEDUP_FLAG)? {
+        config.timing.speedup = Some(speedup);
+    }
+    if let Some(seed) = take_flag(&mut args, SEED_FLAG)? {
+        config.failures.seed = Some(parse_number(&seed, "seed")?);
+    }
+    if let Some(failures) = take_flag(&mut args, FAILURES_FLAG)? {
+        config.failures.model = Some(failures);
+    }
+    if let Some(inventory) = take_flag(&mut args, INVENTORY_FLAG)? {
+        config.inventory = Some(inventory);
+    }
+    if let Some(retry) = take_flag(&mut args, RETRY_FLAG)? {
+        config.retry = RetryConfig::from(RetryPolicy::parse(&retry, config.retry_policy())?);
+    }
+    if let Some(deadline) = take_flag(&mut args, ORDER_DEADLINE_FLAG)? {
+        config.timing.order_deadline_millis = Some(parse_number(&deadline, "deadline")?);
     }
-    if args.len() == 4 {
-        return (
-            parse_addr(args[Arguments::LocalServer as usize].clone()),
-            args[Arguments::Orders as usize].clone(),
-            args[Arguments::SuccessChance as usize]
-                .parse::<f64>()
-                .unwrap(),
+    if let Some(dispensers) = take_flag(&mut args, DISPENSERS_FLAG)? {
+        config.dispensers = parse_number(&dispensers, "dispensers")?;
+    }
+    if let Some(machines) = take_flag(&mut args, MACHINES_FLAG)? {
+        let machines: usize = parse_number(&machines, "machines")?;
+        if machines == 0 {
+            return Err(String::from("There must be at least one machine"));
+        }
+        config.machines.resize(machines, MachineConfig::default());
+    }
+    if let Some(level) = take_flag(&mut args, LOG_LEVEL_FLAG)? {
+        config.logging.level = level;
+    }
+    if let Some(control) = take_flag(&mut args, CONTROL_FLAG)? {
+        config.control = Some(control);
+    }
+    let backups = take_flag(&mut args, BACKUPS_FLAG)?;
+
+    if args.len() > Arguments::SuccessChance as usize + 1 {
+        return Err(String::from("Too many arguments"));
+    }
+    if let Some(local_server) = args.get(Arguments::LocalServer as usize) {
+        let local_server = parse_addr(local_server.clone());
+        match config.servers.first_mut() {
+            Some(server) => *server = local_server,
+            None => config.servers.push(local_server),
+        }
+    }
+    if let Some(orders) = args.get(Arguments::Orders as usize) {
+        config.orders = Some(orders.clone());
+    }
+    if let Some(success_chance) = args.get(Arguments::SuccessChance as usize) {
+        config.failures.success_chance = parse_number(success_chance, "success chance")?;
+    }
+    if let Some(backups) = backups {
+        config.servers.truncate(1);
+        config.servers.extend(
+            backups
+                .split(',')
+                .map(|backup| parse_addr(backup.trim().to_string())),
         );
     }
-    error!("Usage: coffee_maker <local_server> [<orders>] [<success_chance>]");
-    exit(-1);
+
+    config.validate()?;
+    Ok(config)
+}
+
+fn usage() -> String {
+    format!(
+        "Usage: coffee_maker [<local_server>] [<orders>] [<success_chance>] [{} <config>] [{} <catalog>] [{} <promotions>] [{} <factor|max>] [{} <seed>] [{} <failures>] [{} <inventory>] [{} <server>[,<server>...]] [{} <attempts>[,<backoff_millis>[,<deadline_millis>]]] [{} <millis>] [{} <dispensers>] [{} <machines>] [{} <level>] [{} <socket>]",
+        CONFIG_FLAG,
+        CATALOG_FLAG,
+        PROMOTIONS_FLAG,
+        SPEEDUP_FLAG,
+        SEED_FLAG,
+        FAILURES_FLAG,
+        INVENTORY_FLAG,
+        BACKUPS_FLAG,
+        RETRY_FLAG,
+        ORDER_DEADLINE_FLAG,
+        DISPENSERS_FLAG,
+        MACHINES_FLAG,
+        LOG_LEVEL_FLAG,
+        CONTROL_FLAG
+    )
 }
 
 #[actix_rt::main]
 async fn main() -> Res {
+    // Logging is configured by the arguments, so their errors are printed directly
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, usage());
+            exit(-1);
+        }
+    };
+
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
+        .with_max_level(config.log_level().unwrap_or(Level::TRACE))
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let (local_server_addr, orders_path, success_chance) = parse_args();
+    let catalog = Arc::new(load_file(
+        config.catalog.clone(),
+        Some(DEFAULT_CATALOG),
+        Catalog::read,
+    ));
+    let clock: Arc<dyn Clock> = match config.speedup().ok().flatten() {
+        Some(speedup) => Arc::new(VirtualClock::new(speedup)),
+        None => Arc::new(RealClock),
+    };
+    let seed = config.failures.seed.unwrap_or_else(random_seed);
+    let lifecycle = Arc::new(Lifecycle::default());
+    info!("{}", replay_message(SEED_FLAG, seed));
 
-    let point_storage = SyncArbiter::start(1, move || {
-        PointStorage::new(local_server_addr.clone()).unwrap()
-    });
+    let store = Store {
+        servers: config.servers.clone(),
+        read_timeout: config.read_timeout(),
+        promotions: load_file(config.promotions.clone(), None, Promotions::read),
+        clock,
+        failures: Arc::new(load_file(
+            config.failures.model.clone(),
+            None,
+            FailureModel::read,
+        )),
+        inventory: Arc::new(load_file(
+            config.inventory.clone(),
+            None,
+            InventoryModel::read,
+        )),
+        seed,
+        success_chance: config.failures.success_chance,
+        order_time: config.order_time(),
+        take_time: config.take_time(),
+        retry: Arc::new(config.retry_policy()),
+        order_deadline: config.order_deadline(),
+        lifecycle: lifecycle.clone(),
+        tickets: Default::default(),
+    };
 
-    let order_handler = SyncArbiter::start(DISPENSERS, move || OrderHandler {
-        point_storage: point_storage.clone(),
-        success_chance,
-    });
+    // Machines without orders of their own share the ones of the store
+    let store_source = OrderSource::parse(config.orders());
+    let store_clock = store.clock.fork();
+    let mut shared = None;
+    let mut machines = Vec::new();
+    let mut orders = Vec::new();
+    for (id, (own_orders, dispensers)) in config.machines().into_iter().enumerate() {
+        let (source, clock) = match own_orders {
+            Some(own_orders) => {
+                let source = OrderSource::parse(&own_orders);
+                let clock = store.clock.fork();
+                orders.push(open_orders(&source, &catalog, &clock, &store));
+                (source, clock)
+            }
+            None => {
+                let shared = shared.get_or_insert_with(|| {
+                    SharedOrders::new(open_orders(&store_source, &catalog, &store_clock, &store))
+                });
+                orders.push(Box::new(shared.clone()));
+                (store_source.clone(), store_clock.clone())
+            }
+        };
+        // Orders that arrive at their own pace are taken as they arrive, at the time of their source
+        let paced_by = source.paces_orders().then_some(clock);
 
-    let order_handler_clone = order_handler.clone();
-    let order_taker = SyncArbiter::start(1, move || OrderTaker {
-        handler: order_handler_clone.clone(),
-    });
+        let (dead_letter_path, report_path) =
+            output_paths(&source, config.is_store().then_some(id));
+        machines.push((
+            store.start_machine(id, dispensers, paced_by, dead_letter_path),
+            report_path,
+        ));
+    }
+    if config.is_store() {
+        info!("Running a store of {} machines", machines.len());
+    }
+    if let Some(control) = &config.control {
+        let controlled = machines.iter().map(|(machine, _)| machine.clone());
+        if let Err(e) = control::listen(control, controlled.collect()) {
+            error!("{}", e);
+            exit(-1);
+        }
+    }
 
-    order_taker.send(TakeOrders(orders_path)).await?;
+    let taking = join_all(
+        machines
+            .iter()
+            .zip(orders)
+            .map(|((machine, _), orders)| machine.take(orders)),
+    );
+    let taken = match select(Box::pin(taking), Box::pin(stop_signal())).await {
+        Either::Left((taken, _)) => taken,
+        Either::Right((_, taking)) => {
+            warn!("Stop signal received");
+            lifecycle.stop();
+            taking.await
+        }
+    };
+    taken.into_iter().collect::<Result<Vec<()>, _>>()?;
 
-    handle_stop(order_handler, DISPENSERS).await?;
+    // A signal while the orders in flight finish cancels them, freeing their points
+    let draining = handle_stop(lifecycle);
+    match select(Box::pin(draining), Box::pin(stop_signal())).await {
+        Either::Left((drained, _)) => drained?,
+        Either::Right((_, draining)) => {
+            warn!("Stop signal received while finishing the orders in flight, cancelling them");
+            for (machine, _) in &machines {
+                machine.cancel_all(STOP_REASON);
+            }
+            draining.await?;
+        }
+    }
+    let mut code = 0;
+    for (id, (machine, report_path)) in machines.iter().enumerate() {
+        if config.is_store() {
+            println!("Machine {}", id);
+        }
+        machine.report_stock();
+        let summary = machine.report.summary();
+        write_report(&summary, report_path);
+        code = code.max(exit_code(&summary));
+    }
 
-    Ok(())
+    exit(code);
 }
 
-async fn handle_stop(order_handler: Addr<OrderHandler>, threads: usize) -> Res {
-    let stop_barrier = Arc::new(Barrier::new(threads));
-    for i in 0..threads {
-        let stop_barrier = stop_barrier.clone();
-        order_handler
-            .try_send(WaitStop(Some(stop_barrier)))
-            .unwrap();
-        trace!("Sent stop signal to handler {}", i);
+/// Opens a source of orders with its clock, the coffee maker can not run without it.
+fn open_orders(
+    source: &OrderSource,
+    catalog: &Arc<Catalog>,
+    clock: &Arc<dyn Clock>,
+    store: &Store,
+) -> Orders {
+    match source.open(catalog.clone(), clock.clone(), store.lifecycle.clone()) {
+        Ok(orders) => orders,
+        Err(e) => {
+            error!("{}", e);
+            exit(-1);
+        }
     }
+}
 
-    order_handler.send(WaitStop(None)).await?;
+/// Paths of the dead letter file and the report of a machine, next to its orders.
+/// Files of the machines of a store are kept apart, even when they share the orders.
+fn output_paths(source: &OrderSource, machine: Option<usize>) -> (String, String) {
+    let output_path = match machine {
+        Some(id) => format!("{}.machine-{}", source.output_path(), id),
+        None => source.output_path(),
+    };
+    (
+        format!("{}{}", output_path, DEAD_LETTER_SUFFIX),
+        format!("{}{}", output_path, REPORT_SUFFIX),
+    )
+}
+
+/// Resolves when the process is asked to stop with SIGINT or SIGTERM.
+async fn stop_signal() {
+    let terminate = signal::unix::signal(signal::unix::SignalKind::terminate());
+    match terminate {
+        Ok(mut terminate) => {
+            select(Box::pin(signal::ctrl_c()), Box::pin(terminate.recv())).await;
+        }
+        Err(e) => {
+            error!("Could not listen for SIGTERM: {}", e);
+            let _ = signal::ctrl_c().await;
+        }
+    }
+}
+
+/// Waits for every order in flight to have its points committed or freed.
+async fn handle_stop(lifecycle: Arc<Lifecycle>) -> Res {
+    info!("Waiting for {} orders in flight", lifecycle.in_flight());
+    actix_rt::task::spawn_blocking(move || lifecycle.wait_drained()).await?;
+    trace!("All orders done");
 
     Ok(())
 }
 
+/// Exit status of a run: 0 if every order succeeded, `EXIT_ORDERS_FAILED` if some failed
+/// and `EXIT_POINTS_LEFT_LOCKED` if the points of some order could not be committed nor freed.
+fn exit_code(report: &Report) -> i32 {
+    if report.orders.left_locked > 0 {
+        EXIT_POINTS_LEFT_LOCKED
+    } else if report.failed_orders() > 0 {
+        EXIT_ORDERS_FAILED
+    } else {
+        0
+    }
+}
+
+/// Prints the summary of the run and writes it as JSON.
+fn write_report(report: &Report, path: &str) {
+    println!("{}", report);
+
+    let written = serde_json::to_string_pretty(report)
+        .map_err(|e| e.to_string())
+        .and_then(|json| std::fs::write(path, json).map_err(|e| e.to_string()));
+    match written {
+        Ok(()) => info!("Report written to {}", path),
+        Err(e) => error!("Could not write {}: {}", path, e),
+    }
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machines_of_a_store_write_their_own_files() {
+        let source = OrderSource::parse("orders.csv");
+        assert_eq!(
+            output_paths(&source, None),
+            (
+                "orders.csv.rejected".to_string(),
+                "orders.csv.report.json".to_string()
+            )
+        );
+        assert_eq!(
+            output_paths(&source, Some(1)),
+            (
+                "orders.csv.machine-1.rejected".to_string(),
+                "orders.csv.machine-1.report.json".to_string()
+            )
+        );
+        assert_eq!(
+            output_paths(&OrderSource::parse("tcp:9000"), Some(0)).1,
+            "tcp-localhost-9000.machine-0.report.json"
+        );
+    }
+}
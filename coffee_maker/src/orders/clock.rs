@@ -0,0 +1,204 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Time as seen by the actors of the coffee maker.
+/// Every wait that simulates the passing of time, like taking or brewing an order, goes through it.
+/// Each actor has its own clock, forked from the one of the coffee maker.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Lets the given time pass.
+    ///
+    /// # Returns
+    ///
+    /// The real time to wait for it, so async actors can wait without blocking.
+    fn advance(&self, duration: Duration) -> Duration;
+
+    /// Blocks until the given time passed.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(self.advance(duration));
+    }
+
+    /// A clock for another actor, starting at the time of this one.
+    fn fork(&self) -> Arc<dyn Clock>;
+
+    /// Lets the time pass up to the given one, if it is behind it.
+    /// Actors catch up with the time of what they receive from other actors.
+    fn catch_up(&self, _time: SystemTime) {}
+}
+
+/// Wall clock time.
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn advance(&self, duration: Duration) -> Duration {
+        duration
+    }
+
+    fn fork(&self) -> Arc<dyn Clock> {
+        Arc::new(RealClock)
+    }
+}
+
+/// How fast virtual time passes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speedup {
+    /// Virtual time passes the given times faster than real time.
+    Factor(f64),
+    /// Sleeps return at once, advancing the virtual time of the actor by what they would have waited.
+    /// Sleeps of different actors overlap, as they would in real time.
+    Unbounded,
+}
+
+/// Time that starts at the real time and passes faster than it.
+pub struct VirtualClock {
+    start: SystemTime,
+    started: Instant,
+    speedup: Speedup,
+    /// Time skipped by the sleeps of the actor in unbounded mode.
+    skipped: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new(speedup: Speedup) -> Self {
+        VirtualClock {
+            start: SystemTime::now(),
+            started: Instant::now(),
+            speedup,
+            skipped: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    fn skipped(&self) -> Duration {
+        self.skipped
+            .lock()
+            .map(|skipped| *skipped)
+            .unwrap_or_default()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        let elapsed = self.started.elapsed();
+        match self.speedup {
+            Speedup::Factor(factor) => self.start + elapsed.mul_f64(factor),
+            Speedup::Unbounded => self.start + elapsed + self.skipped(),
+        }
+    }
+
+    fn advance(&self, duration: Duration) -> Duration {
+        match self.speedup {
+            Speedup::Factor(factor) => duration.div_f64(factor),
+            Speedup::Unbounded => {
+                if let Ok(mut skipped) = self.skipped.lock() {
+                    *skipped += duration;
+                }
+                Duration::ZERO
+            }
+        }
+    }
+
+    fn fork(&self) -> Arc<dyn Clock> {
+        Arc::new(VirtualClock {
+            start: self.start,
+            started: self.started,
+            speedup: self.speedup,
+            skipped: Mutex::new(self.skipped()),
+        })
+    }
+
+    /// Time passes at the same pace for every actor unless it is unbounded.
+    fn catch_up(&self, time: SystemTime) {
+        if self.speedup != Speedup::Unbounded {
+            return;
+        }
+        if let Ok(behind) = time.duration_since(self.now()) {
+            self.advance(behind);
+        }
+    }
+}
+
+impl Speedup {
+    /// Parses a speedup factor greater than 0, or `max` for unbounded speed.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.eq_ignore_ascii_case("max") {
+            return Ok(Speedup::Unbounded);
+        }
+        match value.parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(Speedup::Factor(factor)),
+            _ => Err(format!("Invalid speedup: {:?}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Virtual time passed since the clock started, without the real time of the test.
+    fn skipped(clock: &dyn Clock, start: SystemTime) -> Duration {
+        let passed = clock.now().duration_since(start).unwrap();
+        Duration::from_secs(passed.as_secs())
+    }
+
+    #[test]
+    fn parse_speedup() {
+        assert_eq!(Speedup::parse("max"), Ok(Speedup::Unbounded));
+        assert_eq!(Speedup::parse("MAX"), Ok(Speedup::Unbounded));
+        assert_eq!(Speedup::parse("2.5"), Ok(Speedup::Factor(2.5)));
+        assert!(Speedup::parse("0").is_err());
+        assert!(Speedup::parse("-1").is_err());
+        assert!(Speedup::parse("inf").is_err());
+        assert!(Speedup::parse("fast").is_err());
+    }
+
+    #[test]
+    fn factor_waits_less_real_time() {
+        let clock = VirtualClock::new(Speedup::Factor(10.0));
+        assert_eq!(
+            clock.advance(Duration::from_secs(10)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn unbounded_sleeps_of_different_actors_overlap() {
+        let clock = VirtualClock::new(Speedup::Unbounded);
+        let (first, second) = (clock.fork(), clock.fork());
+
+        assert_eq!(first.advance(Duration::from_secs(5)), Duration::ZERO);
+        second.sleep(Duration::from_secs(5));
+        second.sleep(Duration::from_secs(3));
+
+        assert_eq!(skipped(first.as_ref(), clock.start), Duration::from_secs(5));
+        assert_eq!(
+            skipped(second.as_ref(), clock.start),
+            Duration::from_secs(8)
+        );
+        assert_eq!(skipped(&clock, clock.start), Duration::ZERO);
+        // Los relojes nuevos arrancan desde el actor que los crea
+        assert_eq!(
+            skipped(second.fork().as_ref(), clock.start),
+            Duration::from_secs(8)
+        );
+    }
+
+    #[test]
+    fn unbounded_catches_up_without_going_back() {
+        let clock = VirtualClock::new(Speedup::Unbounded);
+        let actor = clock.fork();
+        actor.sleep(Duration::from_secs(5));
+
+        clock.catch_up(actor.now());
+        assert_eq!(skipped(&clock, clock.start), Duration::from_secs(5));
+        actor.catch_up(clock.start);
+        assert_eq!(skipped(actor.as_ref(), clock.start), Duration::from_secs(5));
+    }
+}
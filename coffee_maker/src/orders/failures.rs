@@ -0,0 +1,240 @@
+use std::{collections::HashMap, io::BufRead, sync::Arc};
+
+use points::{read_rules, Fields, ParseError, ParseErrorReason};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::Order;
+
+/// How brewing an order fails, on top of the success chance of the coffee maker.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FailureModel {
+    /// Success chance of each dispenser, replaces the one of the coffee maker.
+    dispensers: HashMap<usize, f64>,
+    /// Success chance of each product, multiplies the one of the dispenser.
+    products: HashMap<String, f64>,
+    /// Orders that also fail after a failure of the same dispenser.
+    burst: usize,
+}
+
+impl FailureModel {
+    /// Reads the model of a file with a line per rule, with the format
+    /// `DISPENSER,<dispenser>,<chance>`, `PRODUCT,<product>,<chance>` or `BURST,<orders>`.
+    /// Dispensers are numbered from 0. The file is read with `read_rules`.
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, ParseError> {
+        let mut model = FailureModel::default();
+        read_rules(reader, |content| model.parse_rule(content))?;
+        Ok(model)
+    }
+
+    fn parse_rule(&mut self, line: &str) -> Result<(), ParseError> {
+        let mut fields = Fields::new(line);
+        let (column, kind) = fields.next_or_missing("kind")?;
+        match kind {
+            "DISPENSER" => {
+                let (column, dispenser) = fields.next_or_missing("dispenser")?;
+                let dispenser = dispenser
+                    .parse::<usize>()
+                    .map_err(|_| ParseError::invalid_value(column, dispenser))?;
+                self.dispensers
+                    .insert(dispenser, parse_chance(&mut fields)?);
+            }
+            "PRODUCT" => {
+                let (_, product) = fields.next_or_missing("product")?;
+                self.products
+                    .insert(product.to_string(), parse_chance(&mut fields)?);
+            }
+            "BURST" => {
+                let (column, orders) = fields.next_or_missing("orders")?;
+                self.burst = orders
+                    .parse::<usize>()
+                    .map_err(|_| ParseError::invalid_value(column, orders))?;
+            }
+            _ => {
+                return Err(ParseError::new(
+                    column,
+                    ParseErrorReason::UnknownRule(kind.to_string()),
+                ))
+            }
+        }
+        fields.expect_end()
+    }
+
+    /// Chance that the dispenser brews the order successfully.
+    pub fn success_chance(&self, default: f64, dispenser: usize, order: &Order) -> f64 {
+        let chance = self.dispensers.get(&dispenser).copied().unwrap_or(default);
+        let product = order
+            .product
+            .as_ref()
+            .and_then(|product| self.products.get(product))
+            .copied()
+            .unwrap_or(1.0);
+        chance * product
+    }
+
+    pub fn burst(&self) -> usize {
+        self.burst
+    }
+}
+
+fn parse_chance(fields: &mut Fields) -> Result<f64, ParseError> {
+    let (column, value) = fields.next_or_missing("chance")?;
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|chance| (0.0..=1.0).contains(chance))
+        .ok_or_else(|| ParseError::invalid_value(column, value))
+}
+
+/// What a random stream derived from the seed of a run is drawn for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngStream {
+    Brewing,
+    /// Jitter of the waits before sending a request again.
+    Retries,
+}
+
+/// Derives the random stream of a dispenser of a machine from the seed of the run.
+/// The seed, the machine, the dispenser and the stream together are the key of the stream,
+/// so no two of them draw the same values.
+pub fn derive_rng(seed: u64, machine: usize, dispenser: usize, stream: RngStream) -> StdRng {
+    let mut key = <StdRng as SeedableRng>::Seed::default();
+    let parts = [seed, machine as u64, dispenser as u64, stream as u64];
+    for (chunk, part) in key.chunks_exact_mut(8).zip(parts) {
+        chunk.copy_from_slice(&part.to_le_bytes());
+    }
+    StdRng::from_seed(key)
+}
+
+/// Brewing state of a dispenser.
+pub struct Dispenser {
+    id: usize,
+    /// Random stream of the dispenser, derived from the seed of the run.
+    /// Runs with the same seed draw the same values on every dispenser.
+    rng: StdRng,
+    failures: Arc<FailureModel>,
+    /// Orders left to fail in the current burst.
+    burst_left: usize,
+}
+
+impl Dispenser {
+    pub fn new(machine: usize, id: usize, seed: u64, failures: Arc<FailureModel>) -> Self {
+        Dispenser {
+            id,
+            rng: derive_rng(seed, machine, id, RngStream::Brewing),
+            failures,
+            burst_left: 0,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Decides whether the order is brewed successfully.
+    /// A failure makes the next orders of the burst fail without drawing.
+    pub fn brew(&mut self, default_chance: f64, order: &Order) -> Result<(), String> {
+        if self.burst_left > 0 {
+            self.burst_left -= 1;
+            return Err(String::from("Order failed in a burst"));
+        }
+
+        let chance = self.failures.success_chance(default_chance, self.id, order);
+        if self.rng.gen_bool(chance) {
+            Ok(())
+        } else {
+            self.burst_left = self.failures.burst();
+            Err(String::from("Order failed"))
+        }
+    }
+}
+
+/// Draws a new seed for runs that are not given one.
+pub fn random_seed() -> u64 {
+    rand::thread_rng().gen()
+}
+
+#[cfg(test)]
+mod tests {
+    use points::OrderAction;
+
+    use super::*;
+
+    fn order(product: Option<&str>) -> Order {
+        let mut order = Order::new(1, OrderAction::FillPoints(10));
+        order.product = product.map(str::to_string);
+        order
+    }
+
+    fn dispenser(model: &str) -> Dispenser {
+        let failures = FailureModel::read(model.as_bytes()).unwrap();
+        Dispenser::new(0, 0, 42, Arc::new(failures))
+    }
+
+    #[test]
+    fn read_failure_model() {
+        let model = FailureModel::read(
+            "# reglas\nDISPENSER,1,0.5\n\nPRODUCT,latte,0.8\nBURST,2\n".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(model.success_chance(1.0, 0, &order(None)), 1.0);
+        assert_eq!(model.success_chance(1.0, 1, &order(None)), 0.5);
+        assert_eq!(model.success_chance(1.0, 1, &order(Some("latte"))), 0.4);
+        assert_eq!(model.success_chance(0.5, 0, &order(Some("mocha"))), 0.5);
+        assert_eq!(model.burst(), 2);
+    }
+
+    #[test]
+    fn invalid_failure_model() {
+        assert_eq!(
+            FailureModel::read("BURST,1\nDISPENSER,1,1.5\n".as_bytes()),
+            Err(
+                ParseError::new(13, ParseErrorReason::InvalidRuleValue("1.5".to_string()))
+                    .at_line(2)
+            )
+        );
+        assert_eq!(
+            FailureModel::read("JAM,1\n".as_bytes()),
+            Err(ParseError::new(
+                1,
+                ParseErrorReason::UnknownRule("JAM".to_string())
+            ))
+        );
+        assert_eq!(
+            FailureModel::read("BURST,1,2\n".as_bytes()),
+            Err(ParseError::new(
+                9,
+                ParseErrorReason::UnexpectedField("2".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn failures_come_in_bursts() {
+        let mut dispenser = dispenser("PRODUCT,latte,0\nBURST,2\n");
+        let latte = order(Some("latte"));
+
+        assert_eq!(dispenser.brew(1.0, &latte), Err("Order failed".to_string()));
+        // Las siguientes fallan aunque el producto no falle nunca
+        for _ in 0..2 {
+            assert_eq!(
+                dispenser.brew(1.0, &order(None)),
+                Err("Order failed in a burst".to_string())
+            );
+        }
+        assert_eq!(dispenser.brew(1.0, &order(None)), Ok(()));
+    }
+
+    #[test]
+    fn streams_do_not_overlap() {
+        let draw = |seed, machine, dispenser| {
+            derive_rng(seed, machine, dispenser, RngStream::Brewing).gen::<u64>()
+        };
+        assert_eq!(draw(1, 0, 1), draw(1, 0, 1));
+        assert_ne!(draw(1, 0, 1), draw(2, 0, 0));
+        assert_ne!(draw(1, 1, 0), draw(1, 0, 1));
+        assert_ne!(
+            draw(1, 0, 0),
+            derive_rng(1, 0, 0, RngStream::Retries).gen::<u64>()
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::BufRead,
+    sync::{Arc, Mutex},
+};
+
+use points::{read_rules, Fields, ParseError, ParseErrorReason};
+use serde::Serialize;
+
+use super::{Order, OrderAction, RunReport};
+use tracing::info;
+
+/// Amounts of the ingredients of the dispensers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Ingredients {
+    pub coffee: u32,
+    pub milk: u32,
+    pub water: u32,
+    pub cups: u32,
+}
+
+/// Ingredients of an order taken from the stock of its dispenser, or the one that ran out.
+pub type Supplies = Result<Ingredients, &'static str>;
+
+impl Ingredients {
+    /// Parses the next fields as the amounts, with the format `<coffee>,<milk>,<water>,<cups>`.
+    fn parse(fields: &mut Fields) -> Result<Self, ParseError> {
+        let mut amount = |name| {
+            let (column, amount) = fields.next_or_missing(name)?;
+            amount
+                .parse::<u32>()
+                .map_err(|_| ParseError::invalid_value(column, amount))
+        };
+        Ok(Ingredients {
+            coffee: amount("coffee")?,
+            milk: amount("milk")?,
+            water: amount("water")?,
+            cups: amount("cups")?,
+        })
+    }
+
+    fn amounts(&self) -> [(&'static str, u32); 4] {
+        [
+            ("coffee", self.coffee),
+            ("milk", self.milk),
+            ("water", self.water),
+            ("cups", self.cups),
+        ]
+    }
+
+    /// The first ingredient there is not enough of to take the needed ones.
+    fn missing(&self, needed: &Ingredients) -> Option<&'static str> {
+        self.amounts()
+            .iter()
+            .zip(needed.amounts())
+            .find(|((_, stock), (_, needed))| stock < needed)
+            .map(|((name, _), _)| *name)
+    }
+
+    fn add(&mut self, other: &Ingredients) {
+        self.coffee = self.coffee.saturating_add(other.coffee);
+        self.milk = self.milk.saturating_add(other.milk);
+        self.water = self.water.saturating_add(other.water);
+        self.cups = self.cups.saturating_add(other.cups);
+    }
+
+    fn sub(&mut self, other: &Ingredients) {
+        self.coffee -= other.coffee;
+        self.milk -= other.milk;
+        self.water -= other.water;
+        self.cups -= other.cups;
+    }
+
+    fn min(&self, other: &Ingredients) -> Ingredients {
+        Ingredients {
+            coffee: self.coffee.min(other.coffee),
+            milk: self.milk.min(other.milk),
+            water: self.water.min(other.water),
+            cups: self.cups.min(other.cups),
+        }
+    }
+}
+
+impl fmt::Display for Ingredients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} coffee, {} milk, {} water, {} cups",
+            self.coffee, self.milk, self.water, self.cups
+        )
+    }
+}
+
+/// What each order takes from the dispensers, and how much they hold.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InventoryModel {
+    /// Stock of each dispenser when it is full, the stock is unlimited if it is not given.
+    stock: Option<Ingredients>,
+    /// Ingredients of each product.
+    recipes: HashMap<String, Ingredients>,
+    /// Ingredients of the orders without a product of the recipes.
+    default_recipe: Ingredients,
+    /// Orders taken between refills of every dispenser.
+    refill: Option<usize>,
+}
+
+impl InventoryModel {
+    /// Reads the model of a file with a line per rule, with the format
+    /// `STOCK,<coffee>,<milk>,<water>,<cups>`, `RECIPE,<product>,<coffee>,<milk>,<water>,<cups>`,
+    /// `DEFAULT,<coffee>,<milk>,<water>,<cups>` or `REFILL,<orders>`.
+    /// The file is read with `read_rules`.
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, ParseError> {
+        let mut model = InventoryModel::default();
+        read_rules(reader, |content| model.parse_rule(content))?;
+        Ok(model)
+    }
+
+    fn parse_rule(&mut self, line: &str) -> Result<(), ParseError> {
+        let mut fields = Fields::new(line);
+        let (column, kind) = fields.next_or_missing("kind")?;
+        match kind {
+            "STOCK" => self.stock = Some(Ingredients::parse(&mut fields)?),
+            "RECIPE" => {
+                let (_, product) = fields.next_or_missing("product")?;
+                self.recipes
+                    .insert(product.to_string(), Ingredients::parse(&mut fields)?);
+            }
+            "DEFAULT" => self.default_recipe = Ingredients::parse(&mut fields)?,
+            "REFILL" => {
+                let (column, orders) = fields.next_or_missing("orders")?;
+                let orders = orders
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|orders| *orders > 0)
+                    .ok_or_else(|| ParseError::invalid_value(column, orders))?;
+                self.refill = Some(orders);
+            }
+            _ => {
+                return Err(ParseError::new(
+                    column,
+                    ParseErrorReason::UnknownRule(kind.to_string()),
+                ))
+            }
+        }
+        fields.expect_end()
+    }
+
+    /// Ingredients the order takes from its dispenser, transfers are not brewed.
+    pub fn recipe(&self, order: &Order) -> Ingredients {
+        if let OrderAction::Transfer { .. } = order.action {
+            return Ingredients::default();
+        }
+        order
+            .product
+            .as_ref()
+            .and_then(|product| self.recipes.get(product))
+            .copied()
+            .unwrap_or(self.default_recipe)
+    }
+
+    pub fn refill(&self) -> Option<usize> {
+        self.refill
+    }
+
+    /// Inventory of a full dispenser.
+    pub fn inventory(&self) -> Inventory {
+        Inventory {
+            stock: self.stock.unwrap_or_default(),
+            capacity: self.stock,
+        }
+    }
+}
+
+/// Ingredients left in a dispenser.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inventory {
+    stock: Ingredients,
+    /// The stock is unlimited if there is no capacity.
+    capacity: Option<Ingredients>,
+}
+
+impl Inventory {
+    /// Takes the ingredients from the stock, if there are enough of all of them.
+    pub fn take(&mut self, needed: &Ingredients) -> Supplies {
+        if self.capacity.is_none() {
+            return Ok(*needed);
+        }
+        if let Some(missing) = self.stock.missing(needed) {
+            return Err(missing);
+        }
+        self.stock.sub(needed);
+        Ok(*needed)
+    }
+
+    /// Gives back ingredients that were not used, up to the capacity.
+    pub fn restock(&mut self, unused: &Ingredients) {
+        if let Some(capacity) = self.capacity {
+            self.stock.add(unused);
+            self.stock = self.stock.min(&capacity);
+        }
+    }
+
+    /// Fills the dispenser up to its capacity.
+    pub fn refill(&mut self) {
+        if let Some(capacity) = self.capacity {
+            self.stock = capacity;
+        }
+    }
+
+    pub fn stock(&self) -> Option<Ingredients> {
+        self.capacity.map(|_| self.stock)
+    }
+}
+
+/// Fills the dispensers of a machine up to their capacity, counting the refill in its report.
+pub fn refill_dispensers(inventories: &[Arc<Mutex<Inventory>>], report: &RunReport) {
+    for inventory in inventories {
+        if let Ok(mut inventory) = inventory.lock() {
+            inventory.refill();
+        }
+    }
+    info!("Dispensers refilled");
+    report.refilled();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingredients(coffee: u32, milk: u32, water: u32, cups: u32) -> Ingredients {
+        Ingredients {
+            coffee,
+            milk,
+            water,
+            cups,
+        }
+    }
+
+    fn model() -> InventoryModel {
+        InventoryModel::read(
+            "# recetario\nSTOCK,20,100,100,2\nRECIPE,latte,10,50,30,1\n\nDEFAULT,10,0,30,1\nREFILL,5\n"
+                .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn order(product: Option<&str>) -> Order {
+        let mut order = Order::new(1, OrderAction::FillPoints(10));
+        order.product = product.map(str::to_string);
+        order
+    }
+
+    #[test]
+    fn read_inventory_model() {
+        let model = model();
+        assert_eq!(
+            model.recipe(&order(Some("latte"))),
+            ingredients(10, 50, 30, 1)
+        );
+        assert_eq!(
+            model.recipe(&order(Some("mocha"))),
+            ingredients(10, 0, 30, 1)
+        );
+        assert_eq!(model.recipe(&order(None)), ingredients(10, 0, 30, 1));
+        assert_eq!(model.refill(), Some(5));
+        assert_eq!(
+            model.inventory().stock(),
+            Some(ingredients(20, 100, 100, 2))
+        );
+    }
+
+    #[test]
+    fn invalid_inventory_model() {
+        assert_eq!(
+            InventoryModel::read("REFILL,5\nSTOCK,1,2,x,4\n".as_bytes()),
+            Err(
+                ParseError::new(11, ParseErrorReason::InvalidRuleValue("x".to_string())).at_line(2)
+            )
+        );
+        assert_eq!(
+            InventoryModel::read("STOCK,1,2,3\n".as_bytes()),
+            Err(ParseError::new(12, ParseErrorReason::MissingField("cups")))
+        );
+        assert_eq!(
+            InventoryModel::read("REFILL,0\n".as_bytes()),
+            Err(ParseError::new(
+                8,
+                ParseErrorReason::InvalidRuleValue("0".to_string())
+            ))
+        );
+        assert_eq!(
+            InventoryModel::read("SUGAR,1\n".as_bytes()),
+            Err(ParseError::new(
+                1,
+                ParseErrorReason::UnknownRule("SUGAR".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn orders_take_from_the_stock_until_it_runs_out() {
+        let model = model();
+        let mut inventory = model.inventory();
+        let latte = model.recipe(&order(Some("latte")));
+
+        assert_eq!(inventory.take(&latte), Ok(latte));
+        assert_eq!(inventory.stock(), Some(ingredients(10, 50, 70, 1)));
+        // Falta leche antes que vasos
+        assert_eq!(inventory.take(&ingredients(10, 60, 0, 1)), Err("milk"));
+        assert_eq!(inventory.take(&latte), Ok(latte));
+        assert_eq!(inventory.take(&latte), Err("coffee"));
+        assert_eq!(inventory.stock(), Some(ingredients(0, 0, 40, 0)));
+    }
+
+    #[test]
+    fn restock_and_refill_up_to_the_capacity() {
+        let model = model();
+        let mut inventory = model.inventory();
+        let latte = model.recipe(&order(Some("latte")));
+
+        inventory.take(&latte).unwrap();
+        inventory.restock(&latte);
+        inventory.restock(&latte);
+        assert_eq!(inventory.stock(), Some(ingredients(20, 100, 100, 2)));
+
+        inventory.take(&latte).unwrap();
+        inventory.take(&latte).unwrap();
+        inventory.refill();
+        assert_eq!(inventory.stock(), Some(ingredients(20, 100, 100, 2)));
+    }
+
+    #[test]
+    fn stock_is_unlimited_without_capacity() {
+        let mut inventory = InventoryModel::default().inventory();
+        let needed = ingredients(1000, 1000, 1000, 1000);
+        assert_eq!(inventory.take(&needed), Ok(needed));
+        inventory.restock(&needed);
+        assert_eq!(inventory.stock(), None);
+    }
+}
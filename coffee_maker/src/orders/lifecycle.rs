@@ -0,0 +1,113 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Condvar, Mutex,
+};
+
+use tracing::debug;
+
+/// Tracks the orders in flight, so the coffee maker stops only once all of them are done.
+#[derive(Default)]
+pub struct Lifecycle {
+    stopping: AtomicBool,
+    in_flight: Mutex<usize>,
+    drained: Condvar,
+}
+
+/// An order sent to a handler and not done yet.
+/// It is done when dropped, even if the handler never got the order.
+pub struct InFlight {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Lifecycle {
+    /// Stops taking new orders, the ones in flight are still handled.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Marks an order as in flight until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            *in_flight += 1;
+        }
+        InFlight {
+            lifecycle: self.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+            .lock()
+            .map(|in_flight| *in_flight)
+            .unwrap_or(0)
+    }
+
+    /// Blocks until there are no orders in flight.
+    pub fn wait_drained(&self) {
+        let mut in_flight = match self.in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(_) => return,
+        };
+        while *in_flight > 0 {
+            debug!("Waiting for {} orders in flight", *in_flight);
+            in_flight = match self.drained.wait(in_flight) {
+                Ok(in_flight) => in_flight,
+                Err(_) => return,
+            };
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.lifecycle.in_flight.lock() {
+            *in_flight -= 1;
+            if *in_flight == 0 {
+                self.lifecycle.drained.notify_all();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn orders_are_in_flight_until_their_guard_is_dropped() {
+        let lifecycle = Arc::new(Lifecycle::default());
+        let first = lifecycle.start();
+        let second = lifecycle.start();
+        assert_eq!(lifecycle.in_flight(), 2);
+
+        drop(first);
+        assert_eq!(lifecycle.in_flight(), 1);
+        drop(second);
+        assert_eq!(lifecycle.in_flight(), 0);
+    }
+
+    #[test]
+    fn stopping_waits_for_the_orders_in_flight() {
+        let lifecycle = Arc::new(Lifecycle::default());
+        let in_flight = lifecycle.start();
+        lifecycle.stop();
+        assert!(lifecycle.is_stopping());
+
+        let handler = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(in_flight);
+        });
+        lifecycle.wait_drained();
+        assert_eq!(lifecycle.in_flight(), 0);
+        handler.join().unwrap();
+
+        // Sin pedidos en vuelo no espera
+        lifecycle.wait_drained();
+    }
+}
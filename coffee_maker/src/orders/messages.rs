@@ -1,33 +1,60 @@
-use std::sync::{Arc, Barrier};
+use std::time::SystemTime;
 
 use actix::prelude::*;
 
-use super::Order;
+use super::{
+    Balance, ClientId, IdempotencyKey, InFlight, Order, Orders, PointResponse, ReadConsistency,
+    Supplies,
+};
 
 // Order Taker
-type FilePath = String;
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct TakeOrders(pub FilePath);
+pub struct TakeOrders(pub Orders);
 
 // Order Handler
-#[derive(Message)]
-#[rtype(result = "Result<(),String>")]
-pub struct HandleOrder(pub Order);
-
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct WaitStop(pub Option<Arc<Barrier>>);
-
-// Point Storage
-#[derive(Message)]
-#[rtype(result = "Result<(),String>")]
-pub struct LockOrder(pub Order);
+/// Number of an order, in the order the coffee maker took it.
+pub type Ticket = u64;
 
+/// An order along with the time it was taken, which the handler catches up with before handling it.
 #[derive(Message)]
 #[rtype(result = "Result<(),String>")]
-pub struct FreeOrder(pub Order);
+pub struct HandleOrder(
+    pub Ticket,
+    pub Order,
+    pub Supplies,
+    pub SystemTime,
+    pub InFlight,
+);
+
+/// Orders of a handler a cancellation applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tickets {
+    One(Ticket),
+    /// Every order queued or in progress.
+    All,
+}
+
+/// Cancels orders with the given reason, returns how many were cancelled.
+/// Orders already committing their points are not cancelled.
+#[derive(Message, Clone)]
+#[rtype(result = "usize")]
+pub struct CancelOrder(pub Tickets, pub String);
 
-#[derive(Message)]
-#[rtype(result = "Result<(),String>")]
-pub struct CommitOrder(pub Order);
+// Point Storage
+// Every request carries the key of the request, retries must send the same key
+#[derive(Message, Clone)]
+#[rtype(result = "Result<(),PointResponse>")]
+pub struct LockOrder(pub Order, pub IdempotencyKey);
+
+#[derive(Message, Clone)]
+#[rtype(result = "Result<(),PointResponse>")]
+pub struct FreeOrder(pub Order, pub IdempotencyKey);
+
+#[derive(Message, Clone)]
+#[rtype(result = "Result<(),PointResponse>")]
+pub struct CommitOrder(pub Order, pub IdempotencyKey);
+
+#[derive(Message, Clone)]
+#[rtype(result = "Result<Balance,PointResponse>")]
+pub struct QueryBalance(pub ClientId, pub ReadConsistency, pub IdempotencyKey);
@@ -1,10 +1,27 @@
+mod clock;
+mod failures;
+mod inventory;
+mod lifecycle;
 mod messages;
 mod order_handler;
+mod order_source;
 mod order_taker;
 mod point_storage;
+mod report;
+mod retry;
 
+pub use clock::*;
+pub use failures::*;
+pub use inventory::*;
+pub use lifecycle::*;
 pub use messages::*;
 pub use order_handler::*;
+pub use order_source::*;
 pub use order_taker::*;
 pub use point_storage::*;
-pub use points::{Message as PointMessage, Order, OrderAction};
+pub use points::{
+    Balance, ClientId, IdempotencyKey, KeyedMessage, Message as PointMessage, Order, OrderAction,
+    ReadConsistency, Response as PointResponse,
+};
+pub use report::*;
+pub use retry::*;
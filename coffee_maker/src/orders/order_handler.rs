@@ -1,97 +1,566 @@
-use std::{thread, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use super::*;
 use actix::prelude::*;
-use futures::executor::block_on;
-use rand::Rng;
-use tracing::{debug, info, trace, warn};
+use actix_rt::time::sleep;
+use futures::future::{select, Either};
+use points::{Earned, Promotions, Purchase};
+use rand::rngs::StdRng;
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+use tracing::{debug, enabled, error, info, warn, Level};
 
-pub const DEFAULT_SUCCESS_CHANCE: f64 = 1.0;
-const ORDER_MILLIS: u64 = 1000;
+/// Hands out the idempotency keys of the requests of this coffee maker.
+pub struct KeyGenerator {
+    coffee_maker: u32,
+    sequence: AtomicU64,
+}
+
+impl KeyGenerator {
+    pub fn new(coffee_maker: u32) -> Self {
+        KeyGenerator {
+            coffee_maker,
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next(&self) -> IdempotencyKey {
+        IdempotencyKey {
+            coffee_maker: self.coffee_maker,
+            sequence: self.sequence.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+}
+
+/// Applies the promotions to the purchases taken by this coffee maker.
+pub struct PromotionEngine {
+    promotions: Promotions,
+    /// Purchase numbers of each client so far.
+    purchases: Mutex<HashMap<ClientId, Numbers>>,
+}
 
+/// Purchase numbers handed out to a client.
+#[derive(Default)]
+struct Numbers {
+    /// Last number handed out.
+    last: usize,
+    /// Numbers given back by purchases that did not go through, handed out again first.
+    returned: BTreeSet<usize>,
+}
+
+impl PromotionEngine {
+    pub fn new(promotions: Promotions) -> Self {
+        PromotionEngine {
+            promotions,
+            purchases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the points earned by the order to the ones of the promotions that apply.
+    /// The order is numbered among the purchases of its client when it is applied,
+    /// so orders of the same client in flight at once get different numbers.
+    /// An order that is not committed gives its number back with `give_back`.
+    ///
+    /// # Returns
+    ///
+    /// The number of the purchase and the points it earns, if the order fills points.
+    pub fn apply(&self, order: &mut Order, time: SystemTime) -> Option<(usize, Earned)> {
+        let points = match order.action {
+            OrderAction::FillPoints(points) => points,
+            _ => return None,
+        };
+
+        let number = {
+            let mut purchases = self.purchases.lock().ok()?;
+            let numbers = purchases.entry(order.client_id).or_default();
+            numbers.returned.pop_first().unwrap_or_else(|| {
+                numbers.last += 1;
+                numbers.last
+            })
+        };
+
+        let earned = self.promotions.evaluate(Purchase {
+            points,
+            time,
+            number,
+        });
+        order.action = OrderAction::FillPoints(earned.points);
+        Some((number, earned))
+    }
+
+    /// Gives back the number of a purchase that was not committed, for the next purchase of the client.
+    pub fn give_back(&self, client_id: ClientId, number: usize) {
+        if let Ok(mut purchases) = self.purchases.lock() {
+            purchases
+                .entry(client_id)
+                .or_default()
+                .returned
+                .insert(number);
+        }
+    }
+}
+
+/// Cancellation of an order, queued or in progress.
+#[derive(Clone, Default)]
+pub struct Cancellation {
+    reason: Rc<RefCell<Option<String>>>,
+    /// Set once the order commits its points, from then on it can not be cancelled.
+    committing: Rc<Cell<bool>>,
+    notify: Rc<Notify>,
+}
+
+impl Cancellation {
+    /// Cancels the order, unless it was already cancelled or is committing.
+    fn cancel(&self, reason: &str) -> bool {
+        let mut current = self.reason.borrow_mut();
+        if current.is_some() || self.committing.get() {
+            return false;
+        }
+        *current = Some(reason.to_string());
+        // The permit is kept until the order waits for it
+        self.notify.notify_one();
+        true
+    }
+
+    fn reason(&self) -> Option<String> {
+        self.reason.borrow().clone()
+    }
+
+    /// Marks the order as committing, unless it was cancelled.
+    ///
+    /// # Returns
+    ///
+    /// The reason of the cancellation, if it was cancelled.
+    fn commit(&self) -> Result<(), String> {
+        match self.reason() {
+            Some(reason) => Err(reason),
+            None => {
+                self.committing.set(true);
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves once the order is cancelled, with the reason.
+    async fn cancelled(&self) -> String {
+        loop {
+            if let Some(reason) = self.reason() {
+                return reason;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Handles the orders of a dispenser, one at a time.
+/// Orders of different dispensers are handled at the same time, waiting without blocking.
+/// Orders can be cancelled while they are queued or brewing, and are cancelled once their deadline passes.
+#[derive(Clone)]
 pub struct OrderHandler {
     pub point_storage: Addr<PointStorage>,
     pub success_chance: f64,
+    /// Time it takes to brew an order.
+    pub order_time: Duration,
+    /// Only borrowed while deciding if an order is brewed, never across a wait.
+    pub dispenser: Rc<RefCell<Dispenser>>,
+    /// Ingredients left in the dispenser, taken by the order taker.
+    pub inventory: Arc<Mutex<Inventory>>,
+    pub keys: Arc<KeyGenerator>,
+    pub promotions: Arc<PromotionEngine>,
+    /// Clock of the dispenser, shared by the clones of the handler.
+    pub clock: Arc<dyn Clock>,
+    pub report: Arc<RunReport>,
+    pub retry: Arc<RetryPolicy>,
+    /// Random stream of the dispenser for the waits between retries, never borrowed across a wait.
+    pub retry_rng: Rc<RefCell<StdRng>>,
+    /// Real time since an order is taken after which it is cancelled.
+    pub deadline: Option<Duration>,
+    /// Orders queued or in progress, with their cancellation.
+    pub orders: Rc<RefCell<HashMap<Ticket, Cancellation>>>,
+    /// Held by the order in progress, orders take it in the order they arrive.
+    pub turn: Rc<AsyncMutex<()>>,
 }
 
 impl Actor for OrderHandler {
-    type Context = SyncContext<Self>;
+    type Context = Context<Self>;
 }
 
 impl OrderHandler {
-    fn process_order(&self) -> Result<(), String> {
-        thread::sleep(Duration::from_millis(ORDER_MILLIS));
-        let success = rand::thread_rng().gen_bool(self.success_chance);
-        match success {
-            true => Ok(()),
-            false => Err(String::from("Order failed")),
-        }
+    /// An order without its ingredients fails at once, without drawing from the dispenser.
+    async fn process_order(&self, order: &Order, supplies: &Supplies) -> Result<(), String> {
+        let started = Instant::now();
+        let res = match supplies {
+            Err(ingredient) => {
+                self.report.out_of_stock();
+                Err(format!("Out of {}", ingredient))
+            }
+            Ok(_) => {
+                sleep(self.clock.advance(self.order_time)).await;
+                self.dispenser.borrow_mut().brew(self.success_chance, order)
+            }
+        };
+        let outcome = match res {
+            Ok(()) => StageOutcome::Succeeded,
+            Err(_) => StageOutcome::Failed,
+        };
+        self.report.stage(Stage::Brew, started.elapsed(), outcome);
+        res
     }
 
-    async fn lock_points(&self, order: Order) -> Result<(), String> {
-        self.point_storage
-            .send(LockOrder(order))
+    /// Sends a message to the point storage.
+    /// The message is sent again while it fails for transient reasons, as the retry policy allows.
+    /// Retries carry the same idempotency key, so the servers apply the message only once.
+    /// They wait in real time, since they wait for the servers.
+    /// The stage is reported with the time of all the retries.
+    async fn request<M>(&self, stage: Stage, msg: M) -> Result<(), PointResponse>
+    where
+        M: Message<Result = Result<(), PointResponse>> + Clone + Send + 'static,
+        PointStorage: Handler<M>,
+    {
+        let started = Instant::now();
+        let mut attempts = 0;
+        let res = loop {
+            attempts += 1;
+            let res = self
+                .point_storage
+                .send(msg.clone())
+                .await
+                .map_err(|_| PointResponse::InternalError)
+                .and_then(|res| res);
+
+            let delay = match &res {
+                Err(e) if e.is_transient() => self.retry.next_delay(
+                    attempts,
+                    started.elapsed(),
+                    &mut *self.retry_rng.borrow_mut(),
+                ),
+                _ => None,
+            };
+            match (res, delay) {
+                (Err(e), Some(delay)) => {
+                    warn!(
+                        "Retrying {} in {:?} ({}/{}): {}",
+                        stage, delay, attempts, self.retry.max_attempts, e
+                    );
+                    self.report.retried(stage);
+                    sleep(delay).await;
+                }
+                (res, _) => break res,
+            }
+        };
+        self.report
+            .stage(stage, started.elapsed(), StageOutcome::of(&res));
+        res
+    }
+
+    async fn lock_points(&self, order: Order) -> Result<(), PointResponse> {
+        self.request(Stage::Lock, LockOrder(order, self.keys.next()))
             .await
-            .map_err(|_| "MailboxError")??;
-        Ok(())
     }
 
-    async fn free_points(&self, order: Order) -> Result<(), String> {
-        self.point_storage
-            .send(FreeOrder(order))
+    async fn free_points(&self, order: Order) -> Result<(), PointResponse> {
+        self.request(Stage::Free, FreeOrder(order, self.keys.next()))
+            .await
+    }
+
+    async fn commit_points(&self, order: Order) -> Result<(), PointResponse> {
+        let stage = match order.action {
+            OrderAction::Transfer { .. } => Stage::Transfer,
+            _ => Stage::Commit,
+        };
+        self.request(stage, CommitOrder(order, self.keys.next()))
             .await
-            .map_err(|_| "MailboxError")??;
-        Ok(())
     }
 
-    async fn commit_points(&self, order: Order) -> Result<(), String> {
+    async fn query_balance(&self, client_id: ClientId) -> Result<Balance, PointResponse> {
         self.point_storage
-            .send(CommitOrder(order))
+            .send(QueryBalance(
+                client_id,
+                ReadConsistency::Local,
+                self.keys.next(),
+            ))
             .await
-            .or(Err("MailboxError"))??;
+            .map_err(|_| PointResponse::InternalError)?
+    }
+
+    /// Moves the points of a transfer in a single transaction, nothing is dispensed.
+    async fn transfer_points(&self, order: Order) -> Result<(), String> {
+        if let Err(e) = self.commit_points(order.clone()).await {
+            warn!("Failed to Transfer {:?}: {}", order, e);
+            return Err(e.to_string());
+        }
+        info!("Succeeded {:?}", order);
         Ok(())
     }
 
-    async fn handle_order(&mut self, order: Order) -> Result<(), String> {
-        self.lock_points(order.clone()).await.map_err(|e| {
-            warn!("Failed to Lock {:?}", order);
-            e
-        })?;
+    /// Handles the order and reports its outcome for its client.
+    /// An order cancelled while queued is not started, its ingredients go back to the dispenser.
+    async fn handle_order(
+        &self,
+        ticket: Ticket,
+        mut order: Order,
+        supplies: Supplies,
+        cancellation: &Cancellation,
+    ) -> Result<(), String> {
+        let res = match cancellation.reason() {
+            Some(reason) => {
+                self.restock(&supplies);
+                Err(self.record_cancellation(ticket, &order, &reason))
+            }
+            None => {
+                self.run_order(ticket, &mut order, &supplies, cancellation)
+                    .await
+            }
+        };
+        self.report.finished(&order, res.is_ok());
+        res
+    }
+
+    /// Records the cancellation of an order.
+    ///
+    /// # Returns
+    ///
+    /// The error of the order.
+    fn record_cancellation(&self, ticket: Ticket, order: &Order, reason: &str) -> String {
+        warn!("Order #{} cancelled, {}: {:?}", ticket, reason, order);
+        self.report.cancelled(reason);
+        format!("Cancelled, {}", reason)
+    }
 
-        if self.process_order().is_err() {
-            warn!("Failed {:?}", order);
-            self.free_points(order).await?;
-            Err(String::from("Order failed"))
-        } else {
-            self.commit_points(order.clone()).await?;
-            info!("Succeeded {:?}", order);
-            Ok(())
+    /// Gives the ingredients of an order that was never brewed back to the dispenser.
+    fn restock(&self, supplies: &Supplies) {
+        if let (Ok(unused), Ok(mut inventory)) = (supplies, self.inventory.lock()) {
+            inventory.restock(unused);
+        }
+    }
+
+    /// Cancels orders queued or in progress, returns how many were cancelled.
+    fn cancel(&self, tickets: Tickets, reason: &str) -> usize {
+        let orders = self.orders.borrow();
+        match tickets {
+            Tickets::One(ticket) => orders
+                .get(&ticket)
+                .map_or(0, |order| order.cancel(reason) as usize),
+            Tickets::All => orders.values().filter(|order| order.cancel(reason)).count(),
+        }
+    }
+
+    /// Orders that fill points are numbered among the purchases of their client,
+    /// the number is given back if the order fails.
+    async fn run_order(
+        &self,
+        ticket: Ticket,
+        order: &mut Order,
+        supplies: &Supplies,
+        cancellation: &Cancellation,
+    ) -> Result<(), String> {
+        if let OrderAction::Transfer { .. } = order.action {
+            return self.transfer_points(order.clone()).await;
+        }
+
+        let number = self
+            .promotions
+            .apply(order, self.clock.now())
+            .map(|(number, earned)| {
+                info!(
+                    "Client {} earns {} on purchase #{}",
+                    order.client_id, earned, number
+                );
+                number
+            });
+        let res = self.purchase(ticket, order, supplies, cancellation).await;
+        if let (Err(_), Some(number)) = (&res, number) {
+            self.promotions.give_back(order.client_id, number);
+        }
+        res
+    }
+
+    /// Locks the points of the order, brews it and commits them.
+    /// Requests in flight are not interrupted, as they may be applied anyway.
+    /// Points locked by a cancelled order are freed, unless they are being committed.
+    /// Points of a lock left unanswered are freed too, as it may have been applied.
+    /// The ingredients of an order that is not brewed, because its points could not be locked
+    /// or it was cancelled while brewing, go back to the dispenser.
+    async fn purchase(
+        &self,
+        ticket: Ticket,
+        order: &mut Order,
+        supplies: &Supplies,
+        cancellation: &Cancellation,
+    ) -> Result<(), String> {
+        if let Err(e) = self.lock_points(order.clone()).await {
+            warn!("Failed to Lock {:?}: {}", order, e);
+            // The server may have locked the points without answering
+            if e.is_unknown() {
+                self.release_points(order).await;
+            }
+            self.restock(supplies);
+            // Only worth another request to the server when it is going to be logged
+            if e == PointResponse::NotEnoughPoints && enabled!(Level::DEBUG) {
+                if let Ok(balance) = self.query_balance(order.client_id).await {
+                    debug!("Client {} has {}", order.client_id, balance);
+                }
+            }
+            return Err(e.to_string());
+        }
+
+        let brewing = select(
+            Box::pin(self.process_order(order, supplies)),
+            Box::pin(cancellation.cancelled()),
+        );
+        let brewed = match brewing.await {
+            Either::Left((brewed, _)) => brewed,
+            Either::Right((reason, _)) => {
+                let e = self.record_cancellation(ticket, order, &reason);
+                self.restock(supplies);
+                self.release_points(order).await;
+                return Err(e);
+            }
+        };
+        if let Err(e) = brewed {
+            warn!(
+                "{} on dispenser {}: {:?}",
+                e,
+                self.dispenser.borrow().id(),
+                order
+            );
+            self.release_points(order).await;
+            return Err(e);
+        }
+
+        if let Err(reason) = cancellation.commit() {
+            let e = self.record_cancellation(ticket, order, &reason);
+            self.release_points(order).await;
+            return Err(e);
+        }
+        if let Err(e) = self.commit_points(order.clone()).await {
+            warn!("Failed to Commit {:?}: {}", order, e);
+            self.release_points(order).await;
+            return Err(e.to_string());
+        }
+        info!("Succeeded {:?}", order);
+        Ok(())
+    }
+
+    /// Frees the points locked by an order that will not be committed.
+    async fn release_points(&self, order: &Order) {
+        match self.free_points(order.clone()).await {
+            Ok(()) => {}
+            // Nothing to free, as when a lock left unanswered was never applied
+            Err(PointResponse::NotEnoughLockedPoints) => {
+                warn!("No points of {:?} were locked", order)
+            }
+            Err(e) => {
+                error!("Points of {:?} left locked: {}", order, e);
+                self.report.left_locked();
+            }
         }
     }
 }
 
 impl Handler<HandleOrder> for OrderHandler {
-    type Result = Result<(), String>;
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    /// The order stays in flight until its points are committed or freed.
+    /// The next order of the dispenser is not started until then,
+    /// but cancellations are still handled.
+    fn handle(&mut self, msg: HandleOrder, ctx: &mut Context<Self>) -> Self::Result {
+        let HandleOrder(ticket, order, supplies, taken, in_flight) = msg;
+        let cancellation = Cancellation::default();
+        self.orders
+            .borrow_mut()
+            .insert(ticket, cancellation.clone());
+        if let Some(deadline) = self.deadline {
+            ctx.run_later(deadline, move |handler, _ctx| {
+                let reason = format!("deadline of {:?} passed", deadline);
+                handler.cancel(Tickets::One(ticket), &reason);
+            });
+        }
 
-    fn handle(&mut self, msg: HandleOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let order = msg.0;
-        block_on(self.handle_order(order))
+        let handler = self.clone();
+        Box::pin(
+            async move {
+                let turn = handler.turn.lock().await;
+                // The dispenser may have been idle since its last order
+                handler.clock.catch_up(taken);
+                let res = handler
+                    .handle_order(ticket, order, supplies, &cancellation)
+                    .await;
+                handler.orders.borrow_mut().remove(&ticket);
+                drop(turn);
+                drop(in_flight);
+                res
+            }
+            .into_actor(self),
+        )
     }
 }
 
-impl Handler<WaitStop> for OrderHandler {
-    type Result = ();
+impl Handler<CancelOrder> for OrderHandler {
+    type Result = usize;
 
-    fn handle(&mut self, msg: WaitStop, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        match msg.0 {
-            Some(barrier) => {
-                trace!("Waiting for stop signal...");
-                barrier.wait();
-                trace!("Done");
-            }
-            None => {
-                debug!("Done");
-            }
+    fn handle(&mut self, msg: CancelOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        let CancelOrder(tickets, reason) = msg;
+        self.cancel(tickets, &reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_are_cancelled_once() {
+        let cancellation = Cancellation::default();
+        assert!(cancellation.cancel("first"));
+        assert!(!cancellation.cancel("second"));
+        assert_eq!(cancellation.reason(), Some("first".to_string()));
+        assert_eq!(cancellation.commit(), Err("first".to_string()));
+    }
+
+    fn fill(client_id: ClientId) -> Order {
+        Order {
+            client_id,
+            action: OrderAction::FillPoints(10),
+            product: None,
         }
     }
+
+    #[test]
+    fn purchases_in_flight_get_their_own_number() {
+        let engine = PromotionEngine::new(Promotions::parse("second,NTH,2,100").unwrap());
+        let now = SystemTime::now();
+        let number = |order: &mut Order| engine.apply(order, now).map(|(number, _)| number);
+
+        // Dos pedidos del mismo cliente en distintos dispensers a la vez
+        let (mut first, mut second) = (fill(1), fill(1));
+        assert_eq!(number(&mut first), Some(1));
+        assert_eq!(number(&mut second), Some(2));
+        assert_eq!(first.action, OrderAction::FillPoints(10));
+        assert_eq!(second.action, OrderAction::FillPoints(110));
+        assert_eq!(number(&mut fill(2)), Some(1));
+
+        // El número de un pedido que falla lo toma la próxima compra del cliente
+        engine.give_back(1, 1);
+        let mut third = fill(1);
+        assert_eq!(number(&mut third), Some(1));
+        assert_eq!(number(&mut fill(1)), Some(3));
+        assert_eq!(third.action, OrderAction::FillPoints(10));
+    }
+
+    #[test]
+    fn committing_orders_are_not_cancelled() {
+        let cancellation = Cancellation::default();
+        assert_eq!(cancellation.commit(), Ok(()));
+        assert!(!cancellation.cancel("too late"));
+        assert_eq!(cancellation.reason(), None);
+    }
 }
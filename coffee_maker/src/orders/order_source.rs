@@ -0,0 +1,491 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    net::TcpListener,
+    os::unix::{fs::FileTypeExt, net::UnixListener},
+    path::Path,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{Clock, Lifecycle, Order};
+use load_generator::{Generator, Workload};
+use points::{parse_addr, Catalog, OrderReader, RejectedLine};
+use tracing::{error, info};
+
+/// Prefix of a file that is read as it grows.
+const TAIL_PREFIX: &str = "tail:";
+/// Prefix of a TCP address where orders are pushed.
+const TCP_PREFIX: &str = "tcp:";
+/// Prefix of a Unix socket path where orders are pushed.
+const UNIX_PREFIX: &str = "unix:";
+/// Prefix of a workload file whose orders are generated.
+const GENERATED_PREFIX: &str = "gen:";
+/// Path that stands for the standard input.
+const STDIN_PATH: &str = "-";
+/// Wait before reading a tailed file again once its end is reached.
+const TAIL_POLL_MILLIS: u64 = 200;
+/// Generated orders waiting to be taken, the generator waits for them once it is full.
+const GENERATED_BUFFER: usize = 64;
+/// Wait for a live order before checking if the coffee maker is stopping.
+const LIVE_POLL_MILLIS: u64 = 200;
+/// Wait before accepting connections again after a failure, doubled on each failure in a row.
+const ACCEPT_RETRY_MIN_MILLIS: u64 = 10;
+const ACCEPT_RETRY_MAX_MILLIS: u64 = 1000;
+
+pub type TakenOrder = Result<Order, RejectedLine>;
+/// Orders read from a source, one at a time.
+pub type Orders = Box<dyn Iterator<Item = TakenOrder> + Send>;
+
+/// Where the orders of the coffee maker come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderSource {
+    /// A file that is read once, up to its end.
+    File(String),
+    /// The standard input, read until it is closed.
+    Stdin,
+    /// A file that is read from the start and then followed as it grows.
+    Tail(String),
+    /// A TCP address where POS terminals connect to push orders, a line per order.
+    Tcp(String),
+    /// A Unix socket where POS terminals connect to push orders, a line per order.
+    Unix(String),
+    /// A workload file, its orders are generated as they arrive.
+    Generated(String),
+}
+
+impl OrderSource {
+    /// Parses a source: `-` for the standard input, `tail:<path>`, `tcp:<address>`,
+    /// `unix:<path>`, `gen:<workload>`, or the path of a file otherwise.
+    pub fn parse(value: &str) -> Self {
+        if value == STDIN_PATH {
+            OrderSource::Stdin
+        } else if let Some(path) = value.strip_prefix(TAIL_PREFIX) {
+            OrderSource::Tail(path.to_string())
+        } else if let Some(addr) = value.strip_prefix(TCP_PREFIX) {
+            OrderSource::Tcp(parse_addr(addr.to_string()))
+        } else if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            OrderSource::Unix(path.to_string())
+        } else if let Some(path) = value.strip_prefix(GENERATED_PREFIX) {
+            OrderSource::Generated(path.to_string())
+        } else {
+            OrderSource::File(value.to_string())
+        }
+    }
+
+    /// Whether the orders arrive at their own pace, so they are taken as they arrive.
+    /// Only the orders of a file are all there from the start.
+    pub fn paces_orders(&self) -> bool {
+        !matches!(self, OrderSource::File(_))
+    }
+
+    /// The same source, with a relative path taken from the given directory.
+    pub fn relative_to(&self, dir: &Path) -> Self {
+        let resolve = |path: &String| dir.join(path).to_string_lossy().into_owned();
+        match self {
+            OrderSource::File(path) => OrderSource::File(resolve(path)),
+            OrderSource::Tail(path) => OrderSource::Tail(resolve(path)),
+            OrderSource::Unix(path) => OrderSource::Unix(resolve(path)),
+            OrderSource::Generated(path) => OrderSource::Generated(resolve(path)),
+            OrderSource::Stdin | OrderSource::Tcp(_) => self.clone(),
+        }
+    }
+
+    /// Path next to which the files of the run, like the report, are written.
+    pub fn output_path(&self) -> String {
+        match self {
+            OrderSource::File(path)
+            | OrderSource::Tail(path)
+            | OrderSource::Unix(path)
+            | OrderSource::Generated(path) => path.clone(),
+            OrderSource::Stdin => String::from("stdin"),
+            OrderSource::Tcp(addr) => format!("tcp-{}", addr.replace(':', "-")),
+        }
+    }
+
+    /// Opens the source, reading the orders that may reference the products of the catalog.
+    /// Live sources are read by their own threads, and stop yielding orders once the coffee maker stops.
+    /// Generated orders arrive as the clock passes.
+    pub fn open(
+        &self,
+        catalog: Arc<Catalog>,
+        clock: Arc<dyn Clock>,
+        lifecycle: Arc<Lifecycle>,
+    ) -> Result<Orders, String> {
+        let (sender, receiver) = channel();
+        match self {
+            OrderSource::File(path) => {
+                let file =
+                    File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+                return Ok(Box::new(OrderReader::with_catalog(
+                    BufReader::new(file),
+                    catalog,
+                )));
+            }
+            OrderSource::Stdin => {
+                spawn_reader(BufReader::new(io::stdin()), catalog, sender);
+            }
+            OrderSource::Tail(path) => {
+                let file =
+                    File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+                spawn_reader(BufReader::new(TailReader(file)), catalog, sender);
+            }
+            OrderSource::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .map_err(|e| format!("Could not listen on {}: {}", addr, e))?;
+                info!("Taking orders on {}", addr);
+                spawn_listener(listener, |listener| listener.accept(), catalog, sender);
+            }
+            OrderSource::Unix(path) => {
+                remove_stale_socket(path);
+                let listener = UnixListener::bind(path)
+                    .map_err(|e| format!("Could not listen on {}: {}", path, e))?;
+                info!("Taking orders on {}", path);
+                spawn_listener(listener, |listener| listener.accept(), catalog, sender);
+            }
+            OrderSource::Generated(path) => {
+                let generator = Generator::new(Workload::read(path)?);
+                info!(
+                    "Generating orders of {} with seed {}",
+                    path,
+                    generator.seed()
+                );
+                let (sender, receiver) = sync_channel(GENERATED_BUFFER);
+                spawn_generator(generator, clock, lifecycle.clone(), sender);
+                return Ok(Box::new(LiveOrders {
+                    receiver,
+                    lifecycle,
+                }));
+            }
+        }
+
+        Ok(Box::new(LiveOrders {
+            receiver,
+            lifecycle,
+        }))
+    }
+}
+
+/// Writes the source as it is parsed.
+impl fmt::Display for OrderSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderSource::File(path) => write!(f, "{}", path),
+            OrderSource::Stdin => write!(f, "{}", STDIN_PATH),
+            OrderSource::Tail(path) => write!(f, "{}{}", TAIL_PREFIX, path),
+            OrderSource::Tcp(addr) => write!(f, "{}{}", TCP_PREFIX, addr),
+            OrderSource::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path),
+            OrderSource::Generated(path) => write!(f, "{}{}", GENERATED_PREFIX, path),
+        }
+    }
+}
+
+/// Removes a socket left by a previous run, which can not be bound again.
+/// Any other file at the path is kept.
+pub fn remove_stale_socket(path: &str) {
+    let is_socket =
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Orders of a source taken by several machines, each order goes to the first one that asks.
+#[derive(Clone)]
+pub struct SharedOrders(Arc<Mutex<Orders>>);
+
+impl SharedOrders {
+    pub fn new(orders: Orders) -> Self {
+        SharedOrders(Arc::new(Mutex::new(orders)))
+    }
+}
+
+impl Iterator for SharedOrders {
+    type Item = TakenOrder;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.lock().ok()?.next()
+    }
+}
+
+/// Reads the orders of a stream in a new thread, until the stream ends or nobody takes them.
+fn spawn_reader<R>(reader: R, catalog: Arc<Catalog>, sender: Sender<TakenOrder>)
+where
+    R: BufRead + Send + 'static,
+{
+    thread::spawn(move || {
+        for order in OrderReader::with_catalog(reader, catalog) {
+            if sender.send(order).is_err() {
+                return;
+            }
+        }
+        info!("Order stream closed");
+    });
+}
+
+/// Generates the orders in a new thread, until they run out, nobody takes them or the coffee maker stops.
+fn spawn_generator(
+    generator: Generator,
+    clock: Arc<dyn Clock>,
+    lifecycle: Arc<Lifecycle>,
+    sender: SyncSender<TakenOrder>,
+) {
+    thread::spawn(move || {
+        for arrival in generator {
+            clock.sleep(arrival.delay);
+            if lifecycle.is_stopping() || sender.send(Ok(arrival.order)).is_err() {
+                return;
+            }
+        }
+        info!("Generated orders ran out");
+    });
+}
+
+/// Accepts connections in a new thread, reading the orders of each one in its own thread.
+/// After a failure, as when no more files can be opened, it waits before accepting again.
+fn spawn_listener<L, S, A>(
+    listener: L,
+    accept: fn(&L) -> io::Result<(S, A)>,
+    catalog: Arc<Catalog>,
+    sender: Sender<TakenOrder>,
+) where
+    L: Send + 'static,
+    S: Read + Send + 'static,
+    A: 'static,
+{
+    thread::spawn(move || {
+        let mut delay = Duration::from_millis(ACCEPT_RETRY_MIN_MILLIS);
+        loop {
+            match accept(&listener) {
+                Ok((stream, _)) => {
+                    delay = Duration::from_millis(ACCEPT_RETRY_MIN_MILLIS);
+                    spawn_reader(BufReader::new(stream), catalog.clone(), sender.clone())
+                }
+                Err(e) => {
+                    error!(
+                        "Could not accept connection, trying again in {:?}: {}",
+                        delay, e
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(Duration::from_millis(ACCEPT_RETRY_MAX_MILLIS));
+                }
+            }
+        }
+    });
+}
+
+/// Orders read by other threads.
+struct LiveOrders {
+    receiver: Receiver<TakenOrder>,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Iterator for LiveOrders {
+    type Item = TakenOrder;
+
+    /// Waits for the next order, ending once every stream is closed or the coffee maker stops.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self
+                .receiver
+                .recv_timeout(Duration::from_millis(LIVE_POLL_MILLIS))
+            {
+                Ok(order) => return Some(order),
+                Err(RecvTimeoutError::Timeout) if !self.lifecycle.is_stopping() => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// A file whose end is never reached, reads wait for it to grow.
+struct TailReader(File);
+
+impl Read for TailReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf)? {
+                0 => thread::sleep(Duration::from_millis(TAIL_POLL_MILLIS)),
+                read => return Ok(read),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream, process};
+
+    use super::*;
+    use crate::orders::{OrderAction, RealClock};
+
+    #[test]
+    fn parse_order_source() {
+        assert_eq!(OrderSource::parse("-"), OrderSource::Stdin);
+        assert_eq!(
+            OrderSource::parse("orders.csv"),
+            OrderSource::File("orders.csv".to_string())
+        );
+        assert_eq!(
+            OrderSource::parse("tail:orders.csv"),
+            OrderSource::Tail("orders.csv".to_string())
+        );
+        assert_eq!(
+            OrderSource::parse("tcp:9000"),
+            OrderSource::Tcp("localhost:9000".to_string())
+        );
+        assert_eq!(
+            OrderSource::parse("tcp:0.0.0.0:9000"),
+            OrderSource::Tcp("0.0.0.0:9000".to_string())
+        );
+        assert_eq!(
+            OrderSource::parse("unix:/tmp/orders.sock"),
+            OrderSource::Unix("/tmp/orders.sock".to_string())
+        );
+        assert_eq!(
+            OrderSource::parse("gen:load.toml"),
+            OrderSource::Generated("load.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn shared_orders_are_taken_once() {
+        let orders: Orders = Box::new((0..200).map(|client_id| {
+            Ok(Order {
+                client_id,
+                action: OrderAction::FillPoints(10),
+                product: None,
+            })
+        }));
+        let shared = SharedOrders::new(orders);
+
+        // Cada máquina toma pedidos desde su propio hilo, como el order taker
+        let machines: Vec<_> = (0..4)
+            .map(|_| {
+                let orders = shared.clone();
+                thread::spawn(move || {
+                    orders
+                        .map(|order| order.unwrap().client_id)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut taken: Vec<_> = machines
+            .into_iter()
+            .flat_map(|machine| machine.join().unwrap())
+            .collect();
+        taken.sort();
+        assert_eq!(taken, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn only_files_are_not_paced() {
+        assert!(!OrderSource::parse("orders.csv").paces_orders());
+        for value in [
+            "-",
+            "tail:a.csv",
+            "tcp:9000",
+            "unix:a.sock",
+            "gen:load.toml",
+        ] {
+            assert!(OrderSource::parse(value).paces_orders(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn sources_are_written_as_parsed() {
+        for value in [
+            "-",
+            "orders.csv",
+            "tail:a.csv",
+            "tcp:localhost:9000",
+            "unix:a.sock",
+        ] {
+            assert_eq!(OrderSource::parse(value).to_string(), value);
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_taken_from_the_directory() {
+        let dir = Path::new("configs");
+        let resolved = |value| OrderSource::parse(value).relative_to(dir).to_string();
+        assert_eq!(resolved("orders.csv"), "configs/orders.csv");
+        assert_eq!(resolved("gen:load.toml"), "gen:configs/load.toml");
+        assert_eq!(resolved("/tmp/orders.csv"), "/tmp/orders.csv");
+        assert_eq!(resolved("-"), "-");
+        assert_eq!(resolved("tcp:9000"), "tcp:localhost:9000");
+    }
+
+    fn live_orders() -> (Sender<TakenOrder>, LiveOrders, Arc<Lifecycle>) {
+        let (sender, receiver) = channel();
+        let lifecycle = Arc::new(Lifecycle::default());
+        let orders = LiveOrders {
+            receiver,
+            lifecycle: lifecycle.clone(),
+        };
+        (sender, orders, lifecycle)
+    }
+
+    #[test]
+    fn live_orders_end_once_every_stream_is_closed() {
+        let (sender, mut orders, _) = live_orders();
+        let order = Order::new(1, OrderAction::FillPoints(10));
+        sender.send(Ok(order.clone())).unwrap();
+        drop(sender);
+
+        assert_eq!(orders.next(), Some(Ok(order)));
+        assert_eq!(orders.next(), None);
+    }
+
+    #[test]
+    fn live_orders_end_once_the_coffee_maker_stops() {
+        let (_sender, mut orders, lifecycle) = live_orders();
+        lifecycle.stop();
+        // El stream sigue abierto, pero no se esperan más pedidos
+        assert_eq!(orders.next(), None);
+    }
+
+    fn socket_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.sock", name, process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn open(source: &OrderSource) -> Result<Orders, String> {
+        source.open(
+            Arc::new(Catalog::default()),
+            Arc::new(RealClock),
+            Arc::new(Lifecycle::default()),
+        )
+    }
+
+    #[test]
+    fn unix_source_replaces_a_stale_socket() {
+        let path = socket_path("stale");
+        let source = OrderSource::Unix(path.clone());
+        drop(UnixListener::bind(&path).unwrap());
+
+        let mut orders = open(&source).unwrap();
+        let mut pos = UnixStream::connect(&path).unwrap();
+        writeln!(pos, "1,FILL,10").unwrap();
+        assert_eq!(
+            orders.next(),
+            Some(Ok(Order::new(1, OrderAction::FillPoints(10))))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unix_source_keeps_other_files() {
+        let path = socket_path("regular");
+        fs::write(&path, "not a socket").unwrap();
+
+        assert!(open(&OrderSource::Unix(path.clone())).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
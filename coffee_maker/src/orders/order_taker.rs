@@ -1,16 +1,85 @@
 use std::{
     fs::File,
-    io::{BufRead, BufReader},
-    thread,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use super::*;
 use actix::prelude::*;
-use tracing::info;
+use points::{RejectedLine, COMMENT_PREFIX};
+use tracing::{error, info, warn};
 
 pub struct OrderTaker {
-    pub handler: Addr<OrderHandler>,
+    /// Handler of each dispenser, orders are handed out in turns
+    /// so every dispenser gets the same orders on every run.
+    pub handlers: Vec<Addr<OrderHandler>>,
+    /// Ingredients left in each dispenser, in the order of the handlers.
+    pub inventories: Vec<Arc<Mutex<Inventory>>>,
+    pub inventory: Arc<InventoryModel>,
+    /// File where the lines that are not valid orders are written.
+    pub dead_letter_path: String,
+    pub clock: Arc<dyn Clock>,
+    /// Time between orders taken, zero if the source paces them.
+    pub take_time: Duration,
+    pub report: Arc<RunReport>,
+    pub lifecycle: Arc<Lifecycle>,
+    /// Last ticket handed out by any machine, so every order of the process has its own.
+    pub tickets: Arc<AtomicU64>,
+}
+
+impl OrderTaker {
+    /// Writes the rejected line preceded by a comment with the error,
+    /// so the file can be fixed and taken again.
+    fn reject(&self, dead_letter: &mut Option<File>, rejected: RejectedLine) {
+        warn!("Order rejected: {}", rejected.error);
+
+        if dead_letter.is_none() {
+            match File::create(&self.dead_letter_path) {
+                Ok(file) => *dead_letter = Some(file),
+                Err(e) => {
+                    error!("Could not create {}: {}", self.dead_letter_path, e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(file) = dead_letter {
+            let written = writeln!(file, "{} {}", COMMENT_PREFIX, rejected.error)
+                .and_then(|_| writeln!(file, "{}", rejected.content));
+            if let Err(e) = written {
+                error!("Could not write to {}: {}", self.dead_letter_path, e);
+            }
+        }
+    }
+}
+
+impl OrderTaker {
+    /// Picks the dispenser of an order: the one of its turn, or the next one that has its ingredients.
+    /// The ingredients are taken from the stock of the dispenser as the order is taken,
+    /// so orders go to the same dispensers on every run.
+    /// If no dispenser has them, the order goes to the one of its turn to fail there.
+    fn route(&self, order: &Order, turn: usize) -> (usize, Supplies) {
+        let recipe = self.inventory.recipe(order);
+        let dispensers = self.handlers.len();
+        let mut missing = "stock";
+        for offset in 0..dispensers {
+            let dispenser = (turn + offset) % dispensers;
+            let taken = match self.inventories[dispenser].lock() {
+                Ok(mut inventory) => inventory.take(&recipe),
+                Err(_) => continue,
+            };
+            match taken {
+                Ok(supplies) => return (dispenser, Ok(supplies)),
+                Err(ingredient) if offset == 0 => missing = ingredient,
+                Err(_) => {}
+            }
+        }
+        (turn % dispensers, Err(missing))
+    }
 }
 
 impl Actor for OrderTaker {
@@ -21,18 +90,50 @@ impl Handler<TakeOrders> for OrderTaker {
     type Result = ();
 
     fn handle(&mut self, msg: TakeOrders, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let file_path = msg.0;
-        let file = File::open(file_path).unwrap();
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            if let Ok(order) = Order::parse(line.unwrap()) {
-                info!("Order taken: {:?}", order);
-                self.handler.do_send(HandleOrder(order));
-                thread::sleep(Duration::from_secs(1));
+        let orders = msg.0;
+        let mut dead_letter = None;
+        let mut taken: usize = 0;
+
+        for order in orders {
+            if self.lifecycle.is_stopping() {
+                warn!("Stopped taking orders");
+                break;
+            }
+            match order {
+                Ok(order) => {
+                    let ticket: Ticket = self.tickets.fetch_add(1, Ordering::SeqCst) + 1;
+                    info!("Order #{} taken: {:?}", ticket, order);
+                    self.report.taken();
+                    // Turns start from the first dispenser
+                    let (dispenser, supplies) = self.route(&order, taken);
+                    taken += 1;
+                    if let Err(ingredient) = supplies {
+                        warn!("No dispenser has {} for order #{}", ingredient, ticket);
+                    }
+                    self.handlers[dispenser].do_send(HandleOrder(
+                        ticket,
+                        order,
+                        supplies,
+                        self.clock.now(),
+                        self.lifecycle.start(),
+                    ));
+                    if let Some(refill) = self.inventory.refill() {
+                        if taken.is_multiple_of(refill) {
+                            refill_dispensers(&self.inventories, &self.report);
+                        }
+                    }
+                    self.clock.sleep(self.take_time);
+                }
+                Err(rejected) => {
+                    self.report.invalid();
+                    self.reject(&mut dead_letter, rejected);
+                }
             }
         }
 
+        if dead_letter.is_some() {
+            warn!("Rejected orders written to {}", self.dead_letter_path);
+        }
         info!("Done taking orders");
     }
 }
@@ -1,88 +1,610 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    time::Duration,
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use super::*;
 use actix::prelude::*;
-use points::{CLIENT_CONNECTION, MESSAGE_BUFFER_SIZE};
+use actix_rt::{net::TcpStream, task::JoinHandle, time::timeout};
+use points::{
+    read_tagged_frame, write_tagged_frame, RequestId, CLIENT_CONNECTION, MAX_FRAME_SIZE,
+    PROTOCOL_VERSION, VERSION_NEGOTIATION,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        lookup_host,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{oneshot, Mutex as AsyncMutex},
+};
+use tracing::{debug, error, info, warn};
 
-const READ_TIMEOUT: u64 = 1000;
+const CONNECT_TIMEOUT: u64 = 1000;
+/// Wait before reconnecting after every server failed, doubled on each failed round.
+const RECONNECT_MIN_MILLIS: u64 = 100;
+const RECONNECT_MAX_MILLIS: u64 = 5000;
+/// Times a request is sent again after the connection dropped or timed out while waiting for its response.
+const RESENDS: usize = 2;
 
-pub struct PointStorage {
-    local_server: TcpStream,
+type PendingRequests = Arc<Mutex<HashMap<RequestId, oneshot::Sender<PointResponse>>>>;
+
+/// Why a request got no response.
+enum Failure {
+    /// No server could be reached.
+    Unreachable,
+    /// The connection dropped, the request may or may not have been applied.
+    Dropped,
+    /// No response arrived in time, the request may or may not have been applied.
+    TimedOut,
 }
 
-impl Actor for PointStorage {
-    type Context = SyncContext<Self>;
+/// An open connection with a server.
+struct Link {
+    server: String,
+    read_timeout: Duration,
+    writer: AsyncMutex<OwnedWriteHalf>,
+    pending: PendingRequests,
+    closed: Arc<AtomicBool>,
+    /// Task that reads the responses.
+    reader: JoinHandle<()>,
 }
 
-impl PointStorage {
-    pub fn new(local_server_addr: String) -> Result<Self, String> {
-        let mut local_server =
-            TcpStream::connect(local_server_addr).or(Err("Could not connect to local server"))?;
+impl Link {
+    async fn connect(server: &str, read_timeout: Duration) -> Result<Self, String> {
+        let mut stream = None;
+        let addresses = lookup_host(server)
+            .await
+            .map_err(|_| "Invalid server address")?;
+        for address in addresses {
+            let connecting = TcpStream::connect(address);
+            if let Ok(Ok(connected)) =
+                timeout(Duration::from_millis(CONNECT_TIMEOUT), connecting).await
+            {
+                stream = Some(connected);
+                break;
+            }
+        }
+        let mut stream = stream.ok_or("Could not connect to server")?;
 
-        local_server
-            .set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT)))
-            .map_err(|_| "Could not set read timeout")?;
+        stream
+            .write_all(&[CLIENT_CONNECTION, VERSION_NEGOTIATION, PROTOCOL_VERSION])
+            .await
+            .map_err(|_| "Could not write to server")?;
 
-        local_server
-            .write_all(&[CLIENT_CONNECTION])
-            .map_err(|_| "Could not write to local server")?;
+        let mut version = [0; 1];
+        timeout(read_timeout, stream.read_exact(&mut version))
+            .await
+            .map_err(|_| "Could not negotiate protocol version")?
+            .map_err(|_| "Could not negotiate protocol version")?;
+        if version[0] != PROTOCOL_VERSION {
+            return Err(format!(
+                "Server does not support protocol version {}",
+                PROTOCOL_VERSION
+            ));
+        }
+
+        // Responses may take any time to arrive, requests time out on their own
+        let (reader, writer) = stream.into_split();
+        let pending: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = actix_rt::spawn(Self::dispatch_responses(
+            reader,
+            pending.clone(),
+            closed.clone(),
+        ));
 
-        Ok(PointStorage { local_server })
+        Ok(Link {
+            server: server.to_string(),
+            read_timeout,
+            writer: AsyncMutex::new(writer),
+            pending,
+            closed,
+            reader,
+        })
     }
 
-    fn write(&mut self, buf: [u8; MESSAGE_BUFFER_SIZE]) -> Result<(), String> {
-        self.local_server
-            .write_all(&buf)
-            .or(Err("Could not write to local server"))?;
-        Ok(())
+    /// Reads a frame written by `write_tagged_frame`.
+    async fn read_response(reader: &mut OwnedReadHalf) -> io::Result<(RequestId, Vec<u8>)> {
+        let mut frame = vec![0; 4];
+        reader.read_exact(&mut frame).await?;
+        let len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame is too large",
+            ));
+        }
+
+        frame.resize(4 + len, 0);
+        reader.read_exact(&mut frame[4..]).await?;
+        read_tagged_frame(&mut frame.as_slice())
     }
 
-    fn read(&mut self) -> Result<u8, String> {
-        let mut buf: [u8; 1] = [0];
-        self.local_server
-            .read_exact(&mut buf)
-            .map_err(|_| "Could not read from local server")?;
-        Ok(buf[0])
+    /// Reads responses until the connection is closed, handing each one to its request.
+    async fn dispatch_responses(
+        mut reader: OwnedReadHalf,
+        pending: PendingRequests,
+        closed: Arc<AtomicBool>,
+    ) {
+        while let Ok((id, frame)) = Self::read_response(&mut reader).await {
+            let response = PointResponse::try_from(frame.as_slice()).unwrap_or_else(|e| {
+                error!("Could not decode response: {}", e);
+                PointResponse::InternalError
+            });
+
+            let waiting = match pending.lock() {
+                Ok(mut pending) => pending.remove(&id),
+                Err(_) => break,
+            };
+            match waiting {
+                Some(request) => {
+                    let _ = request.send(response);
+                }
+                None => debug!("Response to request {} arrived too late", id),
+            }
+        }
+
+        error!("Connection with server closed");
+        closed.store(true, Ordering::SeqCst);
+        // Dropping the senders wakes up every request still waiting
+        if let Ok(mut pending) = pending.lock() {
+            pending.clear();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
     }
 
-    fn send(&mut self, msg: PointMessage) -> Result<(), String> {
-        self.write(msg.into())?;
-        let res = self.read()?;
-        if res == 0 {
-            Err("Local server returned error".to_string())
-        } else {
-            Ok(())
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.reader.abort();
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.clear();
+        }
+    }
+
+    fn forget(&self, id: RequestId) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&id);
+        }
+    }
+
+    async fn send(&self, id: RequestId, msg: &KeyedMessage) -> Result<PointResponse, Failure> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .map_err(|_| Failure::Dropped)?
+            .insert(id, sender);
+
+        let payload: Vec<u8> = msg.clone().into();
+        let mut frame = Vec::new();
+        let written = match write_tagged_frame(&mut frame, id, &payload) {
+            Ok(()) => self.writer.lock().await.write_all(&frame).await.is_ok(),
+            Err(_) => false,
+        };
+        if !written {
+            error!("Could not write to {}", self.server);
+            self.forget(id);
+            self.close();
+            return Err(Failure::Dropped);
+        }
+
+        match timeout(self.read_timeout, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(Failure::Dropped),
+            Err(_) => {
+                error!("Could not read from {}", self.server);
+                self.forget(id);
+                Err(Failure::TimedOut)
+            }
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// State of the connection, guarded as a whole so only one request reconnects.
+struct State {
+    link: Option<Arc<Link>>,
+    /// Wait until the next reconnection after every server failed.
+    delay: Duration,
+    retry_at: Instant,
+}
+
+/// Connection with the local server, shared by every request of the `PointStorage`.
+/// Each request is tagged with an id so many of them can be in flight at once.
+/// A reader task hands every response to the request waiting for it.
+/// The connection is opened by the first request. If it drops it is opened again,
+/// failing over to the backup servers when the local one can not be reached.
+pub struct Connection {
+    /// The local server followed by the backups, tried in order.
+    servers: Vec<String>,
+    /// Time to wait for each response.
+    read_timeout: Duration,
+    state: AsyncMutex<State>,
+    next_id: AtomicU32,
+}
+
+impl Connection {
+    /// The local server should go first in the servers.
+    pub fn new(servers: Vec<String>, read_timeout: Duration) -> Self {
+        Connection {
+            servers,
+            read_timeout,
+            state: AsyncMutex::new(State {
+                link: None,
+                delay: Duration::from_millis(RECONNECT_MIN_MILLIS),
+                retry_at: Instant::now(),
+            }),
+            next_id: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the open link, connecting to the first server that answers if there is none.
+    /// After every server failed, no server is tried again until the backoff is over.
+    async fn link(&self) -> Result<Arc<Link>, Failure> {
+        let mut state = self.state.lock().await;
+        if let Some(link) = &state.link {
+            if !link.is_closed() {
+                return Ok(link.clone());
+            }
+            warn!("Connection with {} lost", link.server);
+            state.link = None;
+        }
+        if Instant::now() < state.retry_at {
+            return Err(Failure::Unreachable);
+        }
+
+        for server in &self.servers {
+            match Link::connect(server, self.read_timeout).await {
+                Ok(link) => {
+                    info!("Connected to {}", server);
+                    let link = Arc::new(link);
+                    state.link = Some(link.clone());
+                    state.delay = Duration::from_millis(RECONNECT_MIN_MILLIS);
+                    return Ok(link);
+                }
+                Err(e) => warn!("{}: {}", server, e),
+            }
+        }
+
+        error!("No server reachable, trying again in {:?}", state.delay);
+        state.retry_at = Instant::now() + state.delay;
+        state.delay = (state.delay * 2).min(Duration::from_millis(RECONNECT_MAX_MILLIS));
+        Err(Failure::Unreachable)
+    }
+
+    /// Sends a message to the local server and waits for its response.
+    /// Error responses are returned as errors, `PointResponse::Unanswered` if the message
+    /// was sent but its outcome is unknown, including transient errors answered to a resent message.
+    /// If the connection drops or the response times out, the message is sent again
+    /// with the same key once connected, so the server applies it once and answers with its outcome.
+    /// Only the server that handled a key knows it: after failing over to a backup,
+    /// a message still being applied by the failed server may be applied twice.
+    async fn send(&self, msg: KeyedMessage) -> Result<PointResponse, PointResponse> {
+        let mut resends = 0;
+        // Once sent, the message may be applied even if no response arrives
+        let mut unanswered = false;
+        let response = loop {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let sent = match self.link().await {
+                Ok(link) => link.send(id, &msg).await,
+                Err(e) => Err(e),
+            };
+            match sent {
+                Ok(response) => break response,
+                Err(Failure::Unreachable) if !unanswered => return Err(PointResponse::Unreachable),
+                Err(Failure::Dropped | Failure::TimedOut) if resends < RESENDS => {
+                    unanswered = true;
+                    resends += 1;
+                    warn!("Sending {:?} again ({}/{})", msg.key, resends, RESENDS);
+                }
+                Err(_) => return Err(PointResponse::Unanswered),
+            }
+        };
+
+        match response {
+            res if res.is_ok() => Ok(res),
+            // A resent message that did not succeed may still be applied by the first attempt
+            err if unanswered && err.is_transient() => Err(PointResponse::Unanswered),
+            err => Err(err),
+        }
+    }
+}
+
+/// Sends the requests of every dispenser to the local server.
+/// Each request is handled by its own future, so many of them are in flight at once.
+pub struct PointStorage {
+    connection: Arc<Connection>,
+}
+
+impl Actor for PointStorage {
+    type Context = Context<Self>;
+}
+
+impl PointStorage {
+    pub fn new(connection: Connection) -> Self {
+        PointStorage {
+            connection: Arc::new(connection),
         }
     }
+
+    fn send(
+        &self,
+        msg: PointMessage,
+        key: IdempotencyKey,
+    ) -> ResponseFuture<Result<PointResponse, PointResponse>> {
+        let connection = self.connection.clone();
+        Box::pin(async move { connection.send(KeyedMessage { key, msg }).await })
+    }
 }
 
 impl Handler<LockOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = ResponseFuture<Result<(), PointResponse>>;
 
-    fn handle(&mut self, msg: LockOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let msg = PointMessage::LockOrder(msg.0);
-        self.send(msg)
+    fn handle(&mut self, msg: LockOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        let sent = self.send(PointMessage::LockOrder(msg.0), msg.1);
+        Box::pin(async move { sent.await.map(|_| ()) })
     }
 }
 
 impl Handler<FreeOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = ResponseFuture<Result<(), PointResponse>>;
 
-    fn handle(&mut self, msg: FreeOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let msg = PointMessage::FreeOrder(msg.0);
-        self.send(msg)
+    fn handle(&mut self, msg: FreeOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        let sent = self.send(PointMessage::FreeOrder(msg.0), msg.1);
+        Box::pin(async move { sent.await.map(|_| ()) })
     }
 }
 
 impl Handler<CommitOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = ResponseFuture<Result<(), PointResponse>>;
+
+    fn handle(&mut self, msg: CommitOrder, _ctx: &mut Context<Self>) -> Self::Result {
+        let sent = self.send(PointMessage::CommitOrder(msg.0), msg.1);
+        Box::pin(async move { sent.await.map(|_| ()) })
+    }
+}
+
+impl Handler<QueryBalance> for PointStorage {
+    type Result = ResponseFuture<Result<Balance, PointResponse>>;
+
+    fn handle(&mut self, msg: QueryBalance, _ctx: &mut Context<Self>) -> Self::Result {
+        let sent = self.send(PointMessage::QueryBalance(msg.0, msg.1), msg.2);
+        Box::pin(async move {
+            match sent.await? {
+                PointResponse::Balance(balance) => Ok(balance),
+                _ => Err(PointResponse::InternalError),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net, sync::mpsc, thread};
+
+    use super::*;
+
+    /// Starts a server that takes a single connection, negotiates the version and then
+    /// hands the connection to `serve`.
+    fn fake_server<F>(serve: F) -> String
+    where
+        F: FnOnce(net::TcpStream) + Send + 'static,
+    {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(accept(&listener)));
+        addr
+    }
+
+    /// Takes a connection and negotiates the version.
+    fn accept(listener: &net::TcpListener) -> net::TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut negotiation = [0; 3];
+        io::Read::read_exact(&mut stream, &mut negotiation).unwrap();
+        stream.write_all(&[PROTOCOL_VERSION]).unwrap();
+        stream
+    }
+
+    /// An address where nobody listens.
+    fn refused_address() -> String {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// Reads a request, returning its id and key.
+    fn receive(stream: &mut net::TcpStream) -> (RequestId, IdempotencyKey) {
+        let (id, frame) = read_tagged_frame(stream).unwrap();
+        (id, KeyedMessage::try_from(frame.as_slice()).unwrap().key)
+    }
+
+    fn respond(stream: &mut net::TcpStream, id: RequestId, response: PointResponse) {
+        let payload: Vec<u8> = response.into();
+        write_tagged_frame(stream, id, &payload).unwrap();
+    }
+
+    fn query(sequence: u64) -> KeyedMessage {
+        KeyedMessage {
+            key: IdempotencyKey {
+                coffee_maker: 1,
+                sequence,
+            },
+            msg: PointMessage::QueryBalance(1, ReadConsistency::Local),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn responses_go_to_their_request() {
+        let server = fake_server(|mut stream| {
+            let (first, _) = read_tagged_frame(&mut stream).unwrap();
+            let (second, _) = read_tagged_frame(&mut stream).unwrap();
+            // Se responde en otro orden, y a un pedido que nadie espera
+            respond(&mut stream, 42, PointResponse::Ok);
+            respond(&mut stream, second, PointResponse::NotEnoughPoints);
+            respond(&mut stream, first, PointResponse::Conflict);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let link = Link::connect(&server, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let (first_query, second_query) = (query(1), query(2));
+        let (first, second) = futures::join!(link.send(1, &first_query), async {
+            // El segundo pedido sale después del primero
+            actix_rt::time::sleep(Duration::from_millis(50)).await;
+            link.send(2, &second_query).await
+        });
+        assert!(matches!(first, Ok(PointResponse::Conflict)));
+        assert!(matches!(second, Ok(PointResponse::NotEnoughPoints)));
+        assert!(!link.is_closed());
+    }
+
+    #[actix_rt::test]
+    async fn requests_without_response_time_out() {
+        let server = fake_server(|mut stream| {
+            let _ = read_tagged_frame(&mut stream);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let link = Link::connect(&server, Duration::from_millis(100))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            link.send(1, &query(1)).await,
+            Err(Failure::TimedOut)
+        ));
+        assert!(link.pending.lock().unwrap().is_empty());
+        // La conexión sigue abierta para los próximos pedidos
+        assert!(!link.is_closed());
+    }
+
+    #[actix_rt::test]
+    async fn resent_requests_still_in_progress_are_unanswered() {
+        let server = fake_server(|mut stream| {
+            let _ = read_tagged_frame(&mut stream);
+            // El primer intento sigue en curso cuando llega el reenvío
+            let (resent, _) = read_tagged_frame(&mut stream).unwrap();
+            respond(&mut stream, resent, PointResponse::InProgress);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let connection = Connection::new(vec![server], Duration::from_millis(100));
+
+        assert_eq!(
+            connection.send(query(1)).await,
+            Err(PointResponse::Unanswered)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn requests_are_dropped_with_the_connection() {
+        let server = fake_server(|mut stream| {
+            let _ = read_tagged_frame(&mut stream);
+        });
+        let link = Link::connect(&server, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            link.send(1, &query(1)).await,
+            Err(Failure::Dropped)
+        ));
+        assert!(link.is_closed());
+    }
+
+    #[actix_rt::test]
+    async fn dropped_requests_are_resent_with_the_same_key() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let (keys, received) = mpsc::channel();
+        thread::spawn(move || {
+            // Se corta la conexión sin responder el primer envío
+            let (_, key) = receive(&mut accept(&listener));
+            keys.send(key).unwrap();
+            let mut stream = accept(&listener);
+            let (id, key) = receive(&mut stream);
+            keys.send(key).unwrap();
+            respond(&mut stream, id, PointResponse::Ok);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let connection = Connection::new(vec![server], Duration::from_secs(5));
+
+        assert_eq!(connection.send(query(7)).await, Ok(PointResponse::Ok));
+        let sent: Vec<_> = received.try_iter().collect();
+        assert_eq!(sent, vec![query(7).key, query(7).key]);
+    }
+
+    #[actix_rt::test]
+    async fn refused_connections_fail_over_to_the_backup() {
+        let backup = fake_server(|mut stream| {
+            let (id, _) = receive(&mut stream);
+            respond(&mut stream, id, PointResponse::Ok);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let connection = Connection::new(
+            vec![refused_address(), backup.clone()],
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(connection.send(query(1)).await, Ok(PointResponse::Ok));
+        let state = connection.state.lock().await;
+        assert_eq!(state.link.as_ref().map(|link| &link.server), Some(&backup));
+    }
+
+    #[actix_rt::test]
+    async fn servers_are_not_tried_again_until_the_backoff_is_over() {
+        let server = refused_address();
+        let connection = Connection::new(vec![server.clone()], Duration::from_secs(5));
+        assert_eq!(
+            connection.send(query(1)).await,
+            Err(PointResponse::Unreachable)
+        );
+
+        // El servidor vuelve, pero no se lo prueba hasta que pase la espera
+        let listener = net::TcpListener::bind(&server).unwrap();
+        thread::spawn(move || {
+            let mut stream = accept(&listener);
+            let (id, _) = receive(&mut stream);
+            respond(&mut stream, id, PointResponse::Ok);
+            thread::sleep(Duration::from_millis(500));
+        });
+        assert_eq!(
+            connection.send(query(2)).await,
+            Err(PointResponse::Unreachable)
+        );
+
+        actix_rt::time::sleep(Duration::from_millis(RECONNECT_MIN_MILLIS)).await;
+        assert_eq!(connection.send(query(3)).await, Ok(PointResponse::Ok));
+    }
+
+    #[actix_rt::test]
+    async fn requests_are_unanswered_once_resends_run_out() {
+        let (keys, received) = mpsc::channel();
+        let server = fake_server(move |mut stream| {
+            // Ningún envío recibe respuesta
+            while let Ok(frame) = read_tagged_frame(&mut stream) {
+                let keyed = KeyedMessage::try_from(frame.1.as_slice()).unwrap();
+                keys.send(keyed.key).unwrap();
+            }
+        });
+        let connection = Connection::new(vec![server], Duration::from_millis(100));
 
-    fn handle(&mut self, msg: CommitOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let msg = PointMessage::CommitOrder(msg.0);
-        self.send(msg)
+        assert_eq!(
+            connection.send(query(1)).await,
+            Err(PointResponse::Unanswered)
+        );
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(received.try_iter().count(), 1 + RESENDS);
     }
 }
@@ -0,0 +1,408 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use super::{ClientId, Ingredients, Order, OrderAction, PointResponse};
+
+/// Stages an order goes through in the handler, in order, timed in real time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Lock,
+    Brew,
+    Free,
+    Commit,
+    Transfer,
+}
+
+/// How a stage of an order ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageOutcome {
+    Succeeded,
+    /// Brewing failed, or the server refused the request.
+    Failed,
+    /// The server could not be reached, did not answer, or failed while handling the request.
+    Unavailable,
+}
+
+impl StageOutcome {
+    /// Outcome of a request to the server.
+    pub fn of(res: &Result<(), PointResponse>) -> Self {
+        match res {
+            Ok(()) => StageOutcome::Succeeded,
+            Err(
+                PointResponse::Unreachable
+                | PointResponse::Unanswered
+                | PointResponse::InProgress
+                | PointResponse::InternalError,
+            ) => StageOutcome::Unavailable,
+            Err(_) => StageOutcome::Failed,
+        }
+    }
+}
+
+/// How many orders reached each point of the run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Counts {
+    pub taken: usize,
+    /// Lines of the order file that were not valid orders.
+    pub invalid: usize,
+    pub locked: usize,
+    pub brewed: usize,
+    pub failed: usize,
+    pub freed: usize,
+    pub committed: usize,
+    /// Locks, commits and transfers refused by the server.
+    pub rejected: usize,
+    /// Locks, commits and transfers that the server did not answer, or failed to handle.
+    pub unavailable: usize,
+    /// Orders whose points could not be committed nor freed.
+    pub left_locked: usize,
+    /// Requests sent again after failing for transient reasons.
+    pub retried: usize,
+    /// Orders cancelled before their points were committed.
+    pub cancelled: usize,
+    /// Orders that failed because no dispenser had their ingredients.
+    pub out_of_stock: usize,
+    /// Times every dispenser was refilled.
+    pub refills: usize,
+}
+
+/// Latencies of a stage, in milliseconds with microsecond precision.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Orders of a client handled in the run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientTotals {
+    pub orders: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub earned: usize,
+    pub used: usize,
+    pub transferred: usize,
+}
+
+/// Summary of a run of the coffee maker.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub orders: Counts,
+    pub latencies: BTreeMap<Stage, Percentiles>,
+    /// Retries of the requests of each stage.
+    pub retries: BTreeMap<Stage, usize>,
+    /// Orders cancelled for each reason.
+    pub cancellations: BTreeMap<String, usize>,
+    /// Ingredients left in each dispenser, when their stock is limited.
+    pub stock: BTreeMap<usize, Ingredients>,
+    pub clients: BTreeMap<ClientId, ClientTotals>,
+}
+
+#[derive(Default)]
+struct Collected {
+    counts: Counts,
+    samples: HashMap<Stage, Vec<Duration>>,
+    retries: BTreeMap<Stage, usize>,
+    cancellations: BTreeMap<String, usize>,
+    stock: BTreeMap<usize, Ingredients>,
+    clients: BTreeMap<ClientId, ClientTotals>,
+}
+
+/// Collects the outcomes of the orders of a run, shared by the actors.
+#[derive(Default)]
+pub struct RunReport {
+    collected: Mutex<Collected>,
+}
+
+impl RunReport {
+    fn update(&self, update: impl FnOnce(&mut Collected)) {
+        if let Ok(mut collected) = self.collected.lock() {
+            update(&mut collected);
+        }
+    }
+
+    pub fn taken(&self) {
+        self.update(|collected| collected.counts.taken += 1);
+    }
+
+    pub fn invalid(&self) {
+        self.update(|collected| collected.counts.invalid += 1);
+    }
+
+    pub fn left_locked(&self) {
+        self.update(|collected| collected.counts.left_locked += 1);
+    }
+
+    /// Records that a request of the stage is sent again.
+    pub fn retried(&self, stage: Stage) {
+        self.update(|collected| {
+            collected.counts.retried += 1;
+            *collected.retries.entry(stage).or_default() += 1;
+        });
+    }
+
+    /// Records that an order was cancelled for the reason.
+    pub fn cancelled(&self, reason: &str) {
+        self.update(|collected| {
+            collected.counts.cancelled += 1;
+            *collected
+                .cancellations
+                .entry(reason.to_string())
+                .or_default() += 1;
+        });
+    }
+
+    pub fn out_of_stock(&self) {
+        self.update(|collected| collected.counts.out_of_stock += 1);
+    }
+
+    pub fn refilled(&self) {
+        self.update(|collected| collected.counts.refills += 1);
+    }
+
+    /// Records the ingredients left in a dispenser.
+    pub fn stock(&self, dispenser: usize, stock: Ingredients) {
+        self.update(|collected| {
+            collected.stock.insert(dispenser, stock);
+        });
+    }
+
+    /// Records how long a stage took and how it ended.
+    pub fn stage(&self, stage: Stage, elapsed: Duration, outcome: StageOutcome) {
+        self.update(|collected| {
+            collected.samples.entry(stage).or_default().push(elapsed);
+            let counts = &mut collected.counts;
+            match (stage, outcome) {
+                (Stage::Lock, StageOutcome::Succeeded) => counts.locked += 1,
+                (Stage::Brew, StageOutcome::Succeeded) => counts.brewed += 1,
+                (Stage::Brew, _) => counts.failed += 1,
+                (Stage::Free, StageOutcome::Succeeded) => counts.freed += 1,
+                (Stage::Commit | Stage::Transfer, StageOutcome::Succeeded) => counts.committed += 1,
+                (Stage::Free, _) => {}
+                (_, StageOutcome::Failed) => counts.rejected += 1,
+                (_, StageOutcome::Unavailable) => counts.unavailable += 1,
+            }
+        });
+    }
+
+    /// Records the outcome of a whole order for its client.
+    pub fn finished(&self, order: &Order, succeeded: bool) {
+        self.update(|collected| {
+            let totals = collected.clients.entry(order.client_id).or_default();
+            totals.orders += 1;
+            if !succeeded {
+                totals.failed += 1;
+                return;
+            }
+            totals.succeeded += 1;
+            match order.action {
+                OrderAction::FillPoints(points) => totals.earned += points,
+                OrderAction::UsePoints(points) => totals.used += points,
+                OrderAction::Transfer { points, .. } => totals.transferred += points,
+            }
+        });
+    }
+
+    pub fn summary(&self) -> Report {
+        let collected = match self.collected.lock() {
+            Ok(collected) => collected,
+            Err(_) => return Report::default(),
+        };
+        Report {
+            orders: collected.counts.clone(),
+            latencies: collected
+                .samples
+                .iter()
+                .map(|(stage, samples)| (*stage, Percentiles::of(samples)))
+                .collect(),
+            retries: collected.retries.clone(),
+            cancellations: collected.cancellations.clone(),
+            stock: collected.stock.clone(),
+            clients: collected.clients.clone(),
+        }
+    }
+}
+
+impl Report {
+    /// Orders that did not succeed.
+    pub fn failed_orders(&self) -> usize {
+        self.clients.values().map(|totals| totals.failed).sum()
+    }
+}
+
+impl Percentiles {
+    fn of(samples: &[Duration]) -> Self {
+        let mut millis: Vec<f64> = samples
+            .iter()
+            .map(|sample| sample.as_micros() as f64 / 1000.0)
+            .collect();
+        millis.sort_by(|a, b| a.total_cmp(b));
+
+        // Nearest rank
+        let rank = |percentile: usize| {
+            let index = (percentile * millis.len()).div_ceil(100).max(1) - 1;
+            millis.get(index).copied().unwrap_or_default()
+        };
+        Percentiles {
+            count: millis.len(),
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: millis.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Lock => "lock",
+            Stage::Brew => "brew",
+            Stage::Free => "free",
+            Stage::Commit => "commit",
+            Stage::Transfer => "transfer",
+        };
+        f.pad(name)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let orders = &self.orders;
+        writeln!(
+            f,
+            "Orders: {} taken, {} invalid, {} locked, {} brewed, {} failed, {} freed, {} committed, {} rejected, {} unavailable, {} left locked, {} retried, {} cancelled, {} out of stock, {} refills",
+            orders.taken,
+            orders.invalid,
+            orders.locked,
+            orders.brewed,
+            orders.failed,
+            orders.freed,
+            orders.committed,
+            orders.rejected,
+            orders.unavailable,
+            orders.left_locked,
+            orders.retried,
+            orders.cancelled,
+            orders.out_of_stock,
+            orders.refills
+        )?;
+
+        writeln!(
+            f,
+            "\n{:<10}{:>8}{:>10}{:>10}{:>10}{:>10}{:>9}",
+            "stage", "count", "p50 ms", "p90 ms", "p99 ms", "max ms", "retries"
+        )?;
+        for (stage, latency) in &self.latencies {
+            writeln!(
+                f,
+                "{:<10}{:>8}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>9}",
+                stage,
+                latency.count,
+                latency.p50,
+                latency.p90,
+                latency.p99,
+                latency.max,
+                self.retries.get(stage).copied().unwrap_or_default()
+            )?;
+        }
+
+        if !self.cancellations.is_empty() {
+            writeln!(f, "\n{:<30}{:>8}", "cancelled for", "orders")?;
+            for (reason, orders) in &self.cancellations {
+                writeln!(f, "{:<30}{:>8}", reason, orders)?;
+            }
+        }
+
+        if !self.stock.is_empty() {
+            writeln!(f, "\n{:<10}stock left", "dispenser")?;
+            for (dispenser, stock) in &self.stock {
+                writeln!(f, "{:<10}{}", dispenser, stock)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "\n{:<10}{:>8}{:>11}{:>8}{:>8}{:>8}{:>13}",
+            "client", "orders", "succeeded", "failed", "earned", "used", "transferred"
+        )?;
+        for (client, totals) in &self.clients {
+            writeln!(
+                f,
+                "{:<10}{:>8}{:>11}{:>8}{:>8}{:>8}{:>13}",
+                client,
+                totals.orders,
+                totals.succeeded,
+                totals.failed,
+                totals.earned,
+                totals.used,
+                totals.transferred
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(samples: &[u64]) -> Vec<Duration> {
+        samples
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect()
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let percentiles = Percentiles::of(&millis(&(1..=10).rev().collect::<Vec<u64>>()));
+        assert_eq!(percentiles.count, 10);
+        assert_eq!(percentiles.p50, 5.0);
+        assert_eq!(percentiles.p90, 9.0);
+        assert_eq!(percentiles.p99, 10.0);
+        assert_eq!(percentiles.max, 10.0);
+
+        let single = Percentiles::of(&millis(&[3]));
+        assert_eq!((single.p50, single.p99, single.max), (3.0, 3.0, 3.0));
+        assert_eq!(Percentiles::of(&[]).p50, 0.0);
+    }
+
+    #[test]
+    fn refusals_and_unavailable_servers_are_counted_apart() {
+        let report = RunReport::default();
+        let elapsed = Duration::ZERO;
+        report.stage(
+            Stage::Lock,
+            elapsed,
+            StageOutcome::of(&Err(PointResponse::NotEnoughPoints)),
+        );
+        report.stage(
+            Stage::Commit,
+            elapsed,
+            StageOutcome::of(&Err(PointResponse::Unreachable)),
+        );
+        report.stage(
+            Stage::Lock,
+            elapsed,
+            StageOutcome::of(&Err(PointResponse::Unanswered)),
+        );
+        report.stage(Stage::Lock, elapsed, StageOutcome::of(&Ok(())));
+
+        let counts = report.summary().orders;
+        assert_eq!(
+            (counts.locked, counts.rejected, counts.unavailable),
+            (1, 1, 2)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+const DEFAULT_BACKOFF_MILLIS: u64 = 200;
+const DEFAULT_MAX_BACKOFF_MILLIS: u64 = 2000;
+const DEFAULT_DEADLINE_MILLIS: u64 = 5000;
+
+/// How requests that fail for transient reasons are sent again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts of a request, counting the first one.
+    pub max_attempts: usize,
+    /// Wait before the first retry, doubled on each of the next ones.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time since the first attempt after which the request is not sent again.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: Duration::from_millis(DEFAULT_BACKOFF_MILLIS),
+            max_backoff: Duration::from_millis(DEFAULT_MAX_BACKOFF_MILLIS),
+            deadline: Duration::from_millis(DEFAULT_DEADLINE_MILLIS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Parses a policy with the format `<max_attempts>[,<backoff_millis>[,<deadline_millis>]]`,
+    /// the values not given are taken from the base policy.
+    pub fn parse(value: &str, base: RetryPolicy) -> Result<Self, String> {
+        let fields: Vec<&str> = value.split(',').map(str::trim).collect();
+        let mut policy = base;
+
+        let (attempts, backoff, deadline) = match fields[..] {
+            [attempts] => (attempts, None, None),
+            [attempts, backoff] => (attempts, Some(backoff), None),
+            [attempts, backoff, deadline] => (attempts, Some(backoff), Some(deadline)),
+            _ => return Err(format!("Invalid retry policy: {:?}", value)),
+        };
+        policy.max_attempts = attempts
+            .parse::<usize>()
+            .ok()
+            .filter(|attempts| *attempts > 0)
+            .ok_or_else(|| format!("Invalid attempts: {:?}", attempts))?;
+        if let Some(backoff) = backoff {
+            policy.backoff = parse_millis(backoff)?;
+            policy.max_backoff = policy.max_backoff.max(policy.backoff);
+        }
+        if let Some(deadline) = deadline {
+            policy.deadline = parse_millis(deadline)?;
+        }
+        Ok(policy)
+    }
+
+    /// Wait before sending a request again, after the given attempts failed in the elapsed time.
+    /// Half of the backoff is drawn from the given random stream,
+    /// so requests that failed together are not sent together again.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the request should not be sent again.
+    pub fn next_delay<R: Rng>(
+        &self,
+        attempts: usize,
+        elapsed: Duration,
+        rng: &mut R,
+    ) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        let doublings = attempts.saturating_sub(1).min(31) as u32;
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        let delay = backoff / 2 + backoff.mul_f64(rng.gen_range(0.0..=0.5));
+
+        if elapsed + delay > self.deadline {
+            return None;
+        }
+        Some(delay)
+    }
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid milliseconds: {:?}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn policy(max_attempts: usize, backoff: u64, max_backoff: u64, deadline: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff: Duration::from_millis(backoff),
+            max_backoff: Duration::from_millis(max_backoff),
+            deadline: Duration::from_millis(deadline),
+        }
+    }
+
+    #[test]
+    fn parse_retry_policy() {
+        let base = policy(3, 200, 2000, 5000);
+        assert_eq!(
+            RetryPolicy::parse("5", base.clone()),
+            Ok(policy(5, 200, 2000, 5000))
+        );
+        assert_eq!(
+            RetryPolicy::parse("4, 100, 1000", base.clone()),
+            Ok(policy(4, 100, 2000, 1000))
+        );
+        // La espera máxima nunca queda por debajo de la primera
+        assert_eq!(
+            RetryPolicy::parse("2,3000", base.clone()),
+            Ok(policy(2, 3000, 3000, 5000))
+        );
+
+        for invalid in ["0", "x", "", "3,x", "3,100,-1", "3,100,1000,1"] {
+            assert!(
+                RetryPolicy::parse(invalid, base.clone()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_its_cap() {
+        let policy = policy(10, 100, 500, 60_000);
+        let mut rng = StdRng::seed_from_u64(0);
+        for (attempts, backoff) in [(1, 100), (2, 200), (3, 400), (4, 500), (9, 500)] {
+            let backoff = Duration::from_millis(backoff);
+            let delay = policy
+                .next_delay(attempts, Duration::ZERO, &mut rng)
+                .unwrap();
+            assert!(backoff / 2 <= delay && delay <= backoff, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn no_retries_past_the_attempts_or_the_deadline() {
+        let policy = policy(3, 100, 100, 1000);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(policy.next_delay(2, Duration::ZERO, &mut rng).is_some());
+        assert_eq!(policy.next_delay(3, Duration::ZERO, &mut rng), None);
+        // Aunque la espera sea la mínima, terminaría después del plazo
+        assert_eq!(
+            policy.next_delay(1, Duration::from_millis(951), &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn same_stream_same_delays() {
+        let policy = RetryPolicy::default();
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (1..3)
+                .map(|attempts| policy.next_delay(attempts, Duration::ZERO, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(7), delays(7));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::Duration,
+};
+
+use crate::orders::*;
+use actix::prelude::*;
+use futures::future::join_all;
+use points::Promotions;
+
+/// Settings and state shared by every coffee maker of the process.
+pub struct Store {
+    /// The local server followed by the backups.
+    pub servers: Vec<String>,
+    pub read_timeout: Duration,
+    pub promotions: Promotions,
+    /// Clock of the store, every actor forks its own from it.
+    pub clock: Arc<dyn Clock>,
+    pub failures: Arc<FailureModel>,
+    pub seed: u64,
+    pub success_chance: f64,
+    pub order_time: Duration,
+    pub take_time: Duration,
+    pub retry: Arc<RetryPolicy>,
+    pub order_deadline: Option<Duration>,
+    pub inventory: Arc<InventoryModel>,
+    pub lifecycle: Arc<Lifecycle>,
+    /// Last ticket handed out by any machine.
+    pub tickets: Arc<AtomicU64>,
+}
+
+/// A coffee maker of the store.
+/// It has its own dispensers, connection with the local server, idempotency keys and report.
+#[derive(Clone)]
+pub struct Machine {
+    pub report: Arc<RunReport>,
+    taker: Addr<OrderTaker>,
+    handlers: Vec<Addr<OrderHandler>>,
+    inventories: Vec<Arc<Mutex<Inventory>>>,
+}
+
+/// What the dispensers of a machine share.
+struct MachineParts {
+    id: usize,
+    report: Arc<RunReport>,
+    point_storage: Addr<PointStorage>,
+    keys: Arc<KeyGenerator>,
+    promotions: Arc<PromotionEngine>,
+}
+
+impl Store {
+    /// Starts the actors of a machine, its dead letter file is the one given.
+    /// The dispensers of machine 0 draw the same failures as a coffee maker alone with the same seed.
+    /// Orders of a source that paces them are taken as they arrive, on the clock of the source,
+    /// the rest are taken every take time.
+    pub fn start_machine(
+        &self,
+        id: usize,
+        dispensers: usize,
+        paced_by: Option<Arc<dyn Clock>>,
+        dead_letter_path: String,
+    ) -> Machine {
+        let parts = self.machine_parts(id);
+        let report = parts.report.clone();
+        // Dispensers start full
+        let inventories: Vec<Arc<Mutex<Inventory>>> = (0..dispensers)
+            .map(|_| Arc::new(Mutex::new(self.inventory.inventory())))
+            .collect();
+        // Every dispenser has its own handler, so it takes its orders in the same order on every run
+        let handlers: Vec<Addr<OrderHandler>> = (0..dispensers)
+            .map(|dispenser| {
+                self.handler(&parts, dispenser, inventories[dispenser].clone())
+                    .start()
+            })
+            .collect();
+
+        let (clock, take_time) = match paced_by {
+            Some(clock) => (clock, Duration::ZERO),
+            None => (self.clock.fork(), self.take_time),
+        };
+        let taker_report = report.clone();
+        let lifecycle = self.lifecycle.clone();
+        let tickets = self.tickets.clone();
+        let taker_handlers = handlers.clone();
+        let taker_inventories = inventories.clone();
+        let inventory = self.inventory.clone();
+        let taker = SyncArbiter::start(1, move || OrderTaker {
+            handlers: taker_handlers.clone(),
+            inventories: taker_inventories.clone(),
+            inventory: inventory.clone(),
+            dead_letter_path: dead_letter_path.clone(),
+            clock: clock.clone(),
+            take_time,
+            report: taker_report.clone(),
+            lifecycle: lifecycle.clone(),
+            tickets: tickets.clone(),
+        });
+
+        Machine {
+            report,
+            taker,
+            handlers,
+            inventories,
+        }
+    }
+
+    /// Starts the connection of a machine and sets up the rest of what its dispensers share.
+    fn machine_parts(&self, id: usize) -> MachineParts {
+        MachineParts {
+            id,
+            report: Arc::new(RunReport::default()),
+            point_storage: PointStorage::new(Connection::new(
+                self.servers.clone(),
+                self.read_timeout,
+            ))
+            .start(),
+            // Keys stay random, so replays against the same servers are not taken as retries
+            keys: Arc::new(KeyGenerator::new(rand::random())),
+            // Purchases are counted by each machine on its own
+            promotions: Arc::new(PromotionEngine::new(self.promotions.clone())),
+        }
+    }
+
+    /// Handler of a dispenser of the machine, with its own random streams.
+    fn handler(
+        &self,
+        machine: &MachineParts,
+        dispenser: usize,
+        inventory: Arc<Mutex<Inventory>>,
+    ) -> OrderHandler {
+        OrderHandler {
+            point_storage: machine.point_storage.clone(),
+            success_chance: self.success_chance,
+            order_time: self.order_time,
+            dispenser: Rc::new(RefCell::new(Dispenser::new(
+                machine.id,
+                dispenser,
+                self.seed,
+                self.failures.clone(),
+            ))),
+            inventory,
+            keys: machine.keys.clone(),
+            promotions: machine.promotions.clone(),
+            clock: self.clock.fork(),
+            report: machine.report.clone(),
+            retry: self.retry.clone(),
+            retry_rng: Rc::new(RefCell::new(derive_rng(
+                self.seed,
+                machine.id,
+                dispenser,
+                RngStream::Retries,
+            ))),
+            deadline: self.order_deadline,
+            orders: Default::default(),
+            turn: Default::default(),
+        }
+    }
+}
+
+impl Machine {
+    /// Takes the orders until they run out or the store stops.
+    pub async fn take(&self, orders: Orders) -> Result<(), MailboxError> {
+        self.taker.send(TakeOrders(orders)).await
+    }
+
+    /// Records the ingredients left in each dispenser with a limited stock.
+    pub fn report_stock(&self) {
+        for (dispenser, inventory) in self.inventories.iter().enumerate() {
+            if let Some(stock) = inventory
+                .lock()
+                .ok()
+                .and_then(|inventory| inventory.stock())
+            {
+                self.report.stock(dispenser, stock);
+            }
+        }
+    }
+
+    /// Cancels the order with the ticket if it is queued or in progress on the machine,
+    /// returns how many orders were cancelled.
+    pub async fn cancel(&self, ticket: Ticket, reason: &str) -> usize {
+        let cancelling = self
+            .handlers
+            .iter()
+            .map(|handler| handler.send(CancelOrder(Tickets::One(ticket), reason.to_string())));
+        join_all(cancelling).await.into_iter().flatten().sum()
+    }
+
+    /// Fills every dispenser of the machine up to its capacity.
+    pub fn refill(&self) {
+        refill_dispensers(&self.inventories, &self.report);
+    }
+
+    /// Cancels every order queued or in progress on the dispensers of the machine.
+    pub fn cancel_all(&self, reason: &str) {
+        for handler in &self.handlers {
+            handler.do_send(CancelOrder(Tickets::All, reason.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{Order, OrderAction};
+    use rand::Rng;
+
+    use super::*;
+
+    const SEED: u64 = 7;
+
+    fn store() -> Store {
+        Store {
+            servers: vec![],
+            read_timeout: Duration::from_secs(1),
+            promotions: Promotions::default(),
+            clock: Arc::new(RealClock),
+            failures: Arc::new(FailureModel::default()),
+            seed: SEED,
+            success_chance: 0.5,
+            order_time: Duration::ZERO,
+            take_time: Duration::ZERO,
+            retry: Arc::new(RetryPolicy::default()),
+            order_deadline: None,
+            inventory: Arc::new(InventoryModel::default()),
+            lifecycle: Arc::new(Lifecycle::default()),
+            tickets: Default::default(),
+        }
+    }
+
+    /// Whether each of the next orders of the dispenser is brewed.
+    fn brews(dispenser: &mut Dispenser) -> Vec<bool> {
+        let order = Order {
+            client_id: 1,
+            action: OrderAction::FillPoints(10),
+            product: None,
+        };
+        (0..64)
+            .map(|_| dispenser.brew(0.5, &order).is_ok())
+            .collect()
+    }
+
+    fn retries(handler: &OrderHandler) -> Vec<u64> {
+        let mut rng = handler.retry_rng.borrow_mut();
+        (0..8).map(|_| rng.gen()).collect()
+    }
+
+    #[actix_rt::test]
+    async fn machines_have_their_own_keys_and_streams() {
+        let store = store();
+        let (first, second) = (store.machine_parts(0), store.machine_parts(1));
+        let inventory = || Arc::new(Mutex::new(store.inventory.inventory()));
+        let handlers = [
+            store.handler(&first, 0, inventory()),
+            store.handler(&first, 1, inventory()),
+            store.handler(&second, 0, inventory()),
+        ];
+
+        // Los dispensers de una máquina comparten las claves, cada máquina tiene las suyas
+        assert!(Arc::ptr_eq(&handlers[0].keys, &handlers[1].keys));
+        assert!(!Arc::ptr_eq(&handlers[0].keys, &handlers[2].keys));
+        assert_ne!(
+            handlers[0].keys.next().coffee_maker,
+            handlers[2].keys.next().coffee_maker
+        );
+
+        // La máquina 0 sortea lo mismo que una cafetera sola con la misma semilla
+        let alone = brews(&mut Dispenser::new(0, 0, SEED, store.failures.clone()));
+        let brewed: Vec<_> = handlers
+            .iter()
+            .map(|handler| brews(&mut handler.dispenser.borrow_mut()))
+            .collect();
+        assert_eq!(brewed[0], alone);
+        assert_ne!(brewed[0], brewed[1]);
+        assert_ne!(brewed[0], brewed[2]);
+
+        let retried: Vec<_> = handlers.iter().map(retries).collect();
+        assert_ne!(retried[0], retried[1]);
+        assert_ne!(retried[0], retried[2]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, io::BufRead};
+
+use crate::{
+    error::{ParseError, ParseErrorReason},
+    order::Fields,
+    order_file::{is_header, read_rules},
+};
+
+/// Optional header of a catalog file. Compared ignoring case.
+pub const CATALOG_FILE_HEADER: [&str; 4] = ["product", "price", "cost", "earns"];
+
+/// A product that can be ordered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Product {
+    /// Price in cents when paid with money.
+    pub price: u64,
+    /// Points used when it is redeemed.
+    pub cost: usize,
+    /// Points earned when it is bought.
+    pub earns: usize,
+}
+
+/// Products that can be ordered, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    products: HashMap<String, Product>,
+}
+
+impl Catalog {
+    /// Reads a catalog with a line per product, with the format `<product>,<price>,<cost>,<earns>`.
+    /// Blank lines, comments and the header are skipped as in order files.
+    /// Unlike order files, the first invalid line fails the whole catalog.
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, ParseError> {
+        let mut catalog = Catalog::default();
+        let mut seen_content = false;
+
+        read_rules(reader, |content| {
+            let first = !seen_content;
+            seen_content = true;
+            if first && is_header(content.trim(), &CATALOG_FILE_HEADER) {
+                return Ok(());
+            }
+            catalog.parse_product(content)
+        })?;
+
+        Ok(catalog)
+    }
+
+    /// Reads a catalog from the given content.
+    pub fn parse(content: &str) -> Result<Self, ParseError> {
+        Self::read(content.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Product> {
+        self.products.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.products.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty()
+    }
+
+    fn parse_product(&mut self, line: &str) -> Result<(), ParseError> {
+        let mut fields = Fields::new(line);
+
+        let (name_column, name) = fields.next_or_missing("product")?;
+
+        let (column, price) = fields.next_or_missing("price")?;
+        let price = price.parse::<u64>().map_err(|_| {
+            ParseError::new(column, ParseErrorReason::InvalidPrice(price.to_string()))
+        })?;
+
+        let mut points = |field| {
+            let (column, points) = fields.next_or_missing(field)?;
+            points.parse::<usize>().map_err(|_| {
+                ParseError::new(column, ParseErrorReason::InvalidPoints(points.to_string()))
+            })
+        };
+        let cost = points("cost")?;
+        let earns = points("earns")?;
+        fields.expect_end()?;
+
+        if self.products.contains_key(name) {
+            return Err(ParseError::new(
+                name_column,
+                ParseErrorReason::DuplicateProduct(name.to_string()),
+            ));
+        }
+        self.products
+            .insert(name.to_string(), Product { price, cost, earns });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_catalog() {
+        let catalog =
+            Catalog::parse("Product,Price,Cost,Earns\n# cafes\n\nespresso, 250, 30, 5\n").unwrap();
+        assert_eq!(catalog.len(), 1);
+        assert_eq!(
+            catalog.get("espresso"),
+            Some(&Product {
+                price: 250,
+                cost: 30,
+                earns: 5
+            })
+        );
+        assert_eq!(catalog.get("latte"), None);
+    }
+
+    #[test]
+    fn invalid_catalog_lines() {
+        assert_eq!(
+            Catalog::parse("espresso,250,30,5\nlatte,2.5,30,5\n"),
+            Err(ParseError::new(7, ParseErrorReason::InvalidPrice("2.5".to_string())).at_line(2))
+        );
+        assert_eq!(
+            Catalog::parse("espresso,250,30\n"),
+            Err(ParseError::new(16, ParseErrorReason::MissingField("earns")))
+        );
+        assert_eq!(
+            Catalog::parse("espresso,250,30,5\nespresso,300,40,6\n"),
+            Err(ParseError::new(
+                1,
+                ParseErrorReason::DuplicateProduct("espresso".to_string())
+            )
+            .at_line(2))
+        );
+    }
+}
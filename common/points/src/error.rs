@@ -0,0 +1,147 @@
+use std::fmt;
+
+/// Error returned when bytes received from the network do not form a valid value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer does not have the expected length.
+    InvalidSize {
+        expected: usize,
+        actual: usize,
+    },
+    UnknownMessageType(u8),
+    UnknownActionType(u8),
+    UnknownReadConsistency(u8),
+    UnknownResponseCode(u8),
+    /// The amount of points does not fit in memory.
+    PointsOverflow(u64),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidSize { expected, actual } => {
+                write!(
+                    f,
+                    "Invalid size: expected {} bytes, got {}",
+                    expected, actual
+                )
+            }
+            DecodeError::UnknownMessageType(t) => write!(f, "Unknown message type: {}", t),
+            DecodeError::UnknownActionType(t) => write!(f, "Unknown action type: {}", t),
+            DecodeError::UnknownReadConsistency(c) => write!(f, "Unknown read consistency: {}", c),
+            DecodeError::UnknownResponseCode(c) => write!(f, "Unknown response code: {}", c),
+            DecodeError::PointsOverflow(p) => write!(f, "Points do not fit in memory: {}", p),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Checks that the buffer has exactly the expected length.
+pub(crate) fn expect_size(buf: &[u8], expected: usize) -> Result<(), DecodeError> {
+    if buf.len() != expected {
+        return Err(DecodeError::InvalidSize {
+            expected,
+            actual: buf.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Reason why a line of an order file could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorReason {
+    /// A required field is missing.
+    MissingField(&'static str),
+    InvalidClientId(String),
+    UnknownAction(String),
+    InvalidPoints(String),
+    /// The line has more fields than expected.
+    UnexpectedField(String),
+    /// The line could not be read, usually because it is not valid UTF-8.
+    Unreadable(String),
+    /// The product is not in the catalog.
+    UnknownProduct(String),
+    /// The product is already in the catalog.
+    DuplicateProduct(String),
+    /// The price of a product is not a non negative integer.
+    InvalidPrice(String),
+    /// The kind of a rule, like a promotion, is not known.
+    UnknownRule(String),
+    /// A value of a rule is not valid for its kind.
+    InvalidRuleValue(String),
+    /// The command sent to a running coffee maker is not known.
+    UnknownCommand(String),
+    /// The ticket of an order is not a non negative integer.
+    InvalidTicket(String),
+}
+
+/// Error returned when a line of an order file is not a valid order.
+/// Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub reason: ParseErrorReason,
+}
+
+impl ParseError {
+    pub fn new(column: usize, reason: ParseErrorReason) -> Self {
+        ParseError {
+            line: 1,
+            column,
+            reason,
+        }
+    }
+
+    /// Error of a value that is not valid for its field of a rule.
+    pub fn invalid_value(column: usize, value: &str) -> Self {
+        ParseError::new(
+            column,
+            ParseErrorReason::InvalidRuleValue(value.to_string()),
+        )
+    }
+
+    /// Returns the same error reported at the given line.
+    pub fn at_line(self, line: usize) -> Self {
+        ParseError { line, ..self }
+    }
+}
+
+impl fmt::Display for ParseErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorReason::MissingField(field) => write!(f, "Missing {}", field),
+            ParseErrorReason::InvalidClientId(id) => write!(f, "Invalid client id: {:?}", id),
+            ParseErrorReason::UnknownAction(action) => write!(f, "Unknown action: {:?}", action),
+            ParseErrorReason::InvalidPoints(points) => write!(f, "Invalid points: {:?}", points),
+            ParseErrorReason::UnexpectedField(field) => {
+                write!(f, "Unexpected field: {:?}", field)
+            }
+            ParseErrorReason::Unreadable(e) => write!(f, "Unreadable line: {}", e),
+            ParseErrorReason::UnknownProduct(product) => {
+                write!(f, "Unknown product: {:?}", product)
+            }
+            ParseErrorReason::DuplicateProduct(product) => {
+                write!(f, "Duplicate product: {:?}", product)
+            }
+            ParseErrorReason::InvalidPrice(price) => write!(f, "Invalid price: {:?}", price),
+            ParseErrorReason::UnknownRule(kind) => write!(f, "Unknown rule: {:?}", kind),
+            ParseErrorReason::InvalidRuleValue(value) => {
+                write!(f, "Invalid rule value: {:?}", value)
+            }
+            ParseErrorReason::UnknownCommand(command) => {
+                write!(f, "Unknown command: {:?}", command)
+            }
+            ParseErrorReason::InvalidTicket(ticket) => write!(f, "Invalid ticket: {:?}", ticket),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
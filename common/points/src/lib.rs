@@ -1,12 +1,30 @@
 mod order;
 pub use order::*;
 
+mod catalog;
+pub use catalog::*;
+
+mod order_file;
+pub use order_file::*;
+
 mod message;
 pub use message::*;
 
 mod control;
 pub use control::*;
 
+mod error;
+pub use error::*;
+
+mod promotion;
+pub use promotion::*;
+
+mod protocol;
+pub use protocol::*;
+
+mod response;
+pub use response::*;
+
 pub const CLIENT_CONNECTION: u8 = 1;
 pub const SERVER_MESSAGE: u8 = 2;
 pub const CONTROL_MESSAGE: u8 = 3;
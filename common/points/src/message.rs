@@ -1,57 +1,224 @@
-use crate::{Order, OrderAction, ORDER_BUFFER_SIZE};
+use crate::{error::expect_size, ClientId, DecodeError, Order, OrderAction, ORDER_BUFFER_SIZE};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     LockOrder(Order),
     FreeOrder(Order),
     CommitOrder(Order),
+    QueryBalance(ClientId, ReadConsistency),
+    /// Links a card to a family account, orders of the card use the points of the account.
+    LinkCard(ClientId, ClientId),
+    /// Makes a card use its own points again.
+    UnlinkCard(ClientId),
 }
 
+/// How many servers must answer a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// Only the local server is read.
+    Local,
+    /// At least half of the other servers must answer, the most common balance is returned,
+    /// or the local one on a tie.
+    Quorum,
+}
+
+/// Identifies a request of a coffee maker.
+/// Retries of a request reuse its key, so servers can answer them without applying it twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdempotencyKey {
+    pub coffee_maker: u32,
+    pub sequence: u64,
+}
+
+/// Size of an encoded `IdempotencyKey`: 4 bytes coffee maker id and 8 bytes sequence number.
+pub const IDEMPOTENCY_KEY_SIZE: usize = 12;
+
+/// A message along with the key of the request it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyedMessage {
+    pub key: IdempotencyKey,
+    pub msg: Message,
+}
+
+/// Size of the legacy fixed-size message frame.
+/// It is only accepted by the server while clients migrate to the framed protocol.
 pub const MESSAGE_BUFFER_SIZE: usize = ORDER_BUFFER_SIZE + 1;
 pub type MessageBytes = [u8; MESSAGE_BUFFER_SIZE];
 
-impl From<Message> for MessageBytes {
-    fn from(message: Message) -> Self {
+impl TryFrom<Message> for MessageBytes {
+    type Error = String;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
         let mut buf = [0; MESSAGE_BUFFER_SIZE];
 
         match message {
             Message::LockOrder(order) => {
                 buf[0] = 1;
-                let order: [u8; ORDER_BUFFER_SIZE] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
             Message::FreeOrder(order) => {
                 buf[0] = 2;
-                let order: [u8; ORDER_BUFFER_SIZE] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
             Message::CommitOrder(order) => {
                 buf[0] = 3;
-                let order: [u8; 6] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
+            Message::QueryBalance(..) => {
+                return Err("Balance queries are not supported by the legacy protocol".to_string())
+            }
+            Message::LinkCard(..) | Message::UnlinkCard(_) => {
+                return Err("Family accounts are not supported by the legacy protocol".to_string())
+            }
         }
 
-        buf
+        Ok(buf)
     }
 }
 
-impl From<MessageBytes> for Message {
-    fn from(buf: MessageBytes) -> Self {
+impl TryFrom<MessageBytes> for Message {
+    type Error = DecodeError;
+
+    fn try_from(buf: MessageBytes) -> Result<Self, Self::Error> {
         let mut order_buf = [0; ORDER_BUFFER_SIZE];
         order_buf[..6].copy_from_slice(&buf[1..(MESSAGE_BUFFER_SIZE)]);
 
-        let order = Order::from(order_buf);
+        let order = Order::try_from(order_buf)?;
 
         match buf[0] {
-            1 => Message::LockOrder(order),
-            2 => Message::FreeOrder(order),
-            3 => Message::CommitOrder(order),
-            _ => panic!("Invalid message"),
+            1 => Ok(Message::LockOrder(order)),
+            2 => Ok(Message::FreeOrder(order)),
+            3 => Ok(Message::CommitOrder(order)),
+            t => Err(DecodeError::UnknownMessageType(t)),
         }
     }
 }
 
+impl Message {
+    fn tag(&self) -> u8 {
+        match self {
+            Message::LockOrder(_) => 1,
+            Message::FreeOrder(_) => 2,
+            Message::CommitOrder(_) => 3,
+            Message::QueryBalance(..) => 4,
+            Message::LinkCard(..) => 5,
+            Message::UnlinkCard(_) => 6,
+        }
+    }
+}
+
+/// Payload of a framed message: 1 byte message type followed by its body.
+/// Orders are encoded as is, balance queries as 4 bytes client id and 1 byte read consistency.
+/// Links are encoded as 4 bytes card id and 4 bytes account id, unlinks as 4 bytes card id.
+impl From<Message> for Vec<u8> {
+    fn from(message: Message) -> Self {
+        let mut buf = vec![message.tag()];
+        match message {
+            Message::LockOrder(order) | Message::FreeOrder(order) | Message::CommitOrder(order) => {
+                let order: Vec<u8> = order.into();
+                buf.extend_from_slice(&order);
+            }
+            Message::QueryBalance(client_id, consistency) => {
+                buf.extend_from_slice(&client_id.to_be_bytes());
+                buf.push(match consistency {
+                    ReadConsistency::Local => 0,
+                    ReadConsistency::Quorum => 1,
+                });
+            }
+            Message::LinkCard(card, account) => {
+                buf.extend_from_slice(&card.to_be_bytes());
+                buf.extend_from_slice(&account.to_be_bytes());
+            }
+            Message::UnlinkCard(card) => {
+                buf.extend_from_slice(&card.to_be_bytes());
+            }
+        }
+        buf
+    }
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let (tag, body) = buf.split_first().ok_or(DecodeError::InvalidSize {
+            expected: 1,
+            actual: 0,
+        })?;
+
+        match tag {
+            1 => Ok(Message::LockOrder(Order::try_from(body)?)),
+            2 => Ok(Message::FreeOrder(Order::try_from(body)?)),
+            3 => Ok(Message::CommitOrder(Order::try_from(body)?)),
+            4 => {
+                expect_size(body, 5)?;
+                let client_id = ClientId::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let consistency = match body[4] {
+                    0 => ReadConsistency::Local,
+                    1 => ReadConsistency::Quorum,
+                    c => return Err(DecodeError::UnknownReadConsistency(c)),
+                };
+                Ok(Message::QueryBalance(client_id, consistency))
+            }
+            5 => {
+                expect_size(body, 8)?;
+                let card = ClientId::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let account = ClientId::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                Ok(Message::LinkCard(card, account))
+            }
+            6 => {
+                expect_size(body, 4)?;
+                let card = ClientId::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                Ok(Message::UnlinkCard(card))
+            }
+            t => Err(DecodeError::UnknownMessageType(*t)),
+        }
+    }
+}
+
+/// Payload of a keyed message: the idempotency key followed by the framed message.
+impl From<KeyedMessage> for Vec<u8> {
+    fn from(keyed: KeyedMessage) -> Self {
+        let mut buf = Vec::with_capacity(IDEMPOTENCY_KEY_SIZE);
+        buf.extend_from_slice(&keyed.key.coffee_maker.to_be_bytes());
+        buf.extend_from_slice(&keyed.key.sequence.to_be_bytes());
+        let msg: Vec<u8> = keyed.msg.into();
+        buf.extend_from_slice(&msg);
+        buf
+    }
+}
+
+impl TryFrom<&[u8]> for KeyedMessage {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        if buf.len() < IDEMPOTENCY_KEY_SIZE {
+            return Err(DecodeError::InvalidSize {
+                expected: IDEMPOTENCY_KEY_SIZE,
+                actual: buf.len(),
+            });
+        }
+        let (key, msg) = buf.split_at(IDEMPOTENCY_KEY_SIZE);
+
+        let mut coffee_maker = [0; 4];
+        coffee_maker.copy_from_slice(&key[..4]);
+        let mut sequence = [0; 8];
+        sequence.copy_from_slice(&key[4..]);
+
+        Ok(KeyedMessage {
+            key: IdempotencyKey {
+                coffee_maker: u32::from_be_bytes(coffee_maker),
+                sequence: u64::from_be_bytes(sequence),
+            },
+            msg: Message::try_from(msg)?,
+        })
+    }
+}
+
 impl Message {
     pub fn handle_trivially(&self) -> Result<(), String> {
         let err = "Could not handle message locally".to_string();
@@ -60,19 +227,24 @@ impl Message {
             Message::LockOrder(order) => Ok(order),
             Message::FreeOrder(order) => Ok(order),
             Message::CommitOrder(_) => Err(err.clone()),
+            Message::QueryBalance(..) => Err(err.clone()),
+            Message::LinkCard(..) | Message::UnlinkCard(_) => Err(err.clone()),
         }?;
 
         match order.action {
-            OrderAction::UsePoints(_) => Err(err),
+            OrderAction::UsePoints(_) | OrderAction::Transfer { .. } => Err(err),
             OrderAction::FillPoints(_) => Ok(()),
         }
     }
 
-    pub fn order(&self) -> &Order {
+    /// Returns the order carried by the message, if any.
+    pub fn order(&self) -> Option<&Order> {
         match self {
-            Message::LockOrder(order) => order,
-            Message::FreeOrder(order) => order,
-            Message::CommitOrder(order) => order,
+            Message::LockOrder(order) => Some(order),
+            Message::FreeOrder(order) => Some(order),
+            Message::CommitOrder(order) => Some(order),
+            Message::QueryBalance(..) => None,
+            Message::LinkCard(..) | Message::UnlinkCard(_) => None,
         }
     }
 }
@@ -85,8 +257,8 @@ mod test {
     use super::*;
 
     fn test_message(message: Message) {
-        let buf: [u8; 7] = message.clone().into();
-        let message2 = Message::from(buf);
+        let buf: [u8; 7] = message.clone().try_into().unwrap();
+        let message2 = Message::try_from(buf).unwrap();
         assert_eq!(message, message2);
     }
 
@@ -110,4 +282,86 @@ mod test {
         let message = Message::CommitOrder(order);
         test_message(message);
     }
+
+    fn test_framed_message(message: Message) {
+        let buf: Vec<u8> = message.clone().into();
+        let message2 = Message::try_from(buf.as_slice()).unwrap();
+        assert_eq!(message, message2);
+    }
+
+    #[test]
+    fn framed_lock_order() {
+        let order = Order::new(70_000, OrderAction::UsePoints(5000));
+        test_framed_message(Message::LockOrder(order));
+    }
+
+    #[test]
+    fn framed_commit_order() {
+        let order = Order::new(1, OrderAction::FillPoints(usize::MAX));
+        test_framed_message(Message::CommitOrder(order));
+    }
+
+    #[test]
+    fn framed_query_balance() {
+        test_framed_message(Message::QueryBalance(42, ReadConsistency::Local));
+        test_framed_message(Message::QueryBalance(70_000, ReadConsistency::Quorum));
+    }
+
+    #[test]
+    fn keyed_message() {
+        let keyed = KeyedMessage {
+            key: IdempotencyKey {
+                coffee_maker: 7,
+                sequence: u64::MAX,
+            },
+            msg: Message::CommitOrder(Order::new(3, OrderAction::FillPoints(10))),
+        };
+        let buf: Vec<u8> = keyed.clone().into();
+        assert_eq!(KeyedMessage::try_from(buf.as_slice()), Ok(keyed));
+        assert!(KeyedMessage::try_from(&buf[..IDEMPOTENCY_KEY_SIZE]).is_err());
+        assert!(KeyedMessage::try_from(&buf[..4]).is_err());
+    }
+
+    #[test]
+    fn framed_family_accounts() {
+        test_framed_message(Message::LinkCard(2, 70_000));
+        test_framed_message(Message::UnlinkCard(2));
+        assert!(MessageBytes::try_from(Message::UnlinkCard(2)).is_err());
+    }
+
+    #[test]
+    fn legacy_query_balance() {
+        let message = Message::QueryBalance(42, ReadConsistency::Local);
+        assert!(MessageBytes::try_from(message).is_err());
+    }
+
+    #[test]
+    fn framed_invalid_message() {
+        let mut buf: Vec<u8> = Message::FreeOrder(Order::new(1, OrderAction::UsePoints(1))).into();
+        buf[0] = 9;
+        assert_eq!(
+            Message::try_from(buf.as_slice()),
+            Err(DecodeError::UnknownMessageType(9))
+        );
+        assert!(Message::try_from(&[][..]).is_err());
+        assert_eq!(
+            Message::try_from(&[4, 0, 0, 0, 1, 2][..]),
+            Err(DecodeError::UnknownReadConsistency(2))
+        );
+    }
+
+    #[test]
+    fn legacy_invalid_message() {
+        let buf: MessageBytes = [0, 0, 1, 1, 0, 0, 1];
+        assert_eq!(
+            Message::try_from(buf),
+            Err(DecodeError::UnknownMessageType(0))
+        );
+
+        let buf: MessageBytes = [1, 0, 1, 9, 0, 0, 1];
+        assert_eq!(
+            Message::try_from(buf),
+            Err(DecodeError::UnknownActionType(9))
+        );
+    }
 }
@@ -1,38 +1,180 @@
+use crate::{
+    catalog::Catalog,
+    error::{expect_size, DecodeError, ParseError, ParseErrorReason},
+};
+
+pub type ClientId = u32;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OrderAction {
     UsePoints(usize),
     FillPoints(usize),
+    /// Moves points from the client of the order to another one.
+    Transfer {
+        to: ClientId,
+        points: usize,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Order {
-    pub client_id: u16,
+    pub client_id: ClientId,
     pub action: OrderAction,
+    /// Product of the catalog the order was taken for, if any.
+    /// It is not sent to the server.
+    pub product: Option<String>,
 }
 
 impl Order {
-    pub fn new(client_id: u16, action: OrderAction) -> Self {
-        Order { client_id, action }
+    pub fn new(client_id: ClientId, action: OrderAction) -> Self {
+        Order {
+            client_id,
+            action,
+            product: None,
+        }
     }
 
-    pub fn parse(line: String) -> Result<Self, String> {
-        let mut parts = line.split(',');
+    /// Parses a line with the format `<client_id>,<USE|FILL>,<points>`
+    /// or `<client_id>,TRANSFER,<points>,<to>`.
+    /// Whitespace around fields is ignored. Errors are reported at line 1.
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        Self::parse_with_catalog(line, &Catalog::default())
+    }
+
+    /// Parses a line as `Order::parse`, also accepting `<client_id>,<BUY|REDEEM>,<product>`.
+    /// Products bought earn the points of the catalog, products redeemed use their point cost.
+    pub fn parse_with_catalog(line: &str, catalog: &Catalog) -> Result<Self, ParseError> {
+        let mut fields = Fields::new(line);
 
-        let client_id = parts.next().unwrap().to_string();
-        let action = parts.next().unwrap().to_string();
+        let (column, client_id) = fields.next_or_missing("client id")?;
+        let client_id = client_id.parse::<ClientId>().map_err(|_| {
+            ParseError::new(
+                column,
+                ParseErrorReason::InvalidClientId(client_id.to_string()),
+            )
+        })?;
 
-        let action = match action.as_str() {
-            "USE" => {
-                let points = parts.next().unwrap().parse::<usize>().unwrap();
-                OrderAction::UsePoints(points)
+        let (column, action) = fields.next_or_missing("action")?;
+        let action = match action {
+            "BUY" | "REDEEM" => return Self::parse_product(client_id, action, fields, catalog),
+            "USE" | "FILL" | "TRANSFER" => action,
+            _ => {
+                return Err(ParseError::new(
+                    column,
+                    ParseErrorReason::UnknownAction(action.to_string()),
+                ))
             }
-            "FILL" => {
-                let points = parts.next().unwrap().parse::<usize>().unwrap();
-                OrderAction::FillPoints(points)
+        };
+
+        let (column, points) = fields.next_or_missing("points")?;
+        let points = points.parse::<usize>().map_err(|_| {
+            ParseError::new(column, ParseErrorReason::InvalidPoints(points.to_string()))
+        })?;
+
+        let action = match action {
+            "USE" => OrderAction::UsePoints(points),
+            "FILL" => OrderAction::FillPoints(points),
+            _ => {
+                let (column, to) = fields.next_or_missing("recipient")?;
+                let to = to.parse::<ClientId>().map_err(|_| {
+                    ParseError::new(column, ParseErrorReason::InvalidClientId(to.to_string()))
+                })?;
+                OrderAction::Transfer { to, points }
             }
-            _ => return Err("Invalid action".to_string()),
         };
-        Ok(Order::new(client_id.parse::<u16>().unwrap(), action))
+
+        fields.expect_end()?;
+        Ok(Order::new(client_id, action))
+    }
+
+    /// Parses the product of a `BUY` or `REDEEM` line, computing its action from the catalog.
+    fn parse_product(
+        client_id: ClientId,
+        action: &str,
+        mut fields: Fields,
+        catalog: &Catalog,
+    ) -> Result<Self, ParseError> {
+        let (column, name) = fields.next_or_missing("product")?;
+        let product = catalog.get(name).ok_or_else(|| {
+            ParseError::new(column, ParseErrorReason::UnknownProduct(name.to_string()))
+        })?;
+        fields.expect_end()?;
+
+        let action = match action {
+            "BUY" => OrderAction::FillPoints(product.earns),
+            _ => OrderAction::UsePoints(product.cost),
+        };
+        Ok(Order {
+            client_id,
+            action,
+            product: Some(name.to_string()),
+        })
+    }
+}
+
+/// Comma separated fields of a line, along with the column where each one starts.
+/// Columns start at 1, so they can be reported in a `ParseError`.
+pub struct Fields<'a> {
+    line: &'a str,
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Fields {
+            line,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Fails if the next field is missing or empty.
+    pub fn next_or_missing(&mut self, field: &'static str) -> Result<(usize, &'a str), ParseError> {
+        match self.next() {
+            Some((column, "")) => Err(ParseError::new(
+                column,
+                ParseErrorReason::MissingField(field),
+            )),
+            None => Err(ParseError::new(
+                self.line.chars().count() + 1,
+                ParseErrorReason::MissingField(field),
+            )),
+            Some(field) => Ok(field),
+        }
+    }
+
+    /// Fails if there are fields left.
+    pub fn expect_end(&mut self) -> Result<(), ParseError> {
+        match self.next() {
+            Some((column, field)) => Err(ParseError::new(
+                column,
+                ParseErrorReason::UnexpectedField(field.to_string()),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let rest = &self.line[self.offset..];
+        let raw = match rest.find(',') {
+            Some(end) => &rest[..end],
+            None => {
+                self.done = true;
+                rest
+            }
+        };
+        let start = self.offset + (raw.len() - raw.trim_start().len());
+        self.offset += raw.len() + 1;
+        let column = self.line[..start].chars().count() + 1;
+        Some((column, raw.trim()))
     }
 }
 
@@ -41,14 +183,20 @@ impl OrderAction {
         match self {
             OrderAction::UsePoints(points) => *points,
             OrderAction::FillPoints(points) => *points,
+            OrderAction::Transfer { points, .. } => *points,
         }
     }
 }
 
+/// Size of an order in the legacy fixed-size frame.
+/// Client ids are truncated to 16 bits and points to 3 decimal digits.
+/// Transfers can not be encoded.
 pub const ORDER_BUFFER_SIZE: usize = 6;
 
-impl From<Order> for [u8; ORDER_BUFFER_SIZE] {
-    fn from(order: Order) -> Self {
+impl TryFrom<Order> for [u8; ORDER_BUFFER_SIZE] {
+    type Error = String;
+
+    fn try_from(order: Order) -> Result<Self, Self::Error> {
         let mut buf = [0; ORDER_BUFFER_SIZE];
 
         let client_id = order.client_id;
@@ -68,28 +216,95 @@ impl From<Order> for [u8; ORDER_BUFFER_SIZE] {
                 buf[4] = ((points % 100) / 10) as u8;
                 buf[5] = (points % 10) as u8;
             }
+            OrderAction::Transfer { .. } => {
+                return Err("Transfers are not supported by the legacy protocol".to_string())
+            }
         }
 
-        buf
+        Ok(buf)
     }
 }
 
-impl From<[u8; ORDER_BUFFER_SIZE]> for Order {
-    fn from(buf: [u8; ORDER_BUFFER_SIZE]) -> Self {
+impl TryFrom<[u8; ORDER_BUFFER_SIZE]> for Order {
+    type Error = DecodeError;
+
+    fn try_from(buf: [u8; ORDER_BUFFER_SIZE]) -> Result<Self, Self::Error> {
         // First 2 bytes are client id
         // Next byte is action type
         // Last 3 bytes are points
-        let client_id = ((buf[0] as u16) << 8) | buf[1] as u16;
+        let client_id = ((buf[0] as ClientId) << 8) | buf[1] as ClientId;
 
         let points = (buf[3] as usize) * 100 + (buf[4] as usize) * 10 + (buf[5] as usize);
 
         let action = match buf[2] {
             1 => OrderAction::UsePoints(points),
             2 => OrderAction::FillPoints(points),
-            _ => panic!("Invalid action type"),
+            t => return Err(DecodeError::UnknownActionType(t)),
         };
 
-        Order::new(client_id, action)
+        Ok(Order::new(client_id, action))
+    }
+}
+
+/// Size of an order in the framed protocol.
+/// 4 bytes client id, 1 byte action type, 8 bytes points
+pub const ORDER_FRAME_SIZE: usize = 13;
+/// Size of a transfer in the framed protocol: an order followed by 4 bytes recipient id.
+pub const TRANSFER_FRAME_SIZE: usize = ORDER_FRAME_SIZE + 4;
+
+impl From<Order> for Vec<u8> {
+    fn from(order: Order) -> Self {
+        let mut buf = Vec::with_capacity(ORDER_FRAME_SIZE);
+        buf.extend_from_slice(&order.client_id.to_be_bytes());
+
+        let action_type = match order.action {
+            OrderAction::UsePoints(_) => 1,
+            OrderAction::FillPoints(_) => 2,
+            OrderAction::Transfer { .. } => 3,
+        };
+        buf.push(action_type);
+        buf.extend_from_slice(&(order.action.points() as u64).to_be_bytes());
+        if let OrderAction::Transfer { to, .. } = order.action {
+            buf.extend_from_slice(&to.to_be_bytes());
+        }
+
+        buf
+    }
+}
+
+impl TryFrom<&[u8]> for Order {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        match buf.get(4) {
+            Some(3) => expect_size(buf, TRANSFER_FRAME_SIZE)?,
+            _ => expect_size(buf, ORDER_FRAME_SIZE)?,
+        }
+
+        let mut client_id = [0; 4];
+        client_id.copy_from_slice(&buf[0..4]);
+        let client_id = ClientId::from_be_bytes(client_id);
+
+        let mut points = [0; 8];
+        points.copy_from_slice(&buf[5..13]);
+        let points = u64::from_be_bytes(points);
+        let points = usize::try_from(points).map_err(|_| DecodeError::PointsOverflow(points))?;
+
+        let action = match buf[4] {
+            1 => OrderAction::UsePoints(points),
+            2 => OrderAction::FillPoints(points),
+            3 => {
+                let mut to = [0; 4];
+                to.copy_from_slice(&buf[13..17]);
+                OrderAction::Transfer {
+                    to: ClientId::from_be_bytes(to),
+                    points,
+                }
+            }
+            t => return Err(DecodeError::UnknownActionType(t)),
+        };
+
+        Ok(Order::new(client_id, action))
     }
 }
 
@@ -99,8 +314,8 @@ mod tests {
     use super::*;
 
     fn test_order(order: Order) {
-        let order_from_buf: [u8; 6] = order.clone().into();
-        let expected_order = Order::from(order_from_buf);
+        let order_from_buf: [u8; 6] = order.clone().try_into().unwrap();
+        let expected_order = Order::try_from(order_from_buf).unwrap();
         assert_eq!(order, expected_order);
     }
 
@@ -115,4 +330,93 @@ mod tests {
         let order = Order::new(30, OrderAction::FillPoints(123));
         test_order(order);
     }
+
+    #[test]
+    fn test_order_invalid_action() {
+        let buf = [0, 1, 7, 0, 0, 1];
+        assert_eq!(Order::try_from(buf), Err(DecodeError::UnknownActionType(7)));
+    }
+
+    fn test_framed_order(order: Order) {
+        let buf: Vec<u8> = order.clone().into();
+        assert_eq!(buf.len(), ORDER_FRAME_SIZE);
+        let expected_order = Order::try_from(buf.as_slice()).unwrap();
+        assert_eq!(order, expected_order);
+    }
+
+    #[test]
+    fn test_framed_order_large_points() {
+        let order = Order::new(25, OrderAction::UsePoints(1_000_000));
+        test_framed_order(order);
+    }
+
+    #[test]
+    fn test_framed_order_large_client_id() {
+        let order = Order::new(ClientId::MAX, OrderAction::FillPoints(1000));
+        test_framed_order(order);
+    }
+
+    #[test]
+    fn test_framed_order_invalid_size() {
+        let buf = [0; ORDER_FRAME_SIZE - 1];
+        assert_eq!(
+            Order::try_from(&buf[..]),
+            Err(DecodeError::InvalidSize {
+                expected: ORDER_FRAME_SIZE,
+                actual: ORDER_FRAME_SIZE - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_framed_transfer() {
+        let order = Order::new(1, OrderAction::Transfer { to: 2, points: 30 });
+        let buf: Vec<u8> = order.clone().into();
+        assert_eq!(buf.len(), TRANSFER_FRAME_SIZE);
+        assert_eq!(Order::try_from(buf.as_slice()), Ok(order.clone()));
+        assert!(Order::try_from(&buf[..ORDER_FRAME_SIZE]).is_err());
+        assert!(<[u8; ORDER_BUFFER_SIZE]>::try_from(order).is_err());
+    }
+
+    #[test]
+    fn test_parse_transfer() {
+        assert_eq!(
+            Order::parse("1,TRANSFER,30,2"),
+            Ok(Order::new(1, OrderAction::Transfer { to: 2, points: 30 }))
+        );
+        assert_eq!(
+            Order::parse("1,TRANSFER,30"),
+            Err(ParseError::new(
+                14,
+                ParseErrorReason::MissingField("recipient")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_product() {
+        let catalog = Catalog::parse("latte_large,450,60,12\n").unwrap();
+        assert_eq!(
+            Order::parse_with_catalog("1,BUY,latte_large", &catalog).map(|o| o.action),
+            Ok(OrderAction::FillPoints(12))
+        );
+        let order = Order::parse_with_catalog("1,REDEEM, latte_large ", &catalog).unwrap();
+        assert_eq!(order.action, OrderAction::UsePoints(60));
+        assert_eq!(order.product.as_deref(), Some("latte_large"));
+
+        assert_eq!(
+            Order::parse_with_catalog("1,BUY,mocha", &catalog),
+            Err(ParseError::new(
+                7,
+                ParseErrorReason::UnknownProduct("mocha".to_string())
+            ))
+        );
+        assert_eq!(
+            Order::parse("1,BUY,latte_large"),
+            Err(ParseError::new(
+                7,
+                ParseErrorReason::UnknownProduct("latte_large".to_string())
+            ))
+        );
+    }
 }
@@ -0,0 +1,227 @@
+use std::{
+    io::{BufRead, Lines},
+    sync::Arc,
+};
+
+use crate::{
+    catalog::Catalog,
+    error::{ParseError, ParseErrorReason},
+    order::{Fields, Order},
+};
+
+/// Optional header of an order file. Compared ignoring case.
+pub const ORDER_FILE_HEADER: [&str; 3] = ["client_id", "action", "points"];
+/// Lines starting with this character are ignored.
+pub const COMMENT_PREFIX: char = '#';
+
+/// A line of an order file that is not a valid order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedLine {
+    pub content: String,
+    pub error: ParseError,
+}
+
+/// Reads the orders of a file one line at a time.
+/// Blank lines, comments and the header are skipped, invalid lines are
+/// returned as errors so the rest of the file can still be read.
+pub struct OrderReader<R> {
+    lines: Lines<R>,
+    line: usize,
+    seen_content: bool,
+    catalog: Arc<Catalog>,
+}
+
+impl<R: BufRead> OrderReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_catalog(reader, Arc::new(Catalog::default()))
+    }
+
+    /// Reads orders that may reference the products of the catalog.
+    pub fn with_catalog(reader: R, catalog: Arc<Catalog>) -> Self {
+        OrderReader {
+            lines: reader.lines(),
+            line: 0,
+            seen_content: false,
+            catalog,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for OrderReader<R> {
+    type Item = Result<Order, RejectedLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let content = self.lines.next()?;
+            self.line += 1;
+
+            let content = match content {
+                Ok(content) => content,
+                Err(e) => {
+                    return Some(Err(RejectedLine {
+                        content: String::new(),
+                        error: ParseError::new(1, ParseErrorReason::Unreadable(e.to_string()))
+                            .at_line(self.line),
+                    }))
+                }
+            };
+
+            let trimmed = content.trim();
+            if trimmed.is_empty() || trimmed.starts_with(COMMENT_PREFIX) {
+                continue;
+            }
+
+            let first = !self.seen_content;
+            self.seen_content = true;
+            if first && is_header(trimmed, &ORDER_FILE_HEADER) {
+                continue;
+            }
+
+            let order = Order::parse_with_catalog(&content, &self.catalog);
+            return Some(order.map_err(|error| RejectedLine {
+                error: error.at_line(self.line),
+                content,
+            }));
+        }
+    }
+}
+
+/// Reads a file with a line per rule, handing each line to `parse`.
+/// Blank lines and comments are skipped, the first invalid line fails the whole file.
+pub fn read_rules<R, F>(reader: R, mut parse: F) -> Result<(), ParseError>
+where
+    R: BufRead,
+    F: FnMut(&str) -> Result<(), ParseError>,
+{
+    for (index, content) in reader.lines().enumerate() {
+        let line = index + 1;
+        let content = content.map_err(|e| {
+            ParseError::new(1, ParseErrorReason::Unreadable(e.to_string())).at_line(line)
+        })?;
+
+        let trimmed = content.trim();
+        if trimmed.is_empty() || trimmed.starts_with(COMMENT_PREFIX) {
+            continue;
+        }
+        parse(&content).map_err(|error| error.at_line(line))?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if the fields of the line are the given header, ignoring case.
+pub(crate) fn is_header(line: &str, header: &[&str]) -> bool {
+    let fields: Vec<&str> = Fields::new(line).map(|(_, field)| field).collect();
+    fields.len() == header.len()
+        && fields
+            .iter()
+            .zip(header)
+            .all(|(field, header)| field.eq_ignore_ascii_case(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OrderAction;
+
+    fn read(content: &str) -> Vec<Result<Order, RejectedLine>> {
+        OrderReader::new(content.as_bytes()).collect()
+    }
+
+    #[test]
+    fn parse_valid_lines() {
+        let orders = read("1,USE,10\n 2 , FILL , 5 \n");
+        assert_eq!(
+            orders,
+            vec![
+                Ok(Order::new(1, OrderAction::UsePoints(10))),
+                Ok(Order::new(2, OrderAction::FillPoints(5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_header_comments_and_blank_lines() {
+        let orders = read("Client_Id,Action,Points\n# comentario\n\n1,USE,10\n");
+        assert_eq!(orders, vec![Ok(Order::new(1, OrderAction::UsePoints(10)))]);
+    }
+
+    #[test]
+    fn header_only_allowed_first() {
+        let orders = read("1,USE,10\nclient_id,action,points\n");
+        let error = orders[1].clone().unwrap_err().error;
+        assert_eq!(error.line, 2);
+        assert_eq!(error.column, 1);
+    }
+
+    #[test]
+    fn invalid_lines_do_not_stop_the_batch() {
+        let orders = read("1,USE\nabc,USE,3\n1,SELL,3\n1,USE,-3\n1,USE,3,4\n2,FILL,7\n");
+
+        let errors: Vec<ParseError> = orders[..5]
+            .iter()
+            .map(|o| o.clone().unwrap_err().error)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::new(6, ParseErrorReason::MissingField("points")).at_line(1),
+                ParseError::new(1, ParseErrorReason::InvalidClientId("abc".to_string())).at_line(2),
+                ParseError::new(3, ParseErrorReason::UnknownAction("SELL".to_string())).at_line(3),
+                ParseError::new(7, ParseErrorReason::InvalidPoints("-3".to_string())).at_line(4),
+                ParseError::new(9, ParseErrorReason::UnexpectedField("4".to_string())).at_line(5),
+            ]
+        );
+        assert_eq!(orders[5], Ok(Order::new(2, OrderAction::FillPoints(7))));
+    }
+
+    #[test]
+    fn read_products_of_the_catalog() {
+        let catalog = Arc::new(Catalog::parse("latte_large,450,60,12\n").unwrap());
+        let content = "42,BUY,latte_large\n42,REDEEM,latte_large\n42,BUY,mocha\n";
+        let orders: Vec<_> = OrderReader::with_catalog(content.as_bytes(), catalog).collect();
+
+        let actions: Vec<_> = orders[..2]
+            .iter()
+            .map(|o| o.clone().unwrap().action)
+            .collect();
+        assert_eq!(
+            actions,
+            vec![OrderAction::FillPoints(12), OrderAction::UsePoints(60)]
+        );
+        assert_eq!(
+            orders[2].clone().unwrap_err().error,
+            ParseError::new(8, ParseErrorReason::UnknownProduct("mocha".to_string())).at_line(3)
+        );
+    }
+
+    #[test]
+    fn rejected_line_keeps_content() {
+        let orders = read("1,,3\n");
+        assert_eq!(
+            orders,
+            vec![Err(RejectedLine {
+                content: "1,,3".to_string(),
+                error: ParseError::new(3, ParseErrorReason::MissingField("action")),
+            })]
+        );
+    }
+
+    #[test]
+    fn rules_stop_at_the_first_invalid_line() {
+        let mut rules = vec![];
+        let read = read_rules("# reglas\n\nA,1\n  B,x\nC,3\n".as_bytes(), |content| {
+            let mut fields = Fields::new(content);
+            let (_, name) = fields.next_or_missing("name")?;
+            let (column, value) = fields.next_or_missing("value")?;
+            let value = value
+                .parse::<usize>()
+                .map_err(|_| ParseError::invalid_value(column, value))?;
+            rules.push((name.to_string(), value));
+            Ok(())
+        });
+
+        assert_eq!(read, Err(ParseError::invalid_value(5, "x").at_line(4)));
+        assert_eq!(rules, vec![("A".to_string(), 1)]);
+    }
+}
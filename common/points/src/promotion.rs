@@ -0,0 +1,351 @@
+use std::{
+    fmt,
+    io::BufRead,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{ParseError, ParseErrorReason},
+    order::Fields,
+    order_file::read_rules,
+};
+
+const WEEKDAYS: [&str; 7] = ["MON", "TUE", "WED", "THU", "FRI", "SAT", "SUN"];
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+/// When a promotion applies. Times are in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// On the given weekday, 0 is Monday.
+    Weekday(usize),
+    /// From the first hour, inclusive, to the second one, exclusive.
+    Hours(u64, u64),
+    /// On every nth purchase of the client.
+    Nth(usize),
+}
+
+/// How a promotion changes the earned points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// Multiplies the earned points by the given percentage.
+    Multiplier(usize),
+    /// Adds the given points.
+    Bonus(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Promotion {
+    pub name: String,
+    pub condition: Condition,
+    pub effect: Effect,
+}
+
+/// A purchase that earns points.
+#[derive(Debug, Clone, Copy)]
+pub struct Purchase {
+    /// Points earned without promotions.
+    pub points: usize,
+    pub time: SystemTime,
+    /// Number of the purchase among the ones of the client, starting at 1.
+    pub number: usize,
+}
+
+/// Points earned by a purchase, along with the promotions that applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Earned {
+    pub base: usize,
+    pub points: usize,
+    pub fired: Vec<(String, Effect)>,
+}
+
+/// Promotions that change the points earned by purchases.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Promotions {
+    rules: Vec<Promotion>,
+}
+
+impl Promotions {
+    /// Reads the promotions of a file with a line per rule, with the format
+    /// `<name>,WEEKDAY,<MON..SUN>,<multiplier>`, `<name>,HOURS,<from>,<to>,<multiplier>`
+    /// or `<name>,NTH,<n>,<bonus>`.
+    /// Multipliers have up to 2 decimals. The file is read with `read_rules`.
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, ParseError> {
+        let mut rules = vec![];
+        read_rules(reader, |content| {
+            rules.push(parse_rule(content)?);
+            Ok(())
+        })?;
+
+        Ok(Promotions { rules })
+    }
+
+    /// Reads promotions from the given content.
+    pub fn parse(content: &str) -> Result<Self, ParseError> {
+        Self::read(content.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Computes the points earned by a purchase.
+    /// Multipliers of every promotion that applies are compounded, then bonuses are added.
+    /// Points are only truncated once, so they do not depend on the order of the rules.
+    pub fn evaluate(&self, purchase: Purchase) -> Earned {
+        let fired: Vec<&Promotion> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.condition.holds(&purchase))
+            .collect();
+
+        let (numerator, denominator) = fired.iter().fold(
+            (purchase.points as u128, 1u128),
+            |(numerator, denominator), rule| match rule.effect {
+                Effect::Multiplier(percentage) => (
+                    numerator.saturating_mul(percentage as u128),
+                    denominator.saturating_mul(100),
+                ),
+                Effect::Bonus(_) => (numerator, denominator),
+            },
+        );
+        let mut points = usize::try_from(numerator / denominator).unwrap_or(usize::MAX);
+        for rule in &fired {
+            if let Effect::Bonus(bonus) = rule.effect {
+                points += bonus;
+            }
+        }
+
+        Earned {
+            base: purchase.points,
+            points,
+            fired: fired
+                .into_iter()
+                .map(|rule| (rule.name.clone(), rule.effect))
+                .collect(),
+        }
+    }
+}
+
+impl Condition {
+    fn holds(&self, purchase: &Purchase) -> bool {
+        let secs = purchase
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        match self {
+            // The epoch was a Thursday
+            Condition::Weekday(day) => (secs / SECS_PER_DAY + 3) % 7 == *day as u64,
+            Condition::Hours(from, to) => {
+                let hour = secs % SECS_PER_DAY / SECS_PER_HOUR;
+                *from <= hour && hour < *to
+            }
+            Condition::Nth(n) => purchase.number.is_multiple_of(*n),
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Multiplier(percentage) => {
+                write!(f, "x{}.{:02}", percentage / 100, percentage % 100)
+            }
+            Effect::Bonus(bonus) => write!(f, "+{}", bonus),
+        }
+    }
+}
+
+impl fmt::Display for Earned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} points", self.points)?;
+        if self.fired.is_empty() {
+            return Ok(());
+        }
+        let fired: Vec<String> = self
+            .fired
+            .iter()
+            .map(|(name, effect)| format!("{} {}", name, effect))
+            .collect();
+        write!(f, " ({} base, {})", self.base, fired.join(", "))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Promotion, ParseError> {
+    let mut fields = Fields::new(line);
+
+    let (_, name) = fields.next_or_missing("name")?;
+    let (column, kind) = fields.next_or_missing("kind")?;
+
+    let (condition, effect) = match kind {
+        "WEEKDAY" => {
+            let (column, day) = fields.next_or_missing("weekday")?;
+            let day = WEEKDAYS
+                .iter()
+                .position(|weekday| weekday.eq_ignore_ascii_case(day))
+                .ok_or_else(|| ParseError::invalid_value(column, day))?;
+            (Condition::Weekday(day), parse_multiplier(&mut fields)?)
+        }
+        "HOURS" => {
+            let from = parse_number(&mut fields, "from")?;
+            let (column, to) = fields.next_or_missing("to")?;
+            let to = to
+                .parse::<u64>()
+                .ok()
+                .filter(|to| from < *to && *to <= 24)
+                .ok_or_else(|| ParseError::invalid_value(column, to))?;
+            (Condition::Hours(from, to), parse_multiplier(&mut fields)?)
+        }
+        "NTH" => {
+            let (column, n) = fields.next_or_missing("n")?;
+            let n = n
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| ParseError::invalid_value(column, n))?;
+            let bonus = parse_number(&mut fields, "bonus")? as usize;
+            (Condition::Nth(n), Effect::Bonus(bonus))
+        }
+        _ => {
+            return Err(ParseError::new(
+                column,
+                ParseErrorReason::UnknownRule(kind.to_string()),
+            ))
+        }
+    };
+    fields.expect_end()?;
+
+    Ok(Promotion {
+        name: name.to_string(),
+        condition,
+        effect,
+    })
+}
+
+fn parse_number(fields: &mut Fields, field: &'static str) -> Result<u64, ParseError> {
+    let (column, value) = fields.next_or_missing(field)?;
+    value
+        .parse::<u64>()
+        .map_err(|_| ParseError::invalid_value(column, value))
+}
+
+/// Parses a multiplier with up to 2 decimals as a percentage.
+fn parse_multiplier(fields: &mut Fields) -> Result<Effect, ParseError> {
+    let (column, value) = fields.next_or_missing("multiplier")?;
+    let (units, decimals) = value.split_once('.').unwrap_or((value, ""));
+    if decimals.len() > 2 || !decimals.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ParseError::invalid_value(column, value));
+    }
+    let units = units
+        .parse::<usize>()
+        .map_err(|_| ParseError::invalid_value(column, value))?;
+    let decimals = format!("{:0<2}", decimals)
+        .parse::<usize>()
+        .map_err(|_| ParseError::invalid_value(column, value))?;
+    Ok(Effect::Multiplier(units * 100 + decimals))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // 2024-01-01 fue lunes
+    const MONDAY_NOON: u64 = 1_704_110_400;
+
+    fn purchase(points: usize, secs: u64, number: usize) -> Purchase {
+        Purchase {
+            points,
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+            number,
+        }
+    }
+
+    #[test]
+    fn parse_promotions() {
+        let promotions = Promotions::parse(
+            "# reglas\ndouble_monday,WEEKDAY,MON,2\nhappy_hour,HOURS,15,17,1.5\ntenth,NTH,10,20\n",
+        )
+        .unwrap();
+        assert_eq!(
+            promotions.rules,
+            vec![
+                Promotion {
+                    name: "double_monday".to_string(),
+                    condition: Condition::Weekday(0),
+                    effect: Effect::Multiplier(200),
+                },
+                Promotion {
+                    name: "happy_hour".to_string(),
+                    condition: Condition::Hours(15, 17),
+                    effect: Effect::Multiplier(150),
+                },
+                Promotion {
+                    name: "tenth".to_string(),
+                    condition: Condition::Nth(10),
+                    effect: Effect::Bonus(20),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_promotions() {
+        assert_eq!(
+            Promotions::parse("x,WEEKDAY,MON,2\nx,DAILY,2\n"),
+            Err(ParseError::new(3, ParseErrorReason::UnknownRule("DAILY".to_string())).at_line(2))
+        );
+        assert_eq!(
+            Promotions::parse("x,HOURS,17,15,2\n"),
+            Err(ParseError::new(
+                12,
+                ParseErrorReason::InvalidRuleValue("15".to_string())
+            ))
+        );
+        assert_eq!(
+            Promotions::parse("x,WEEKDAY,MON,1.555\n"),
+            Err(ParseError::new(
+                15,
+                ParseErrorReason::InvalidRuleValue("1.555".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn evaluate_promotions() {
+        let promotions = Promotions::parse(
+            "double_monday,WEEKDAY,MON,2\nlunch,HOURS,12,14,1.5\ntenth,NTH,10,20\n",
+        )
+        .unwrap();
+
+        let earned = promotions.evaluate(purchase(10, MONDAY_NOON, 10));
+        assert_eq!(earned.points, 10 * 2 * 3 / 2 + 20);
+        let fired: Vec<&str> = earned.fired.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(fired, vec!["double_monday", "lunch", "tenth"]);
+
+        // El orden de las reglas no cambia el redondeo
+        let earned = promotions.evaluate(purchase(7, MONDAY_NOON, 1));
+        assert_eq!(earned.points, 21);
+        let reversed = Promotions::parse("lunch,HOURS,12,14,1.5\ndouble_monday,WEEKDAY,MON,2\n")
+            .unwrap()
+            .evaluate(purchase(7, MONDAY_NOON, 1));
+        assert_eq!(reversed.points, 21);
+
+        // Martes a la noche, compra 3
+        let earned = promotions.evaluate(purchase(10, MONDAY_NOON + SECS_PER_DAY + 9 * 3600, 3));
+        assert_eq!(
+            earned,
+            Earned {
+                base: 10,
+                points: 10,
+                fired: vec![]
+            }
+        );
+    }
+}
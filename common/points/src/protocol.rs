@@ -0,0 +1,141 @@
+use std::io::{self, Read, Write};
+
+/// Version of the legacy protocol, using fixed-size `MessageBytes` frames.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+/// Version of the length-prefixed framed protocol, answered strictly in order.
+pub const FRAMED_PROTOCOL_VERSION: u8 = 2;
+/// Version of the framed protocol where every frame carries a `RequestId`.
+/// Requests can be pipelined and responses may arrive out of order.
+pub const PIPELINED_PROTOCOL_VERSION: u8 = 3;
+/// Version of the pipelined protocol where every request is a `KeyedMessage`.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// Identifies a request and its response within a connection.
+pub type RequestId = u32;
+
+/// First byte sent after `CLIENT_CONNECTION` by clients that negotiate a version.
+/// It is never a valid legacy message type, so the server can tell both kinds of clients apart.
+pub const VERSION_NEGOTIATION: u8 = 0;
+
+/// Maximum payload size accepted in a single frame.
+pub const MAX_FRAME_SIZE: usize = 1024;
+
+/// Returns the version both ends will speak, given the one requested by the client.
+pub fn negotiate_version(requested: u8) -> u8 {
+    requested.clamp(LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Writes a frame to the given writer.
+/// The first four bytes are the payload length.
+/// The rest of the bytes are the payload.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame is too large",
+        ));
+    }
+
+    let len = payload.len() as u32;
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(payload);
+    writer.write_all(&buf)
+}
+
+/// Reads a frame from the given reader.
+///
+/// # Returns
+///
+/// The payload of the frame.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame is too large",
+        ));
+    }
+
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Writes a frame whose payload starts with the id of the request it belongs to.
+pub fn write_tagged_frame(
+    writer: &mut impl Write,
+    id: RequestId,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(payload);
+    write_frame(writer, &buf)
+}
+
+/// Reads a frame written by `write_tagged_frame`.
+///
+/// # Returns
+///
+/// The id of the request and the rest of the payload.
+pub fn read_tagged_frame(reader: &mut impl Read) -> io::Result<(RequestId, Vec<u8>)> {
+    let mut frame = read_frame(reader)?;
+    if frame.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame is missing the request id",
+        ));
+    }
+
+    let payload = frame.split_off(4);
+    let id = RequestId::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    Ok((id, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &[1, 2, 3]).unwrap();
+        write_frame(&mut buf, &[]).unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_frame(&mut reader).unwrap(), Vec::<u8>::new());
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut buf = Vec::new();
+        assert!(write_frame(&mut buf, &[0; MAX_FRAME_SIZE + 1]).is_err());
+
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        assert!(read_frame(&mut &len[..]).is_err());
+    }
+
+    #[test]
+    fn tagged_frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_tagged_frame(&mut buf, 7, &[1, 2]).unwrap();
+        write_frame(&mut buf, &[0, 0]).unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_tagged_frame(&mut reader).unwrap(), (7, vec![1, 2]));
+        assert!(read_tagged_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1), PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(0), LEGACY_PROTOCOL_VERSION);
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{error::expect_size, DecodeError};
+
+/// Points of a client: available and locked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Balance {
+    pub available: usize,
+    pub locked: usize,
+    /// Next points to expire, if any of them do.
+    pub expiring: Option<Expiration>,
+}
+
+/// Points earned at the same moment, which expire together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expiration {
+    pub points: usize,
+    /// Milliseconds since the unix epoch.
+    pub at: u64,
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Available [{} Locked]", self.available, self.locked)?;
+        if let Some(expiring) = self.expiring {
+            write!(f, " ({})", expiring)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Expiration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = UNIX_EPOCH + Duration::from_millis(self.at);
+        let left = at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        write!(f, "{} expire in {}s", self.points, left.as_secs())
+    }
+}
+
+const BALANCE_CODE: u8 = 9;
+
+/// Outcome of a client message, as answered by the local server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    /// Answer to a balance query.
+    Balance(Balance),
+    /// The client does not have enough available points.
+    NotEnoughPoints,
+    /// The client does not have enough locked points to free or consume.
+    NotEnoughLockedPoints,
+    /// The local server is disconnected from the rest of the network.
+    Offline,
+    /// The transaction lost a wait-die race or was aborted by another server.
+    Conflict,
+    /// Not enough servers approved the transaction.
+    QuorumNotReached,
+    /// The message is not valid for the requested operation.
+    InvalidMessage,
+    /// The local server failed while handling the message.
+    InternalError,
+    /// The local server could not be reached. It is never sent by the server.
+    Unreachable,
+    /// The message was sent but no response arrived, it may or may not have been applied.
+    /// It is never sent by the server.
+    Unanswered,
+    /// A message with the same key is still being handled, its outcome is not known yet.
+    InProgress,
+}
+
+impl Response {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Response::Ok | Response::Balance(_))
+    }
+
+    /// Returns true if sending the same message again later could succeed.
+    /// A message left unanswered is sent again with the same key to learn its outcome.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Response::Offline
+                | Response::Conflict
+                | Response::QuorumNotReached
+                | Response::Unanswered
+                | Response::InProgress
+        )
+    }
+
+    /// Returns true if the message may have been applied even though it did not succeed.
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Response::Unanswered | Response::InProgress)
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Response::Ok => 0,
+            Response::NotEnoughPoints => 1,
+            Response::NotEnoughLockedPoints => 2,
+            Response::Offline => 3,
+            Response::Conflict => 4,
+            Response::QuorumNotReached => 5,
+            Response::InvalidMessage => 6,
+            Response::InternalError => 7,
+            Response::Unreachable => 8,
+            Response::Balance(_) => BALANCE_CODE,
+            Response::Unanswered => 10,
+            Response::InProgress => 11,
+        }
+    }
+}
+
+impl<T> From<Result<T, Response>> for Response {
+    fn from(result: Result<T, Response>) -> Self {
+        match result {
+            Ok(_) => Response::Ok,
+            Err(response) => response,
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Response::Ok => "Ok",
+            Response::Balance(balance) => return write!(f, "{}", balance),
+            Response::NotEnoughPoints => "Not enough points available",
+            Response::NotEnoughLockedPoints => "Not enough points locked",
+            Response::Offline => "Local server is offline",
+            Response::Conflict => "Transaction aborted by a concurrent one",
+            Response::QuorumNotReached => "Not enough servers approved the transaction",
+            Response::InvalidMessage => "Invalid message",
+            Response::InternalError => "Local server returned error",
+            Response::Unreachable => "Could not reach local server",
+            Response::Unanswered => "Local server did not answer",
+            Response::InProgress => "Request is still being handled",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// Size of a balance without expiring points: 8 bytes available points and 8 bytes locked points.
+const BALANCE_SIZE: usize = 16;
+/// Size of a balance with expiring points: 8 bytes more for the points and 8 bytes for the expiration.
+const EXPIRING_BALANCE_SIZE: usize = BALANCE_SIZE + 16;
+
+/// Payload of a framed response: 1 byte response code.
+/// Balances are followed by 8 bytes available points and 8 bytes locked points,
+/// and by 8 bytes expiring points and 8 bytes expiration time if some points expire.
+impl From<Response> for Vec<u8> {
+    fn from(response: Response) -> Self {
+        let mut buf = vec![response.code()];
+        if let Response::Balance(balance) = response {
+            buf.extend_from_slice(&(balance.available as u64).to_be_bytes());
+            buf.extend_from_slice(&(balance.locked as u64).to_be_bytes());
+            if let Some(expiring) = balance.expiring {
+                buf.extend_from_slice(&(expiring.points as u64).to_be_bytes());
+                buf.extend_from_slice(&expiring.at.to_be_bytes());
+            }
+        }
+        buf
+    }
+}
+
+fn read_u64(buf: &[u8], start: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[start..start + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl TryFrom<&[u8]> for Response {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let (code, body) = buf.split_first().ok_or(DecodeError::InvalidSize {
+            expected: 1,
+            actual: 0,
+        })?;
+
+        if *code == BALANCE_CODE {
+            if body.len() != EXPIRING_BALANCE_SIZE {
+                expect_size(body, BALANCE_SIZE)?;
+            }
+            let expiring = (body.len() == EXPIRING_BALANCE_SIZE).then(|| Expiration {
+                points: read_u64(body, 16) as usize,
+                at: read_u64(body, 24),
+            });
+            return Ok(Response::Balance(Balance {
+                available: read_u64(body, 0) as usize,
+                locked: read_u64(body, 8) as usize,
+                expiring,
+            }));
+        }
+
+        expect_size(buf, 1)?;
+
+        match code {
+            0 => Ok(Response::Ok),
+            1 => Ok(Response::NotEnoughPoints),
+            2 => Ok(Response::NotEnoughLockedPoints),
+            3 => Ok(Response::Offline),
+            4 => Ok(Response::Conflict),
+            5 => Ok(Response::QuorumNotReached),
+            6 => Ok(Response::InvalidMessage),
+            7 => Ok(Response::InternalError),
+            8 => Ok(Response::Unreachable),
+            10 => Ok(Response::Unanswered),
+            11 => Ok(Response::InProgress),
+            c => Err(DecodeError::UnknownResponseCode(*c)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_roundtrip() {
+        let responses = [
+            Response::Ok,
+            Response::NotEnoughPoints,
+            Response::NotEnoughLockedPoints,
+            Response::Offline,
+            Response::Conflict,
+            Response::QuorumNotReached,
+            Response::InvalidMessage,
+            Response::InternalError,
+            Response::Unreachable,
+            Response::Unanswered,
+            Response::InProgress,
+            Response::Balance(Balance {
+                available: 1000,
+                locked: 5,
+                expiring: None,
+            }),
+            Response::Balance(Balance {
+                available: 1000,
+                locked: 5,
+                expiring: Some(Expiration {
+                    points: 30,
+                    at: 1_700_000_000_000,
+                }),
+            }),
+        ];
+        for response in responses {
+            let buf: Vec<u8> = response.clone().into();
+            assert_eq!(Response::try_from(buf.as_slice()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn invalid_response() {
+        assert!(Response::try_from(&[42][..]).is_err());
+        assert!(Response::try_from(&[][..]).is_err());
+        assert!(Response::try_from(&[0, 1][..]).is_err());
+        assert!(Response::try_from(&[9, 0, 0][..]).is_err());
+    }
+
+    #[test]
+    fn response_from_result() {
+        assert_eq!(Response::from(Ok::<(), Response>(())), Response::Ok);
+        assert_eq!(
+            Response::from(Err::<(), Response>(Response::Conflict)),
+            Response::Conflict
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 
-use points::{parse_addr, ControlMessage, CONTROL_MESSAGE};
+use points::{
+    parse_addr, read_tagged_frame, write_tagged_frame, ControlMessage, IdempotencyKey,
+    KeyedMessage, Message, Response, CLIENT_CONNECTION, CONTROL_MESSAGE, PROTOCOL_VERSION,
+    VERSION_NEGOTIATION,
+};
 
 #[derive(Debug)]
 struct Request {
@@ -38,10 +42,66 @@ impl Request {
     }
 }
 
+/// Changes the members of a family account, sent to a server as a client would.
+#[derive(Debug)]
+struct MembershipRequest {
+    msg: Message,
+    addr: String,
+}
+
+impl MembershipRequest {
+    /// Parses `l <card> <account> <server>` to link a card and `u <card> <server>` to unlink it.
+    pub fn parse(line: &str) -> Option<MembershipRequest> {
+        let mut parts = line.split_whitespace();
+        let msg = match parts.next()?.to_lowercase().as_str() {
+            "l" => {
+                let card = parts.next()?.parse().ok()?;
+                let account = parts.next()?.parse().ok()?;
+                Message::LinkCard(card, account)
+            }
+            "u" => Message::UnlinkCard(parts.next()?.parse().ok()?),
+            _ => return None,
+        };
+        let addr = parse_addr(parts.next()?.to_string());
+        Some(MembershipRequest { msg, addr })
+    }
+
+    pub fn send(self, key: IdempotencyKey) -> Result<Response, std::io::Error> {
+        let mut stream = std::net::TcpStream::connect(&self.addr)?;
+        stream.write_all(&[CLIENT_CONNECTION, VERSION_NEGOTIATION, PROTOCOL_VERSION])?;
+        let mut version = [0; 1];
+        stream.read_exact(&mut version)?;
+        if version[0] != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Server does not support family accounts",
+            ));
+        }
+
+        let msg: Vec<u8> = KeyedMessage { key, msg: self.msg }.into();
+        write_tagged_frame(&mut stream, 0, &msg)?;
+        let (_, response) = read_tagged_frame(&mut stream)?;
+        Response::try_from(response.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
 fn main() {
     let stdin = io::stdin();
+    let mut sequence = 0;
     for line in stdin.lock().lines() {
         let line = line.unwrap();
+        if let Some(request) = MembershipRequest::parse(&line) {
+            println!("{:?}", request);
+            let key = IdempotencyKey {
+                coffee_maker: std::process::id(),
+                sequence,
+            };
+            sequence += 1;
+            println!("{:?}", request.send(key));
+            continue;
+        }
+
         let request = Request::parse(&line);
         println!("{:?}", request);
         if let Some(request) = request {
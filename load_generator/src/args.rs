@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+/// Removes a flag and its value from the arguments.
+///
+/// # Returns
+///
+/// The value of the flag, if it was given.
+pub fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let position = match args.iter().position(|arg| arg == flag) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    if position + 1 >= args.len() {
+        return Err(format!("{} expects a value", flag));
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+    Ok(Some(value))
+}
+
+/// Parses the value of an argument, naming it in the error.
+pub fn parse_number<T: FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid {}: {:?}", name, value))
+}
+
+/// Message with the seed of a run and how to run it again with the same one.
+pub fn replay_message(seed_flag: &str, seed: u64) -> String {
+    format!(
+        "Using seed {}, run again with {} {} to replay",
+        seed, seed_flag, seed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_take_flag() {
+        let mut taken = args("bin --seed 7 orders.csv");
+        assert_eq!(take_flag(&mut taken, "--seed"), Ok(Some("7".to_string())));
+        assert_eq!(taken, args("bin orders.csv"));
+        assert_eq!(take_flag(&mut taken, "--seed"), Ok(None));
+        assert!(take_flag(&mut args("bin --seed"), "--seed").is_err());
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number::<u64>("7", "seed"), Ok(7));
+        assert_eq!(
+            parse_number::<u64>("-7", "seed"),
+            Err(String::from("Invalid seed: \"-7\""))
+        );
+    }
+}
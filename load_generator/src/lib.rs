@@ -0,0 +1,437 @@
+use std::{fs, str::FromStr, time::Duration};
+
+/// Command line helpers shared by the binaries of the workspace.
+pub mod args;
+
+use points::{ClientId, Order, OrderAction};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+const DEFAULT_CLIENTS: u32 = 100;
+const DEFAULT_FILL_RATIO: f64 = 0.5;
+const DEFAULT_MIN_POINTS: usize = 1;
+const DEFAULT_MAX_POINTS: usize = 20;
+/// Longest wait between orders, the draws of very low rates are cut to it.
+const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Shape of a stream of orders, read from a TOML file.
+/// Every setting is optional, missing ones take their default value.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workload {
+    /// Orders generated, the stream never ends if it is not given.
+    pub orders: Option<usize>,
+    /// Seed of the stream, a new one is drawn if it is not given.
+    pub seed: Option<u64>,
+    pub clients: Clients,
+    pub actions: Actions,
+    pub points: Points,
+    pub arrivals: Arrivals,
+}
+
+/// Clients that place the orders, with ids from 1 to `count`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "distribution", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Clients {
+    /// Every client places orders as often as the others.
+    Uniform { count: u32 },
+    /// Client `k` places orders `1 / k^exponent` times as often as client 1,
+    /// so the first clients are hot accounts that many orders contend for.
+    Zipf { count: u32, exponent: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Actions {
+    /// Share of the orders that fill points, the rest use them.
+    pub fill_ratio: f64,
+}
+
+/// Points of each order, drawn uniformly between both bounds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Points {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// When the orders arrive.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "process", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Arrivals {
+    /// Every order arrives at once.
+    #[default]
+    Immediate,
+    /// Orders arrive independently, `rate` per second on average.
+    Poisson { rate: f64 },
+    /// Orders arrive `size` at a time, a burst every `every_millis`.
+    Bursts { size: usize, every_millis: u64 },
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Clients::Uniform {
+            count: DEFAULT_CLIENTS,
+        }
+    }
+}
+
+impl Default for Actions {
+    fn default() -> Self {
+        Actions {
+            fill_ratio: DEFAULT_FILL_RATIO,
+        }
+    }
+}
+
+impl Default for Points {
+    fn default() -> Self {
+        Points {
+            min: DEFAULT_MIN_POINTS,
+            max: DEFAULT_MAX_POINTS,
+        }
+    }
+}
+
+impl Clients {
+    /// Parses clients with the format `<count>` for a uniform distribution
+    /// or `<count>,<exponent>` for a Zipf one.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (count, exponent) = match value.split_once(',') {
+            Some((count, exponent)) => (count, Some(exponent)),
+            None => (value, None),
+        };
+        let count = parse_value(count, "client count")?;
+        match exponent {
+            Some(exponent) => Ok(Clients::Zipf {
+                count,
+                exponent: parse_value(exponent, "exponent")?,
+            }),
+            None => Ok(Clients::Uniform { count }),
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        match self {
+            Clients::Uniform { count } | Clients::Zipf { count, .. } => *count,
+        }
+    }
+}
+
+impl Points {
+    /// Parses points with the format `<points>` or `<min>,<max>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (min, max) = value.split_once(',').unwrap_or((value, value));
+        Ok(Points {
+            min: parse_value(min, "points")?,
+            max: parse_value(max, "points")?,
+        })
+    }
+}
+
+impl Arrivals {
+    /// Parses arrivals with the format `immediate`, `poisson:<rate>` or `bursts:<size>,<every_millis>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (process, params) = value.split_once(':').unwrap_or((value, ""));
+        match (process, params.split_once(',')) {
+            ("immediate", _) if params.is_empty() => Ok(Arrivals::Immediate),
+            ("poisson", None) => Ok(Arrivals::Poisson {
+                rate: parse_value(params, "rate")?,
+            }),
+            ("bursts", Some((size, every_millis))) => Ok(Arrivals::Bursts {
+                size: parse_value(size, "burst size")?,
+                every_millis: parse_value(every_millis, "milliseconds")?,
+            }),
+            _ => Err(format!("Invalid arrivals: {:?}", value)),
+        }
+    }
+}
+
+impl Workload {
+    /// Reads the workload of a TOML file.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let workload: Workload =
+            toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+        workload
+            .validate()
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(workload)
+    }
+
+    /// Checks that every setting has a valid value.
+    ///
+    /// # Returns
+    ///
+    /// The first invalid setting, with the reason.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.clients.count() == 0 {
+            return Err(String::from("clients.count: must be at least 1"));
+        }
+        if let Clients::Zipf { exponent, .. } = self.clients {
+            if exponent.is_nan() || exponent <= 0.0 {
+                return Err(format!(
+                    "clients.exponent: must be greater than 0, not {}",
+                    exponent
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.actions.fill_ratio) {
+            return Err(format!(
+                "actions.fill_ratio: must be between 0 and 1, not {}",
+                self.actions.fill_ratio
+            ));
+        }
+        if self.points.max < self.points.min {
+            return Err(String::from("points.max: must not be less than points.min"));
+        }
+        match self.arrivals {
+            Arrivals::Poisson { rate } if rate.is_nan() || rate <= 0.0 => Err(format!(
+                "arrivals.rate: must be greater than 0, not {}",
+                rate
+            )),
+            Arrivals::Bursts { size: 0, .. } => {
+                Err(String::from("arrivals.size: must be at least 1"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An order of the stream, along with the time since the previous one arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arrival {
+    pub delay: Duration,
+    pub order: Order,
+}
+
+/// Generates the orders of a workload, the same ones on every run with the same seed.
+pub struct Generator {
+    workload: Workload,
+    seed: u64,
+    rng: StdRng,
+    /// Accumulated weights of the clients, when they follow a Zipf distribution.
+    weights: Vec<f64>,
+    generated: usize,
+}
+
+impl Generator {
+    pub fn new(workload: Workload) -> Self {
+        let seed = workload.seed.unwrap_or_else(rand::random);
+        let weights = match workload.clients {
+            Clients::Uniform { .. } => Vec::new(),
+            Clients::Zipf { count, exponent } => (1..=count)
+                .scan(0.0, |total, rank| {
+                    *total += 1.0 / (rank as f64).powf(exponent);
+                    Some(*total)
+                })
+                .collect(),
+        };
+
+        Generator {
+            workload,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            weights,
+            generated: 0,
+        }
+    }
+
+    /// Seed of the stream, to generate it again.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn client(&mut self) -> ClientId {
+        match self.workload.clients {
+            Clients::Uniform { count } => self.rng.gen_range(1..=count),
+            Clients::Zipf { .. } => {
+                let total = self.weights[self.weights.len() - 1];
+                let drawn = self.rng.gen_range(0.0..total);
+                let rank = self.weights.partition_point(|weight| *weight <= drawn);
+                rank.min(self.weights.len() - 1) as ClientId + 1
+            }
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        match self.workload.arrivals {
+            Arrivals::Immediate => Duration::ZERO,
+            // Time between independent arrivals is exponentially distributed
+            Arrivals::Poisson { rate } => {
+                let drawn: f64 = self.rng.gen_range(0.0..1.0);
+                Duration::try_from_secs_f64(-(1.0 - drawn).ln() / rate)
+                    .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+            }
+            Arrivals::Bursts { size, every_millis } => {
+                if self.generated > 0 && self.generated.is_multiple_of(size) {
+                    Duration::from_millis(every_millis)
+                } else {
+                    Duration::ZERO
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Arrival;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if Some(self.generated) == self.workload.orders {
+            return None;
+        }
+
+        let delay = self.delay();
+        let client_id = self.client();
+        let points = self
+            .rng
+            .gen_range(self.workload.points.min..=self.workload.points.max);
+        let action = if self.rng.gen_bool(self.workload.actions.fill_ratio) {
+            OrderAction::FillPoints(points)
+        } else {
+            OrderAction::UsePoints(points)
+        };
+        self.generated += 1;
+
+        Some(Arrival {
+            delay,
+            order: Order::new(client_id, action),
+        })
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("Invalid {}: {:?}", name, value))
+}
+
+/// Line of an orders file for the order, as read by `Order::parse`.
+pub fn order_line(order: &Order) -> String {
+    match &order.action {
+        OrderAction::UsePoints(points) => format!("{},USE,{}", order.client_id, points),
+        OrderAction::FillPoints(points) => format!("{},FILL,{}", order.client_id, points),
+        OrderAction::Transfer { to, points } => {
+            format!("{},TRANSFER,{},{}", order.client_id, points, to)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workload(toml: &str) -> Workload {
+        let workload: Workload = toml::from_str(toml).unwrap();
+        workload.validate().unwrap();
+        workload
+    }
+
+    #[test]
+    fn test_same_seed_same_orders() {
+        let workload = workload("orders = 50\nseed = 7\n");
+        let first: Vec<Arrival> = Generator::new(workload.clone()).collect();
+        let second: Vec<Arrival> = Generator::new(workload).collect();
+        assert_eq!(first.len(), 50);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_orders_within_bounds() {
+        let workload = workload(
+            "orders = 500\n[clients]\ndistribution = \"uniform\"\ncount = 5\n[actions]\nfill_ratio = 0.0\n[points]\nmin = 3\nmax = 4\n",
+        );
+        for arrival in Generator::new(workload) {
+            assert!((1..=5).contains(&arrival.order.client_id));
+            match arrival.order.action {
+                OrderAction::UsePoints(points) => assert!((3..=4).contains(&points)),
+                action => panic!("Unexpected action {:?}", action),
+            }
+        }
+    }
+
+    #[test]
+    fn test_zipf_favors_first_clients() {
+        let workload = workload(
+            "orders = 2000\nseed = 1\n[clients]\ndistribution = \"zipf\"\ncount = 100\nexponent = 1.2\n",
+        );
+        let mut counts = vec![0; 101];
+        for arrival in Generator::new(workload) {
+            counts[arrival.order.client_id as usize] += 1;
+        }
+        assert!(counts[1] > counts[2]);
+        assert!(counts[1] > 10 * counts[100]);
+    }
+
+    #[test]
+    fn test_bursts() {
+        let workload = workload(
+            "orders = 6\n[arrivals]\nprocess = \"bursts\"\nsize = 3\nevery_millis = 100\n",
+        );
+        let delays: Vec<u128> = Generator::new(workload)
+            .map(|arrival| arrival.delay.as_millis())
+            .collect();
+        assert_eq!(delays, vec![0, 0, 0, 100, 0, 0]);
+    }
+
+    #[test]
+    fn test_poisson_rate() {
+        let workload =
+            workload("orders = 1000\nseed = 3\n[arrivals]\nprocess = \"poisson\"\nrate = 100.0\n");
+        let total: Duration = Generator::new(workload).map(|arrival| arrival.delay).sum();
+        assert!(total > Duration::from_secs(8) && total < Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_poisson_delays_are_capped() {
+        let workload =
+            workload("orders = 100\nseed = 3\n[arrivals]\nprocess = \"poisson\"\nrate = 1e-300\n");
+        for arrival in Generator::new(workload) {
+            assert!(arrival.delay <= MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn test_invalid_workload() {
+        let invalid: Workload = toml::from_str("[points]\nmin = 5\nmax = 1\n").unwrap();
+        assert!(invalid.validate().is_err());
+        assert!(
+            toml::from_str::<Workload>("[clients]\ndistribution = \"pareto\"\ncount = 5\n")
+                .is_err()
+        );
+        assert!(toml::from_str::<Workload>("rate = 5\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(
+            Clients::parse("50,1.1"),
+            Ok(Clients::Zipf {
+                count: 50,
+                exponent: 1.1
+            })
+        );
+        assert_eq!(Points::parse("5"), Ok(Points { min: 5, max: 5 }));
+        assert_eq!(
+            Arrivals::parse("bursts:10,500"),
+            Ok(Arrivals::Bursts {
+                size: 10,
+                every_millis: 500
+            })
+        );
+        assert_eq!(
+            Arrivals::parse("poisson:20"),
+            Ok(Arrivals::Poisson { rate: 20.0 })
+        );
+        assert!(Arrivals::parse("immediate:3").is_err());
+        assert!(Arrivals::parse("bursts:10").is_err());
+    }
+
+    #[test]
+    fn test_order_line() {
+        let order = Order::new(3, OrderAction::FillPoints(12));
+        assert_eq!(Order::parse(&order_line(&order)), Ok(order));
+    }
+}
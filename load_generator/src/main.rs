@@ -0,0 +1,122 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    process::exit,
+    thread,
+};
+
+use load_generator::{
+    args::{parse_number, replay_message, take_flag},
+    order_line, Arrivals, Clients, Generator, Points, Workload,
+};
+
+/// Flag followed by the amount of orders to generate.
+const ORDERS_FLAG: &str = "--orders";
+/// Flag followed by the clients, `<count>` or `<count>,<zipf_exponent>`.
+const CLIENTS_FLAG: &str = "--clients";
+/// Flag followed by the share of the orders that fill points.
+const FILL_RATIO_FLAG: &str = "--fill-ratio";
+/// Flag followed by the points of each order, `<points>` or `<min>,<max>`.
+const POINTS_FLAG: &str = "--points";
+/// Flag followed by `immediate`, `poisson:<rate>` or `bursts:<size>,<every_millis>`.
+const ARRIVALS_FLAG: &str = "--arrivals";
+/// Flag followed by the seed of the stream, to generate it again.
+const SEED_FLAG: &str = "--seed";
+/// Flag followed by the file where the orders are written, at once.
+/// Without it they are written to the standard output as they arrive.
+const OUTPUT_FLAG: &str = "--output";
+
+/// Reads the workload, if one is given, and overrides it with the rest of the arguments.
+fn parse_args() -> Result<(Workload, Option<String>), String> {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    let orders = take_flag(&mut args, ORDERS_FLAG)?;
+    let clients = take_flag(&mut args, CLIENTS_FLAG)?;
+    let fill_ratio = take_flag(&mut args, FILL_RATIO_FLAG)?;
+    let points = take_flag(&mut args, POINTS_FLAG)?;
+    let arrivals = take_flag(&mut args, ARRIVALS_FLAG)?;
+    let seed = take_flag(&mut args, SEED_FLAG)?;
+    let output = take_flag(&mut args, OUTPUT_FLAG)?;
+
+    let mut workload = match args.get(1) {
+        Some(path) => Workload::read(path)?,
+        None => Workload::default(),
+    };
+    if args.len() > 2 {
+        return Err(format!("Unexpected argument: {}", args[2]));
+    }
+
+    if let Some(orders) = orders {
+        workload.orders = Some(parse_number(&orders, "orders")?);
+    }
+    if let Some(clients) = clients {
+        workload.clients = Clients::parse(&clients)?;
+    }
+    if let Some(fill_ratio) = fill_ratio {
+        workload.actions.fill_ratio = parse_number(&fill_ratio, "fill ratio")?;
+    }
+    if let Some(points) = points {
+        workload.points = Points::parse(&points)?;
+    }
+    if let Some(arrivals) = arrivals {
+        workload.arrivals = Arrivals::parse(&arrivals)?;
+    }
+    if let Some(seed) = seed {
+        workload.seed = Some(parse_number(&seed, "seed")?);
+    }
+    workload.validate()?;
+
+    Ok((workload, output))
+}
+
+fn usage() -> String {
+    format!(
+        "Usage: load_generator [<workload>] [{} <orders>] [{} <count>[,<zipf_exponent>]] [{} <ratio>] [{} <min>[,<max>]] [{} <immediate|poisson:<rate>|bursts:<size>,<every_millis>>] [{} <seed>] [{} <path>]",
+        ORDERS_FLAG, CLIENTS_FLAG, FILL_RATIO_FLAG, POINTS_FLAG, ARRIVALS_FLAG, SEED_FLAG, OUTPUT_FLAG
+    )
+}
+
+/// Writes the orders to a file at once, or to the standard output paced by their arrivals.
+fn main() {
+    let (workload, output) = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, usage());
+            exit(-1);
+        }
+    };
+
+    let generator = Generator::new(workload);
+    // The standard output only has orders, so it can be piped to a coffee maker
+    eprintln!("{}", replay_message(SEED_FLAG, generator.seed()));
+
+    let written = match output {
+        Some(path) => File::create(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+            .and_then(|file| write_orders(generator, BufWriter::new(file), false)),
+        None => write_orders(generator, io::stdout().lock(), true),
+    };
+
+    match written {
+        // The coffee maker stopped reading
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("Could not write orders: {}", e);
+            exit(-1);
+        }
+        Ok(()) => {}
+    }
+}
+
+fn write_orders<W: Write>(generator: Generator, mut writer: W, paced: bool) -> io::Result<()> {
+    for arrival in generator {
+        if paced {
+            thread::sleep(arrival.delay);
+        }
+        writeln!(writer, "{}", order_line(&arrival.order))?;
+        if paced {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
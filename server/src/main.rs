@@ -1,23 +1,48 @@
 mod server;
 mod threadpool;
 
+use std::time::Duration;
+
 use points::parse_addr;
 use server::Server;
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
 
-fn parse_args() -> Result<(String, Option<String>), ()> {
-    let args: Vec<String> = std::env::args().collect();
+/// Flag followed by the seconds after which added points expire.
+const EXPIRE_AFTER_FLAG: &str = "--expire-after";
+
+type Args = (String, Option<String>, Option<Duration>);
+
+fn parse_args() -> Result<Args, ()> {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    let mut expiration = None;
+    if let Some(flag) = args.iter().position(|arg| arg == EXPIRE_AFTER_FLAG) {
+        let secs = args.get(flag + 1).and_then(|secs| secs.parse().ok());
+        match secs {
+            Some(secs) => expiration = Some(Duration::from_secs(secs)),
+            None => {
+                error!("{} expects a number of seconds", EXPIRE_AFTER_FLAG);
+                return Err(());
+            }
+        }
+        args.drain(flag..flag + 2);
+    }
+
     if args.len() == 2 {
-        return Ok((parse_addr(args[1].clone()), None));
+        return Ok((parse_addr(args[1].clone()), None, expiration));
     }
     if args.len() == 3 {
         return Ok((
             parse_addr(args[1].clone()),
             Some(parse_addr(args[2].clone())),
+            expiration,
         ));
     }
-    error!("Usage: local_server <address> [<known_server_address>]");
+    error!(
+        "Usage: local_server <address> [<known_server_address>] [{} <seconds>]",
+        EXPIRE_AFTER_FLAG
+    );
     Err(())
 }
 
@@ -32,8 +57,8 @@ fn init_logger() {
 fn main() {
     init_logger();
 
-    if let Ok((addr, core_server_addr)) = parse_args() {
-        let server = Server::new(addr, core_server_addr);
+    if let Ok((addr, core_server_addr, expiration)) = parse_args() {
+        let server = Server::new(addr, core_server_addr, expiration);
         let handler = server.listen();
 
         handler.join().unwrap();
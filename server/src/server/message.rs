@@ -4,7 +4,7 @@ use std::{
     net::TcpStream,
 };
 
-use points::SERVER_MESSAGE;
+use points::{Balance, ClientId, Expiration, SERVER_MESSAGE};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, trace};
 
@@ -15,6 +15,7 @@ pub const CONNECT: u8 = 1;
 pub const SYNC: u8 = 2;
 pub const TRANSACTION: u8 = 3;
 pub const PING: u8 = 4;
+pub const BALANCE: u8 = 5;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectRequest {
@@ -35,6 +36,19 @@ pub struct SyncResponse {
     pub points: PointMap,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceRequest {
+    pub client_id: ClientId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceResponse {
+    pub available: usize,
+    pub locked: usize,
+    #[serde(default)]
+    pub expiring: Option<Expiration>,
+}
+
 /// Sends a message to the given address.
 /// The message is serialized and sent as a byte array.
 /// The first byte is the message type.
@@ -183,3 +197,22 @@ pub fn sync_with(addr: &String) -> Result<PointMap, String> {
 
     Ok(points)
 }
+
+/// Sends a BALANCE message to the given address.
+///
+/// # Returns
+///
+/// The balance of the client on the given server.
+pub fn balance_from(addr: &String, client_id: ClientId) -> Result<Balance, String> {
+    let msg = BalanceRequest { client_id };
+    trace!("Sending BALANCE to {}", addr);
+    let res = send_message_to(BALANCE, msg, addr)?;
+    let res: BalanceResponse =
+        serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
+
+    Ok(Balance {
+        available: res.available,
+        locked: res.locked,
+        expiring: res.expiring,
+    })
+}
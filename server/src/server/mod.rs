@@ -1,14 +1,18 @@
 mod message;
+mod outcomes;
 mod pending_transactions;
 mod ping;
 mod point_record;
 mod point_storage;
 mod transaction;
 
-use point_storage::PointStorage;
+use outcomes::Outcome;
+use point_storage::{PointStorage, EXPIRY_SWEEP_INTERVAL};
 use points::{
-    ControlBytes, ControlMessage, Message, CLIENT_CONNECTION, CONTROL_MESSAGE, MESSAGE_BUFFER_SIZE,
-    SERVER_MESSAGE,
+    negotiate_version, read_frame, read_tagged_frame, write_frame, write_tagged_frame, ClientId,
+    ControlBytes, ControlMessage, IdempotencyKey, KeyedMessage, Message, MessageBytes,
+    ReadConsistency, Response, CLIENT_CONNECTION, CONTROL_MESSAGE, FRAMED_PROTOCOL_VERSION,
+    LEGACY_PROTOCOL_VERSION, PIPELINED_PROTOCOL_VERSION, SERVER_MESSAGE, VERSION_NEGOTIATION,
 };
 
 use std::thread::JoinHandle;
@@ -29,7 +33,9 @@ use crate::server::{
 use crate::threadpool::{Builder, ThreadPool};
 
 use self::{
-    message::{ConnectRequest, CONNECT, PING, SYNC, TRANSACTION},
+    message::{
+        BalanceRequest, BalanceResponse, ConnectRequest, BALANCE, CONNECT, PING, SYNC, TRANSACTION,
+    },
     transaction::{Transaction, TxOk},
 };
 
@@ -49,6 +55,9 @@ const PING_INTERVAL: u64 = 1000;
 
 const N_THREADS: usize = 10;
 
+/// Requests of a pipelined connection that are handled at the same time.
+const PIPELINED_THREADS: usize = 10;
+
 const INTERVAL_LOGGER: u64 = 3000;
 
 impl Server {
@@ -58,13 +67,18 @@ impl Server {
     ///
     /// * `address` - The address to listen on.
     /// * `core_server_addr` - The address of any known server.
-    pub fn new(address: String, core_server_addr: Option<String>) -> Server {
+    /// * `expiration` - The time after which added points expire, if they do.
+    pub fn new(
+        address: String,
+        core_server_addr: Option<String>,
+        expiration: Option<Duration>,
+    ) -> Server {
         let listener = TcpListener::bind(address.clone()).unwrap();
 
         Server {
             address: address.clone(),
             listener,
-            points: PointStorage::new(address, core_server_addr),
+            points: PointStorage::new(address, core_server_addr, expiration),
             thread_pool: Builder::new().num_threads(N_THREADS).build(),
         }
     }
@@ -76,6 +90,7 @@ impl Server {
         self.spawn_logger(INTERVAL_LOGGER);
         self.spawn_pending_handler();
         self.spawn_ping_handler();
+        self.spawn_expiry_sweeper();
 
         thread::spawn(move || {
             debug!("Listening on {}", self.address);
@@ -121,53 +136,256 @@ impl Server {
     }
 
     /// Handles messages from a client connection while the connection is open.
+    /// Clients that negotiate a version start with `VERSION_NEGOTIATION`,
+    /// any other first byte is the start of a legacy fixed-size message.
+    /// Connections reset before their address can be read are closed right away.
     fn connection_handler(mut stream: TcpStream, points: Arc<Mutex<PointStorage>>) {
-        let addr = stream.local_addr().unwrap().ip().to_string();
+        let addr = match stream.local_addr() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(e) => {
+                error!("Could not read the client address: {}", e);
+                return;
+            }
+        };
         debug!("Connection established with {}", addr);
 
-        let mut message_buffer = [0; MESSAGE_BUFFER_SIZE];
+        let mut first_byte = [0; 1];
+        if stream.read_exact(&mut first_byte).is_ok() {
+            if first_byte[0] == VERSION_NEGOTIATION {
+                Self::negotiated_connection_handler(&mut stream, points);
+            } else {
+                Self::legacy_connection_handler(&mut stream, Some(first_byte[0]), points);
+            }
+        }
+
+        debug!("Connection closed with {}", addr);
+    }
+
+    /// Agrees on a protocol version with the client and handles its messages accordingly.
+    /// Messages that cannot be decoded are answered with `Response::InvalidMessage`.
+    /// Frames that cannot be read close the connection.
+    fn negotiated_connection_handler(stream: &mut TcpStream, points: Arc<Mutex<PointStorage>>) {
+        let mut version = [0; 1];
+        if stream.read_exact(&mut version).is_err() {
+            return;
+        }
+
+        let version = negotiate_version(version[0]);
+        if stream.write_all(&[version]).is_err() {
+            error!("Failed to send negotiated version");
+            return;
+        }
+        debug!("Negotiated protocol version {}", version);
+
+        match version {
+            LEGACY_PROTOCOL_VERSION => Self::legacy_connection_handler(stream, None, points),
+            FRAMED_PROTOCOL_VERSION => Self::framed_connection_handler(stream, points),
+            PIPELINED_PROTOCOL_VERSION => Self::pipelined_connection_handler(stream, false, points),
+            _ => Self::pipelined_connection_handler(stream, true, points),
+        }
+    }
+
+    /// Handles framed messages one at a time, answering them in order.
+    fn framed_connection_handler(stream: &mut TcpStream, points: Arc<Mutex<PointStorage>>) {
+        while let Ok(frame) = read_frame(stream) {
+            let response: Vec<u8> = Self::handle_client_frame(&frame, false, points.clone()).into();
+            if write_frame(stream, &response).is_err() {
+                error!("Failed to send response");
+            }
+        }
+    }
+
+    /// Handles the requests on a pool of threads of the connection, so a slow transaction
+    /// does not delay the rest. Requests wait in the pool while every thread is busy.
+    /// Responses are sent as soon as they are ready, tagged with the id of their request.
+    /// If `keyed` is set, every request is a `KeyedMessage`.
+    fn pipelined_connection_handler(
+        stream: &mut TcpStream,
+        keyed: bool,
+        points: Arc<Mutex<PointStorage>>,
+    ) {
+        let writer = match stream.try_clone() {
+            Ok(writer) => Arc::new(Mutex::new(writer)),
+            Err(_) => {
+                error!("Failed to clone client stream");
+                return;
+            }
+        };
+
+        let handlers = ThreadPool::new(PIPELINED_THREADS);
+        while let Ok((id, frame)) = read_tagged_frame(stream) {
+            let writer = writer.clone();
+            let points = points.clone();
+            handlers.execute(move || {
+                let response: Vec<u8> = Self::handle_client_frame(&frame, keyed, points).into();
+                let sent = match writer.lock() {
+                    Ok(mut writer) => write_tagged_frame(&mut *writer, id, &response).is_ok(),
+                    Err(_) => false,
+                };
+                if !sent {
+                    error!("Failed to send response to request {}", id);
+                }
+            });
+        }
+
+        handlers.join();
+    }
+
+    /// Decodes and handles a framed message, which is a `KeyedMessage` if `keyed` is set.
+    /// Messages that cannot be decoded are answered with `Response::InvalidMessage`.
+    fn handle_client_frame(
+        frame: &[u8],
+        keyed: bool,
+        points: Arc<Mutex<PointStorage>>,
+    ) -> Response {
+        let response = if keyed {
+            KeyedMessage::try_from(frame).map(|keyed| Self::handle_keyed_message(keyed, points))
+        } else {
+            Message::try_from(frame).map(|msg| Self::handle_client_message(msg, None, points))
+        };
+
+        match response {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to decode message: {}", e);
+                Response::InvalidMessage
+            }
+        }
+    }
+
+    /// Handles legacy fixed-size messages.
+    /// The first byte of the first message may have already been read from the stream.
+    /// Legacy clients are answered with a single byte: 1 if the message succeeded, 0 otherwise.
+    fn legacy_connection_handler(
+        stream: &mut TcpStream,
+        first_byte: Option<u8>,
+        points: Arc<Mutex<PointStorage>>,
+    ) {
+        let mut message_buffer: MessageBytes = Default::default();
+
+        if let Some(first_byte) = first_byte {
+            message_buffer[0] = first_byte;
+            if stream.read_exact(&mut message_buffer[1..]).is_err() {
+                return;
+            }
+            Self::respond_legacy(message_buffer, stream, points.clone());
+        }
 
         while stream.read_exact(&mut message_buffer).is_ok() {
-            let msg = message_buffer.into();
-            Self::handle_client_message(msg, &mut stream, points.clone())
+            Self::respond_legacy(message_buffer, stream, points.clone());
         }
+    }
 
-        debug!("Connection closed with {}", addr);
+    /// Handles a legacy message and answers it with a single byte.
+    /// Messages that cannot be decoded are answered as failed, the connection stays open
+    /// since every legacy message has the same size.
+    fn respond_legacy(
+        message_buffer: MessageBytes,
+        stream: &mut TcpStream,
+        points: Arc<Mutex<PointStorage>>,
+    ) {
+        let response = match Message::try_from(message_buffer) {
+            Ok(msg) => Self::handle_client_message(msg, None, points),
+            Err(e) => {
+                error!("Failed to decode legacy message: {}", e);
+                Response::InvalidMessage
+            }
+        };
+        if stream.write_all(&[u8::from(response.is_ok())]).is_err() {
+            error!("Failed to send response");
+        }
+    }
+
+    /// Handles a message with an idempotency key.
+    /// Requests that were already handled are answered with their original response,
+    /// requests still being handled elsewhere are answered with `Response::InProgress`.
+    fn handle_keyed_message(keyed: KeyedMessage, points: Arc<Mutex<PointStorage>>) -> Response {
+        let KeyedMessage { key, msg } = keyed;
+        if let Message::QueryBalance(..) = msg {
+            return Self::handle_client_message(msg, None, points);
+        }
+
+        let outcomes = match points.lock() {
+            Ok(points) => points.outcomes.clone(),
+            Err(_) => return Response::InternalError,
+        };
+
+        match outcomes.begin(key) {
+            Outcome::New => {}
+            Outcome::InProgress => {
+                info!("Request {:?} is already in progress", key);
+                return Response::InProgress;
+            }
+            Outcome::Known(response) => {
+                info!("Request {:?} was already handled: {:?}", key, response);
+                return response;
+            }
+        }
+
+        let response = Self::handle_client_message(msg, Some(key), points);
+        outcomes.finish(key, &response);
+        response
     }
 
     /// Handles a message from a client connection.
     /// The message could mean the beginning of a new transaction.
-    /// The message is responded to with a message containing the points that were affected by the transaction.
     /// The points are also synchronized with other servers.
+    ///
+    /// # Returns
+    ///
+    /// The response to be sent to the client.
     fn handle_client_message(
         msg: Message,
-        stream: &mut TcpStream,
+        key: Option<IdempotencyKey>,
         points: Arc<Mutex<PointStorage>>,
-    ) {
+    ) -> Response {
         info!("Received {:?}", msg);
 
+        if let Message::QueryBalance(client_id, consistency) = msg {
+            return Self::handle_balance_query(client_id, consistency, points);
+        }
+
         let result = match msg.handle_trivially() {
             Ok(()) => {
                 debug!("Handled trivially {:?}", msg);
                 Ok(())
             }
-            Err(_) => Self::handle_client_message_distributively(msg, points),
+            Err(_) => Self::handle_client_message_distributively(msg, key, points),
         };
 
-        let response = u8::from(result.is_ok());
-        if stream.write_all(&[response]).is_err() {
-            error!("Failed to send response");
+        let response = Response::from(result);
+        info!("Sending response: {:?}", response);
+        response
+    }
+
+    /// Handles a balance query from a client connection.
+    /// A quorum read also asks the other servers for the balance.
+    fn handle_balance_query(
+        client_id: ClientId,
+        consistency: ReadConsistency,
+        points: Arc<Mutex<PointStorage>>,
+    ) -> Response {
+        let balance = match consistency {
+            ReadConsistency::Local => PointStorage::local_balance(points, client_id),
+            ReadConsistency::Quorum => PointStorage::quorum_balance(points, client_id),
+        };
+
+        let response = match balance {
+            Ok(balance) => Response::Balance(balance),
+            Err(e) => e,
         };
-        info!("Sent response: {:?} [{}]", result, response);
+        info!("Sending response: {:?}", response);
+        response
     }
 
     /// Handles a message from a client connection that needs to be distributed to other servers.
     /// Verifies if the transaction could be completed and attempts to distribute it.
     fn handle_client_message_distributively(
         msg: Message,
+        key: Option<IdempotencyKey>,
         points: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        PointStorage::coordinate_msg(msg, points)?;
+    ) -> Result<(), Response> {
+        PointStorage::coordinate_msg(msg, key, points)?;
         Ok(())
     }
 
@@ -198,6 +416,7 @@ impl Server {
             SYNC => Self::handle_server_sync(stream, storage),
             TRANSACTION => Self::handle_server_transaction(stream, storage),
             PING => Self::handle_server_ping(stream, storage),
+            BALANCE => Self::handle_server_balance(stream, storage),
             _ => Err("Unknown message type".to_string()),
         };
 
@@ -255,10 +474,13 @@ impl Server {
             serde_json::from_slice(&res).map_err(|_| "Failed to parse transaction")?;
         // debug!("Received: {:?}", tx);
         let action = match tx.action {
-            TransactionAction::Add => "ADD",
-            TransactionAction::Lock => "LOCK",
-            TransactionAction::Free => "FREE",
-            TransactionAction::Consume => "CONSUME",
+            TransactionAction::Add => "ADD".to_string(),
+            TransactionAction::Lock => "LOCK".to_string(),
+            TransactionAction::Free => "FREE".to_string(),
+            TransactionAction::Consume => "CONSUME".to_string(),
+            TransactionAction::Transfer { to } => format!("TRANSFER to client {}", to),
+            TransactionAction::Link { account } => format!("LINK to account {:?}", account),
+            TransactionAction::Expire { before } => format!("EXPIRE earned before {}", before),
         };
         debug!(
             "Received transaction from coordinator '{}' with timestamp {} for client {} to {} {} points.",
@@ -298,6 +520,26 @@ impl Server {
         });
     }
 
+    /// Spawns a thread to expire points periodically, if they expire.
+    /// It runs for the life of the server, so it does not take a thread of the pool.
+    fn spawn_expiry_sweeper(&self) {
+        let storage = self.points.clone();
+        let expires = storage
+            .lock()
+            .expect("Failed to lock storage")
+            .expiration
+            .is_some();
+        if !expires {
+            return;
+        }
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(EXPIRY_SWEEP_INTERVAL));
+            if let Err(e) = PointStorage::expire_points(storage.clone()) {
+                error!("Failed to expire points: {}", e);
+            }
+        });
+    }
+
     /// Handles pending transactions.
     /// Coordinates the pending transactions if the server is online.
     fn pending_handler(storage: Arc<Mutex<PointStorage>>) {
@@ -343,6 +585,34 @@ impl Server {
         respond_to(&mut stream, serialized_res)
     }
 
+    /// Handles a balance request from another server.
+    /// The request is responded to with the local balance of the client.
+    /// Offline servers do not respond.
+    fn handle_server_balance(
+        mut stream: TcpStream,
+        storage: Arc<Mutex<PointStorage>>,
+    ) -> Result<(), String> {
+        let res = receive_from(&mut stream)?;
+        let online = storage.lock().map_err(|_| "Failed to lock points")?.online;
+        if !online {
+            return Ok(());
+        }
+
+        let req: BalanceRequest =
+            serde_json::from_slice(&res).map_err(|_| "Failed to parse balance request")?;
+
+        let balance =
+            PointStorage::local_balance(storage, req.client_id).map_err(|e| e.to_string())?;
+        let res = BalanceResponse {
+            available: balance.available,
+            locked: balance.locked,
+            expiring: balance.expiring,
+        };
+
+        let serialized_res = serde_json::to_string(&res).map_err(|e| e.to_string())?;
+        respond_to(&mut stream, serialized_res)
+    }
+
     /// Spawns a job to handle pings to other servers.
     fn spawn_ping_handler(&mut self) {
         let storage = self.points.clone();
@@ -386,14 +656,22 @@ impl Server {
 #[cfg(test)]
 mod tests {
     use crate::server::message::{send_message_to, SyncRequest, SYNC};
-    use points::{parse_addr, ControlMessage, CONTROL_MESSAGE};
+    use points::{
+        parse_addr, read_frame, read_tagged_frame, write_frame, write_tagged_frame, Balance,
+        ClientId, ControlMessage, IdempotencyKey, KeyedMessage, Message, MessageBytes, Order,
+        OrderAction, ReadConsistency, Response, CLIENT_CONNECTION, CONTROL_MESSAGE,
+        FRAMED_PROTOCOL_VERSION, PIPELINED_PROTOCOL_VERSION, PROTOCOL_VERSION, VERSION_NEGOTIATION,
+    };
     use serde_json::{json, Value};
     use serial_test::serial;
-    use std::io::Write;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
     use std::process::{Child, Command, Stdio};
     use std::thread;
     use std::time::Duration;
 
+    const CONNECT_RETRIES: usize = 10;
+
     // Este codigo es el mismo que en controller/src pero no lo podia importar :(
     #[derive(Debug)]
     struct Request {
@@ -431,19 +709,42 @@ mod tests {
         }
     }
 
-    fn create_server(address: &str, known_server_address: Option<&str>) -> Child {
-        if let Some(known_address) = known_server_address {
-            return Command::new("cargo")
-                .args(["run", "--bin", "server", address, known_address])
-                .stdout(Stdio::null())
-                .spawn()
-                .expect("Failed to start server");
+    /// A server started by a test, which is reaped once it is killed.
+    struct ServerProcess(Child);
+
+    impl ServerProcess {
+        fn kill(&mut self) -> std::io::Result<()> {
+            self.0.kill()?;
+            self.0.wait()?;
+            Ok(())
         }
-        Command::new("cargo")
-            .args(["run", "--bin", "server", address])
+    }
+
+    fn create_server(address: &str, known_server_address: Option<&str>) -> ServerProcess {
+        let mut args = vec!["run", "--bin", "server", address];
+        args.extend(known_server_address);
+        let server = Command::new("cargo")
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+        ServerProcess(server)
+    }
+
+    fn create_expiring_server(
+        address: &str,
+        known_server_address: Option<&str>,
+        expire_after: &str,
+    ) -> ServerProcess {
+        let mut args = vec!["run", "--bin", "server", address];
+        args.extend(known_server_address);
+        args.extend(["--expire-after", expire_after]);
+        let server = Command::new("cargo")
+            .args(args)
             .stdout(Stdio::null())
             .spawn()
-            .expect("Failed to start server")
+            .expect("Failed to start server");
+        ServerProcess(server)
     }
 
     fn create_coffee_maker(
@@ -481,6 +782,46 @@ mod tests {
             .expect("Failed to start coffee maker")
     }
 
+    fn connect_to_server(address: &str) -> TcpStream {
+        // Reintentamos mientras el servidor termina de levantar
+        for _ in 0..CONNECT_RETRIES {
+            if let Ok(stream) = TcpStream::connect(parse_addr(address.to_string())) {
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        panic!("Failed to connect to {}", address);
+    }
+
+    fn connect_client(address: &str, version: u8) -> TcpStream {
+        let mut stream = connect_to_server(address);
+        stream
+            .write_all(&[CLIENT_CONNECTION, VERSION_NEGOTIATION, version])
+            .unwrap();
+        let mut negotiated = [0; 1];
+        stream.read_exact(&mut negotiated).unwrap();
+        assert_eq!(negotiated, [version]);
+        stream
+    }
+
+    fn send_keyed(address: &str, key: IdempotencyKey, msg: Message) -> Response {
+        let mut stream = connect_client(address, PROTOCOL_VERSION);
+        let msg: Vec<u8> = KeyedMessage { key, msg }.into();
+        write_tagged_frame(&mut stream, 1, &msg).unwrap();
+        let (id, response) = read_tagged_frame(&mut stream).unwrap();
+        assert_eq!(id, 1);
+        Response::try_from(response.as_slice()).unwrap()
+    }
+
+    fn query_balance(address: &str, client_id: ClientId, consistency: ReadConsistency) -> Response {
+        // Las consultas no usan la clave
+        let key = IdempotencyKey {
+            coffee_maker: 0,
+            sequence: 0,
+        };
+        send_keyed(address, key, Message::QueryBalance(client_id, consistency))
+    }
+
     fn disconnect_server(address: &str) {
         let disconnect = "d ".to_string() + address;
         let request_disconnect = Request::parse(disconnect.as_str());
@@ -1038,4 +1379,342 @@ mod tests {
         assert_eq!(sync_final_points_server_2, expected_final_points);
         assert_eq!(sync_final_points_server_3, expected_final_points);
     }
+
+    #[test]
+    #[serial]
+    fn server_should_accept_legacy_fixed_size_messages() {
+        let expected_result = json!({
+            "points": {
+                "2": {
+                    "points": [50, 0],
+                    "transaction": null,
+                }
+            }
+        })
+        .to_string();
+
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut stream = connect_to_server("9000");
+        let order = Order::new(2, OrderAction::FillPoints(50));
+        let msg: MessageBytes = Message::CommitOrder(order).try_into().unwrap();
+        stream.write_all(&[CLIENT_CONNECTION]).unwrap();
+        stream.write_all(&msg).unwrap();
+
+        let mut response = [0; 1];
+        stream.read_exact(&mut response).unwrap();
+
+        let synced_points = send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
+            .expect("Failed to sync");
+        server_1.kill().expect("Failed to kill server 1");
+
+        assert_eq!(response, [1]);
+        assert_eq!(synced_points, expected_result);
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_answer_balance_queries() {
+        let expected_balance = Response::Balance(Balance {
+            available: 50,
+            locked: 0,
+            expiring: None,
+        });
+
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut coffee_maker = create_coffee_maker("9000", "assets/orders-3-test-2.csv", None);
+        // Esperamos que la cafetera termine de procesar
+        coffee_maker.wait().unwrap();
+
+        let local_balance = query_balance("9001", 2, ReadConsistency::Local);
+        let quorum_balance = query_balance("9001", 2, ReadConsistency::Quorum);
+        let unknown_balance = query_balance("9000", 3, ReadConsistency::Quorum);
+
+        server_1.kill().expect("Failed to kill server 1");
+        server_2.kill().expect("Failed to kill server 2");
+
+        assert_eq!(local_balance, expected_balance);
+        assert_eq!(quorum_balance, expected_balance);
+        assert_eq!(unknown_balance, Response::Balance(Balance::default()));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_answer_invalid_messages_and_keep_the_connection() {
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        // Mensaje legacy con un tipo de accion invalido
+        let mut legacy = connect_to_server("9000");
+        legacy.write_all(&[CLIENT_CONNECTION]).unwrap();
+        legacy.write_all(&[1, 0, 1, 9, 0, 0, 1]).unwrap();
+        let mut legacy_response = [0; 1];
+        legacy.read_exact(&mut legacy_response).unwrap();
+
+        // Mensaje con un tipo de mensaje invalido
+        let mut stream = connect_client("9000", FRAMED_PROTOCOL_VERSION);
+        write_frame(&mut stream, &[42, 0, 0]).unwrap();
+        let invalid_response = read_frame(&mut stream).unwrap();
+
+        // La conexion sigue abierta
+        let msg: Vec<u8> = Message::QueryBalance(1, ReadConsistency::Local).into();
+        write_frame(&mut stream, &msg).unwrap();
+        let balance_response = read_frame(&mut stream).unwrap();
+
+        server_1.kill().expect("Failed to kill server 1");
+
+        assert_eq!(legacy_response, [0]);
+        assert_eq!(
+            Response::try_from(invalid_response.as_slice()),
+            Ok(Response::InvalidMessage)
+        );
+        assert_eq!(
+            Response::try_from(balance_response.as_slice()),
+            Ok(Response::Balance(Balance::default()))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_answer_pipelined_requests_by_id() {
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut stream = connect_client("9000", PIPELINED_PROTOCOL_VERSION);
+        let fill: Vec<u8> = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10))).into();
+        write_tagged_frame(&mut stream, 1, &fill).unwrap();
+        let (_, fill_response) = read_tagged_frame(&mut stream).unwrap();
+
+        // Mandamos varios pedidos sin esperar las respuestas
+        let lock: Vec<u8> = Message::LockOrder(Order::new(1, OrderAction::UsePoints(4))).into();
+        let invalid = vec![42, 0, 0];
+        let query: Vec<u8> = Message::QueryBalance(2, ReadConsistency::Local).into();
+        write_tagged_frame(&mut stream, 10, &lock).unwrap();
+        write_tagged_frame(&mut stream, 11, &invalid).unwrap();
+        write_tagged_frame(&mut stream, 12, &query).unwrap();
+
+        let mut responses = std::collections::HashMap::new();
+        for _ in 0..3 {
+            let (id, response) = read_tagged_frame(&mut stream).unwrap();
+            responses.insert(id, Response::try_from(response.as_slice()).unwrap());
+        }
+        let balance = query_balance("9000", 1, ReadConsistency::Local);
+
+        server_1.kill().expect("Failed to kill server 1");
+
+        assert_eq!(
+            Response::try_from(fill_response.as_slice()),
+            Ok(Response::Ok)
+        );
+        assert_eq!(responses[&10], Response::Ok);
+        assert_eq!(responses[&11], Response::InvalidMessage);
+        assert_eq!(responses[&12], Response::Balance(Balance::default()));
+        assert_eq!(
+            balance,
+            Response::Balance(Balance {
+                available: 6,
+                locked: 4,
+                expiring: None,
+            })
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_apply_retried_requests_once() {
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let key = IdempotencyKey {
+            coffee_maker: 1,
+            sequence: 1,
+        };
+        let fill = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        let first = send_keyed("9000", key, fill.clone());
+        // El reintento llega a otro servidor, que tambien conoce el resultado
+        let retry_same_server = send_keyed("9000", key, fill.clone());
+        let retry_other_server = send_keyed("9001", key, fill);
+
+        let balance_1 = query_balance("9000", 1, ReadConsistency::Local);
+        let balance_2 = query_balance("9001", 1, ReadConsistency::Local);
+
+        server_1.kill().expect("Failed to kill server 1");
+        server_2.kill().expect("Failed to kill server 2");
+
+        let expected = Response::Balance(Balance {
+            available: 10,
+            locked: 0,
+            expiring: None,
+        });
+        assert_eq!(first, Response::Ok);
+        assert_eq!(retry_same_server, Response::Ok);
+        assert_eq!(retry_other_server, Response::Ok);
+        assert_eq!(balance_1, expected);
+        assert_eq!(balance_2, expected);
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_transfer_points_atomically() {
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let key = |sequence| IdempotencyKey {
+            coffee_maker: 1,
+            sequence,
+        };
+        let fill = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(50)));
+        let transfer =
+            |points| Message::CommitOrder(Order::new(1, OrderAction::Transfer { to: 2, points }));
+        let fill_response = send_keyed("9000", key(1), fill);
+        let transfer_response = send_keyed("9001", key(2), transfer(20));
+        let too_much_response = send_keyed("9000", key(3), transfer(31));
+
+        let balances: Vec<Response> = ["9000", "9001"]
+            .iter()
+            .flat_map(|server| {
+                [1, 2].map(|client_id| query_balance(server, client_id, ReadConsistency::Local))
+            })
+            .collect();
+
+        server_1.kill().expect("Failed to kill server 1");
+        server_2.kill().expect("Failed to kill server 2");
+
+        let balance = |available| {
+            Response::Balance(Balance {
+                available,
+                locked: 0,
+                expiring: None,
+            })
+        };
+        assert_eq!(fill_response, Response::Ok);
+        assert_eq!(transfer_response, Response::Ok);
+        assert_eq!(too_much_response, Response::NotEnoughPoints);
+        assert_eq!(
+            balances,
+            vec![balance(30), balance(20), balance(30), balance(20)]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn family_cards_should_share_the_account_points() {
+        let mut server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let key = |sequence| IdempotencyKey {
+            coffee_maker: 1,
+            sequence,
+        };
+        let link_response = send_keyed("9000", key(1), Message::LinkCard(2, 1));
+        // La cuenta no puede ser miembro de otra familia
+        let nested_response = send_keyed("9000", key(2), Message::LinkCard(1, 3));
+        let fill = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(50)));
+        let fill_response = send_keyed("9000", key(3), fill);
+        // La tarjeta 2 usa los puntos de la cuenta 1, en el otro servidor
+        let lock = Message::LockOrder(Order::new(2, OrderAction::UsePoints(20)));
+        let lock_response = send_keyed("9001", key(4), lock);
+
+        // Esperamos que el otro servidor reciba el commit
+        thread::sleep(Duration::from_millis(500));
+        let card_balance = query_balance("9001", 2, ReadConsistency::Local);
+        let account_balance = query_balance("9000", 1, ReadConsistency::Local);
+
+        let unlink_response = send_keyed("9001", key(5), Message::UnlinkCard(2));
+        thread::sleep(Duration::from_millis(500));
+        let unlinked_balance = query_balance("9000", 2, ReadConsistency::Local);
+
+        server_1.kill().expect("Failed to kill server 1");
+        server_2.kill().expect("Failed to kill server 2");
+
+        let shared = Response::Balance(Balance {
+            available: 30,
+            locked: 20,
+            expiring: None,
+        });
+        assert_eq!(link_response, Response::Ok);
+        assert_eq!(nested_response, Response::InvalidMessage);
+        assert_eq!(fill_response, Response::Ok);
+        assert_eq!(lock_response, Response::Ok);
+        assert_eq!(card_balance, shared);
+        assert_eq!(account_balance, shared);
+        assert_eq!(unlink_response, Response::Ok);
+        assert_eq!(unlinked_balance, Response::Balance(Balance::default()));
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_expire_the_same_available_points() {
+        let mut server_1 = create_expiring_server("9000", None, "2");
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut server_2 = create_expiring_server("9001", Some("9000"), "2");
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let key = |sequence| IdempotencyKey {
+            coffee_maker: 1,
+            sequence,
+        };
+        let fill = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        let fill_response = send_keyed("9000", key(1), fill);
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(4)));
+        let lock_response = send_keyed("9001", key(2), lock);
+        let balance_before = query_balance("9001", 1, ReadConsistency::Local);
+
+        // Esperamos que venzan los puntos y que algun servidor los barra
+        thread::sleep(Duration::from_millis(5000));
+
+        let balance_1 = query_balance("9000", 1, ReadConsistency::Local);
+        let balance_2 = query_balance("9001", 1, ReadConsistency::Local);
+
+        server_1.kill().expect("Failed to kill server 1");
+        server_2.kill().expect("Failed to kill server 2");
+
+        assert_eq!(fill_response, Response::Ok);
+        assert_eq!(lock_response, Response::Ok);
+        match balance_before {
+            Response::Balance(balance) => {
+                assert_eq!((balance.available, balance.locked), (6, 4));
+                assert_eq!(balance.expiring.map(|e| e.points), Some(10));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        // Los puntos bloqueados no vencen hasta que termine el pedido
+        match &balance_1 {
+            Response::Balance(balance) => {
+                assert_eq!((balance.available, balance.locked), (0, 4));
+                assert_eq!(balance.expiring.map(|e| e.points), Some(4));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(balance_1, balance_2);
+    }
 }
@@ -0,0 +1,149 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use points::{IdempotencyKey, Response};
+use tracing::debug;
+
+/// Amount of outcomes remembered, older ones are forgotten first.
+const RECENT_OUTCOMES: usize = 4096;
+
+/// What is known about a request when it is received.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The request was never seen, it should be handled.
+    New,
+    /// The request is being handled by another connection.
+    InProgress,
+    /// The request was already handled with the given response.
+    Known(Response),
+}
+
+/// Outcomes of the recent client requests, by idempotency key.
+#[derive(Debug)]
+pub struct RecentOutcomes {
+    outcomes: Mutex<HashMap<IdempotencyKey, Option<Response>>>,
+    order: Mutex<VecDeque<IdempotencyKey>>,
+}
+
+impl RecentOutcomes {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            outcomes: Mutex::new(HashMap::new()),
+            order: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Marks the request as in progress, unless it was already seen.
+    pub fn begin(&self, key: IdempotencyKey) -> Outcome {
+        let mut outcomes = self.outcomes.lock().expect("Could not lock outcomes");
+        match outcomes.get(&key) {
+            Some(Some(response)) => Outcome::Known(response.clone()),
+            Some(None) => Outcome::InProgress,
+            None => {
+                outcomes.insert(key, None);
+                self.track(&mut outcomes, key);
+                Outcome::New
+            }
+        }
+    }
+
+    /// Stores the response to a request started with `begin`.
+    /// Transient failures did not change any points, so they are forgotten to let the request be retried.
+    pub fn finish(&self, key: IdempotencyKey, response: &Response) {
+        let mut outcomes = self.outcomes.lock().expect("Could not lock outcomes");
+        if response.is_transient() || *response == Response::InternalError {
+            outcomes.remove(&key);
+            self.untrack(key);
+        } else {
+            outcomes.insert(key, Some(response.clone()));
+        }
+    }
+
+    /// Stores the response to a request coordinated by another server.
+    pub fn remember(&self, key: IdempotencyKey, response: Response) {
+        let mut outcomes = self.outcomes.lock().expect("Could not lock outcomes");
+        if !outcomes.contains_key(&key) {
+            self.track(&mut outcomes, key);
+        }
+        outcomes.insert(key, Some(response));
+    }
+
+    /// Keeps the order in which requests were seen, forgetting the oldest ones.
+    fn track(&self, outcomes: &mut HashMap<IdempotencyKey, Option<Response>>, key: IdempotencyKey) {
+        let mut order = self.order.lock().expect("Could not lock outcome order");
+        order.push_back(key);
+        while order.len() > RECENT_OUTCOMES {
+            if let Some(oldest) = order.pop_front() {
+                debug!("Forgetting outcome of {:?}", oldest);
+                outcomes.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drops a forgotten request from the order, so its retry is not forgotten early.
+    fn untrack(&self, key: IdempotencyKey) {
+        let mut order = self.order.lock().expect("Could not lock outcome order");
+        if let Some(position) = order.iter().rposition(|tracked| *tracked == key) {
+            order.remove(position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(sequence: u64) -> IdempotencyKey {
+        IdempotencyKey {
+            coffee_maker: 1,
+            sequence,
+        }
+    }
+
+    #[test]
+    fn test_duplicate_gets_original_response() {
+        let outcomes = RecentOutcomes::new();
+        assert_eq!(outcomes.begin(key(1)), Outcome::New);
+        assert_eq!(outcomes.begin(key(1)), Outcome::InProgress);
+
+        outcomes.finish(key(1), &Response::NotEnoughPoints);
+        assert_eq!(
+            outcomes.begin(key(1)),
+            Outcome::Known(Response::NotEnoughPoints)
+        );
+    }
+
+    #[test]
+    fn test_transient_failures_are_forgotten() {
+        let outcomes = RecentOutcomes::new();
+        outcomes.begin(key(1));
+        outcomes.finish(key(1), &Response::Offline);
+        assert_eq!(outcomes.begin(key(1)), Outcome::New);
+    }
+
+    #[test]
+    fn test_retried_transient_failures_are_not_forgotten_early() {
+        let outcomes = RecentOutcomes::new();
+        outcomes.begin(key(0));
+        outcomes.finish(key(0), &Response::Conflict);
+        outcomes.begin(key(0));
+        outcomes.finish(key(0), &Response::Ok);
+        for sequence in 1..RECENT_OUTCOMES as u64 {
+            outcomes.remember(key(sequence), Response::Ok);
+        }
+        assert_eq!(outcomes.begin(key(0)), Outcome::Known(Response::Ok));
+    }
+
+    #[test]
+    fn test_oldest_outcomes_are_forgotten() {
+        let outcomes = RecentOutcomes::new();
+        outcomes.remember(key(0), Response::Ok);
+        for sequence in 1..=RECENT_OUTCOMES as u64 {
+            outcomes.remember(key(sequence), Response::Ok);
+        }
+        assert_eq!(outcomes.begin(key(1)), Outcome::Known(Response::Ok));
+        assert_eq!(outcomes.begin(key(0)), Outcome::New);
+    }
+}
@@ -2,6 +2,7 @@ use super::{
     pending_transactions::PendingTransactions,
     transaction::{Transaction, TransactionAction, TransactionState, TxOk, COMMIT_TIMEOUT},
 };
+use points::{ClientId, Response};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -9,32 +10,75 @@ use std::{
     fmt,
     io::Read,
     net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
 };
 use tracing::{debug, info, warn};
 
-/// Points tuple: available points, locked points
+/// Points tuple: available points, locked points, earned batches
+/// Batches hold the points that were added and not consumed yet, oldest first.
+/// Points without a batch do not expire, and are consumed first.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Points(pub usize, pub usize);
+#[serde(from = "PointsRepr", into = "PointsRepr")]
+pub struct Points(pub usize, pub usize, pub Vec<EarnedPoints>);
+
+/// Serialized points, without batches if none of them expire.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PointsRepr {
+    Counters(usize, usize),
+    Batches(usize, usize, Vec<EarnedPoints>),
+}
+
+impl From<PointsRepr> for Points {
+    fn from(repr: PointsRepr) -> Self {
+        match repr {
+            PointsRepr::Counters(available, locked) => Points::new(available, locked),
+            PointsRepr::Batches(available, locked, batches) => Points(available, locked, batches),
+        }
+    }
+}
+
+impl From<Points> for PointsRepr {
+    fn from(points: Points) -> Self {
+        if points.2.is_empty() {
+            PointsRepr::Counters(points.0, points.1)
+        } else {
+            PointsRepr::Batches(points.0, points.1, points.2)
+        }
+    }
+}
+
+/// Points added by the same transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EarnedPoints {
+    pub points: usize,
+    /// Timestamp of the transaction that added them.
+    pub earned_at: u128,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PointRecord {
     pub points: Arc<Mutex<Points>>,
     pub transaction: Option<Transaction>,
+    /// Family account the card is linked to, its orders use the points of the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<ClientId>,
 }
 
 impl PointRecord {
     pub fn new() -> Self {
         PointRecord {
-            points: Arc::new(Mutex::new(Points(0, 0))),
+            points: Arc::new(Mutex::new(Points::new(0, 0))),
             transaction: None,
+            account: None,
         }
     }
 
-    pub fn wait_die(&self, transaction: &Transaction) -> Result<(), String> {
+    pub fn wait_die(&self, transaction: &Transaction) -> Result<(), Response> {
         if let Some(etx) = self.transaction.clone() {
             if transaction.older_than(&etx) {
-                return Err("Transaction is older than the current one".to_string());
+                debug!("Transaction is older than the current one");
+                return Err(Response::Conflict);
             }
         }
         Ok(())
@@ -42,6 +86,194 @@ impl PointRecord {
 }
 
 impl Points {
+    pub fn new(available: usize, locked: usize) -> Self {
+        Points(available, locked, vec![])
+    }
+
+    pub fn can_perform(&self, transaction: &Transaction) -> Result<(), Response> {
+        match transaction.action {
+            TransactionAction::Add | TransactionAction::Link { .. } => Ok(()),
+            TransactionAction::Expire { before } => {
+                if self.expirable(before) < transaction.points {
+                    Err(Response::NotEnoughPoints)
+                } else {
+                    Ok(())
+                }
+            }
+            TransactionAction::Lock | TransactionAction::Transfer { .. } => {
+                if self.0 < transaction.points {
+                    Err(Response::NotEnoughPoints)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => {
+                // Free or Consume
+                if self.1 < transaction.points {
+                    Err(Response::NotEnoughLockedPoints)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Applies a transaction to the points
+    /// If the transaction is a lock, the points are locked (increasing the locked points and decreasing the available points)
+    /// If the transaction is free, the points are unlocked (decreasing the locked points and increasing the available points)
+    /// If the transaction is an add, the points are added (increasing the available points), as a batch if they expire
+    /// If the transaction is a consume, the points are subtracted (decreasing the locked points)
+    /// If the transaction is a transfer, the available points are subtracted, the recipient gets them
+    /// If the transaction is a link, the points do not change, the record is updated by the storage
+    /// If the transaction is an expiration, the available points earned before its cutoff are subtracted
+    ///
+    /// # Returns
+    ///
+    /// The earned batches of the points removed from the client, oldest first.
+    pub fn apply(&mut self, transaction: Transaction) -> Vec<EarnedPoints> {
+        let mut removed = vec![];
+        match transaction.action {
+            TransactionAction::Add => {
+                self.0 += transaction.points;
+                if transaction.expires {
+                    self.earn(vec![EarnedPoints {
+                        points: transaction.points,
+                        earned_at: transaction.timestamp,
+                    }]);
+                }
+            }
+            TransactionAction::Lock => {
+                self.0 -= transaction.points;
+                self.1 += transaction.points;
+            }
+            TransactionAction::Free => {
+                self.0 += transaction.points;
+                self.1 -= transaction.points;
+            }
+            TransactionAction::Consume => {
+                removed = self.take_oldest(transaction.points);
+                self.1 -= transaction.points;
+            }
+            TransactionAction::Transfer { .. } => {
+                removed = self.take_oldest(transaction.points);
+                self.0 -= transaction.points;
+            }
+            TransactionAction::Link { .. } => {}
+            TransactionAction::Expire { before } => {
+                removed = take_batches(&mut self.2, transaction.points, before);
+                self.0 -= transaction.points;
+            }
+        }
+        info!("Applied {:?}.", transaction);
+        removed
+    }
+
+    /// Available points earned before the given timestamp, which can expire.
+    /// Locked points do not expire until their order is finished.
+    pub fn expirable(&self, before: u128) -> usize {
+        let earned: usize = self
+            .2
+            .iter()
+            .filter(|batch| batch.earned_at < before)
+            .map(|batch| batch.points)
+            .sum();
+        earned.min(self.0)
+    }
+
+    /// Oldest batch of points, the next ones to expire.
+    pub fn oldest(&self) -> Option<EarnedPoints> {
+        self.2.first().copied()
+    }
+
+    /// Adds earned batches, keeping them sorted by earn date.
+    fn earn(&mut self, batches: Vec<EarnedPoints>) {
+        self.2.extend(batches);
+        self.2.sort_by_key(|batch| batch.earned_at);
+    }
+
+    /// Removes the batches of the oldest points, before they are subtracted.
+    /// Points without a batch are older than any batch, so they are taken first.
+    fn take_oldest(&mut self, points: usize) -> Vec<EarnedPoints> {
+        let tracked: usize = self.2.iter().map(|batch| batch.points).sum();
+        let untracked = (self.0 + self.1).saturating_sub(tracked);
+        take_batches(&mut self.2, points.saturating_sub(untracked), u128::MAX)
+    }
+}
+
+/// Removes up to the given points from the batches earned before the given timestamp, oldest first.
+fn take_batches(
+    batches: &mut Vec<EarnedPoints>,
+    mut points: usize,
+    before: u128,
+) -> Vec<EarnedPoints> {
+    let mut taken = vec![];
+    for batch in batches.iter_mut() {
+        if points == 0 || batch.earned_at >= before {
+            break;
+        }
+        let amount = batch.points.min(points);
+        batch.points -= amount;
+        points -= amount;
+        taken.push(EarnedPoints {
+            points: amount,
+            earned_at: batch.earned_at,
+        });
+    }
+    batches.retain(|batch| batch.points > 0);
+    taken
+}
+
+/// Points changed by a transaction.
+/// Transfers change the points of two clients, any other transaction only the ones of its client.
+pub struct TransactionPoints<'a> {
+    client: MutexGuard<'a, Points>,
+    recipient: Option<MutexGuard<'a, Points>>,
+}
+
+impl<'a> TransactionPoints<'a> {
+    /// Locks the points of every client of the transaction.
+    /// `points` holds the points of each client returned by `Transaction::client_ids`, in the same order,
+    /// so they are always locked in ascending client id order.
+    pub fn lock(
+        transaction: &Transaction,
+        points: &'a [Arc<Mutex<Points>>],
+    ) -> Result<Self, Response> {
+        let mut guards = points
+            .iter()
+            .map(|points| points.lock().map_err(|_| Response::InternalError))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let client = transaction
+            .client_ids()
+            .iter()
+            .position(|id| *id == transaction.client_id)
+            .ok_or(Response::InternalError)?;
+        if client >= guards.len() {
+            return Err(Response::InternalError);
+        }
+        let client = guards.remove(client);
+
+        Ok(TransactionPoints {
+            client,
+            recipient: guards.pop(),
+        })
+    }
+
+    pub fn can_perform(&self, transaction: &Transaction) -> Result<(), Response> {
+        self.client.can_perform(transaction)
+    }
+
+    /// Applies a transaction to the points of its client, and of the recipient for transfers.
+    /// Transferred points keep their earn date.
+    pub fn apply(&mut self, transaction: Transaction) {
+        let points = transaction.points;
+        let removed = self.client.apply(transaction);
+        if let Some(recipient) = self.recipient.as_mut() {
+            recipient.0 += points;
+            recipient.earn(removed);
+        }
+    }
+
     /// Prepares the transaction
     /// Returns (abort, streams)
     fn prepare(
@@ -99,13 +331,18 @@ impl Points {
             return Ok((TransactionState::Disconnected, streams));
         }
 
-        let abort = abort > 0 || proceed < servers.len() / 2;
-        let state = if abort {
+        let state = if abort > 0 {
             debug!(
                 "Coordinator decided to ABORT transaction with timestamp {}.",
                 transaction.timestamp
             );
             TransactionState::Abort
+        } else if proceed < servers.len() / 2 {
+            debug!(
+                "Coordinator decided to ABORT transaction with timestamp {} (no quorum).",
+                transaction.timestamp
+            );
+            TransactionState::NoQuorum
         } else {
             debug!(
                 "Coordinator decided to COMMIT transaction with timestamp {}.",
@@ -121,14 +358,14 @@ impl Points {
     /// 1. The coordinator sends a prepare message to all other servers
     /// 2. Each server responds with a proceed message if it can commit the transaction
     /// 3. If all servers (or if less than half of them timeout) respond with proceed, the coordinator sends a commit message to all server
-    /// 3.1 If any server responds with an abort, the coordinator sends an abort message to all servers
+    ///    3.1 If any server responds with an abort, the coordinator sends an abort message to all servers
     pub fn coordinate(
         &mut self,
         transaction: Transaction,
         servers: HashSet<String>,
         online: bool,
         pending: Arc<PendingTransactions>,
-    ) -> Result<TxOk, String> {
+    ) -> Result<TxOk, Response> {
         self.can_perform(&transaction)?;
 
         // Commit the transaction directly if this is the only server
@@ -138,7 +375,9 @@ impl Points {
         }
 
         // PREPARE TRANSACTION
-        let (state, streams) = self.prepare(transaction.clone(), servers, online)?;
+        let (state, streams) = self
+            .prepare(transaction.clone(), servers, online)
+            .map_err(|_| Response::InternalError)?;
 
         // FINALIZE TRANSACTION
         for stream in streams {
@@ -160,45 +399,40 @@ impl Points {
             }
             TransactionState::Abort => {
                 pending.connect();
-                match transaction.action {
-                    TransactionAction::Lock => Err("Transaction Aborted".to_string()),
-                    _ => {
-                        pending.add(transaction)?;
-                        Ok(TxOk::Pending)
-                    }
-                }
+                Self::abort_or_pend(transaction, pending, Response::Conflict)
+            }
+            TransactionState::NoQuorum => {
+                pending.connect();
+                Self::abort_or_pend(transaction, pending, Response::QuorumNotReached)
             }
             TransactionState::Disconnected => {
                 pending.disconnect();
-                match transaction.action {
-                    TransactionAction::Lock => Err("Transaction Aborted".to_string()),
-                    _ => {
-                        pending.add(transaction)?;
-                        Ok(TxOk::Pending)
-                    }
-                }
+                Self::abort_or_pend(transaction, pending, Response::Offline)
             }
-            _ => Err("Invalid TransactionState".to_string()),
+            _ => Err(Response::InternalError),
         }
     }
 
-    pub fn can_perform(&self, transaction: &Transaction) -> Result<(), String> {
+    /// Fails a transaction that could not be committed with the given reason.
+    /// Only locks and transfers can fail, since they need points that may not be available later,
+    /// links, since every server must agree on the members of a family before they are used,
+    /// and expirations, which are retried by the next sweep.
+    /// Any other transaction is left pending to be retried later.
+    fn abort_or_pend(
+        transaction: Transaction,
+        pending: Arc<PendingTransactions>,
+        reason: Response,
+    ) -> Result<TxOk, Response> {
         match transaction.action {
-            TransactionAction::Add => Ok(()),
-            TransactionAction::Lock => {
-                if self.0 < transaction.points {
-                    Err("Not enough points available".to_string())
-                } else {
-                    Ok(())
-                }
-            }
+            TransactionAction::Lock
+            | TransactionAction::Transfer { .. }
+            | TransactionAction::Link { .. }
+            | TransactionAction::Expire { .. } => Err(reason),
             _ => {
-                // Free or Consume
-                if self.1 < transaction.points {
-                    Err("Not enough points locked".to_string())
-                } else {
-                    Ok(())
-                }
+                pending
+                    .add(transaction)
+                    .map_err(|_| Response::InternalError)?;
+                Ok(TxOk::Pending)
             }
         }
     }
@@ -237,31 +471,6 @@ impl Points {
             Err("Aborted Transaction".to_string())
         }
     }
-
-    /// Applies a transaction to the points
-    /// If the transaction is a lock, the points are locked (increasing the locked points and decreasing the available points)
-    /// If the transaction is free, the points are unlocked (decreasing the locked points and increasing the available points)
-    /// If the transaction is an add, the points are added (increasing the available points)
-    /// If the transaction is a consume, the points are subtracted (decreasing the locked points)
-    pub fn apply(&mut self, transaction: Transaction) {
-        match transaction.action {
-            TransactionAction::Add => {
-                self.0 += transaction.points;
-            }
-            TransactionAction::Lock => {
-                self.0 -= transaction.points;
-                self.1 += transaction.points;
-            }
-            TransactionAction::Free => {
-                self.0 += transaction.points;
-                self.1 -= transaction.points;
-            }
-            TransactionAction::Consume => {
-                self.1 -= transaction.points;
-            }
-        }
-        info!("Applied {:?}.", transaction);
-    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -289,7 +498,7 @@ mod tests {
     use super::*;
     #[test]
     fn test_add_points() {
-        let mut points = Points(0, 0);
+        let mut points = Points::new(0, 0);
         let order = Order::new(1, OrderAction::FillPoints(100));
         let message = Message::CommitOrder(order);
         let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
@@ -300,7 +509,7 @@ mod tests {
 
     #[test]
     fn test_lock_points() {
-        let mut points = Points(100, 0);
+        let mut points = Points::new(100, 0);
         let order = Order::new(1, OrderAction::UsePoints(100));
         let message = Message::LockOrder(order);
         let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
@@ -311,7 +520,7 @@ mod tests {
 
     #[test]
     fn test_free_points() {
-        let mut points = Points(0, 100);
+        let mut points = Points::new(0, 100);
         let order = Order::new(1, OrderAction::UsePoints(100));
         let message = Message::FreeOrder(order);
         let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
@@ -322,7 +531,7 @@ mod tests {
 
     #[test]
     fn test_consume_points() {
-        let mut points = Points(0, 100);
+        let mut points = Points::new(0, 100);
         let order = Order::new(1, OrderAction::UsePoints(100));
         let message = Message::CommitOrder(order);
         let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
@@ -330,4 +539,194 @@ mod tests {
         assert_eq!(0, points.0);
         assert_eq!(0, points.1);
     }
+
+    #[test]
+    fn test_cannot_lock_more_than_available() {
+        let points = Points::new(10, 0);
+        let order = Order::new(1, OrderAction::UsePoints(11));
+        let message = Message::LockOrder(order);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        assert_eq!(
+            points.can_perform(&transaction),
+            Err(Response::NotEnoughPoints)
+        );
+    }
+
+    #[test]
+    fn test_cannot_consume_more_than_locked() {
+        let points = Points::new(100, 5);
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let message = Message::CommitOrder(order);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        assert_eq!(
+            points.can_perform(&transaction),
+            Err(Response::NotEnoughLockedPoints)
+        );
+    }
+
+    #[test]
+    fn test_wait_die_conflict() {
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let message = Message::LockOrder(order);
+        let older = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        let newer = Transaction::new("127.0.0.1:9002".to_string(), &message).unwrap();
+
+        let mut record = PointRecord::new();
+        record.transaction = Some(newer);
+        assert_eq!(record.wait_die(&older), Err(Response::Conflict));
+    }
+
+    #[test]
+    fn test_transfer_points() {
+        let order = Order::new(2, OrderAction::Transfer { to: 1, points: 30 });
+        let message = Message::CommitOrder(order);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+
+        // Los puntos se pasan en orden de client id: primero el 1 (destino) y despues el 2
+        let points = vec![
+            Arc::new(Mutex::new(Points::new(5, 0))),
+            Arc::new(Mutex::new(Points::new(100, 10))),
+        ];
+        let mut locked = TransactionPoints::lock(&transaction, &points).unwrap();
+        assert_eq!(locked.can_perform(&transaction), Ok(()));
+        locked.apply(transaction);
+        drop(locked);
+
+        let recipient = points[0].lock().unwrap();
+        let client = points[1].lock().unwrap();
+        assert_eq!((recipient.0, recipient.1), (35, 0));
+        assert_eq!((client.0, client.1), (70, 10));
+    }
+
+    #[test]
+    fn test_cannot_transfer_more_than_available() {
+        let order = Order::new(1, OrderAction::Transfer { to: 2, points: 30 });
+        let message = Message::CommitOrder(order);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+
+        let points = vec![
+            Arc::new(Mutex::new(Points::new(20, 50))),
+            Arc::new(Mutex::new(Points::new(0, 0))),
+        ];
+        let locked = TransactionPoints::lock(&transaction, &points).unwrap();
+        assert_eq!(
+            locked.can_perform(&transaction),
+            Err(Response::NotEnoughPoints)
+        );
+    }
+
+    #[test]
+    fn test_added_points_are_earned_at_the_transaction_timestamp() {
+        let mut points = Points::new(10, 0);
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(30)));
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message)
+            .unwrap()
+            .with_expiration(true);
+        let timestamp = transaction.timestamp;
+        points.apply(transaction);
+
+        assert_eq!(
+            points.oldest(),
+            Some(EarnedPoints {
+                points: 30,
+                earned_at: timestamp
+            })
+        );
+        // Los puntos sin fecha nunca vencen
+        assert_eq!(points.expirable(timestamp), 0);
+        assert_eq!(points.expirable(timestamp + 1), 30);
+    }
+
+    #[test]
+    fn test_points_without_expiration_are_not_earned() {
+        let mut points = Points::new(0, 0);
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(30)));
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        points.apply(transaction);
+
+        assert_eq!(points.oldest(), None);
+        assert_eq!(serde_json::to_string(&points).unwrap(), "[30,0]");
+    }
+
+    #[test]
+    fn test_expire_points() {
+        let batches = vec![
+            EarnedPoints {
+                points: 20,
+                earned_at: 100,
+            },
+            EarnedPoints {
+                points: 30,
+                earned_at: 200,
+            },
+        ];
+        let mut points = Points(40, 10, batches);
+        // Los puntos bloqueados no vencen
+        assert_eq!(points.expirable(150), 20);
+        assert_eq!(points.expirable(250), 40);
+
+        let transaction = Transaction::expiration("127.0.0.1:9001".to_string(), 1, 250, 40);
+        assert_eq!(points.can_perform(&transaction), Ok(()));
+        points.apply(transaction);
+        assert_eq!((points.0, points.1), (0, 10));
+        assert_eq!(
+            points.oldest(),
+            Some(EarnedPoints {
+                points: 10,
+                earned_at: 200
+            })
+        );
+
+        let transaction = Transaction::expiration("127.0.0.1:9001".to_string(), 1, 250, 1);
+        assert_eq!(
+            points.can_perform(&transaction),
+            Err(Response::NotEnoughPoints)
+        );
+    }
+
+    #[test]
+    fn test_consume_oldest_points_first() {
+        let batches = vec![EarnedPoints {
+            points: 20,
+            earned_at: 100,
+        }];
+        let mut points = Points(15, 10, batches);
+
+        // Primero se consumen los 5 puntos sin fecha
+        let message = Message::CommitOrder(Order::new(1, OrderAction::UsePoints(10)));
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        let removed = points.apply(transaction);
+        assert_eq!(
+            removed,
+            vec![EarnedPoints {
+                points: 5,
+                earned_at: 100
+            }]
+        );
+        assert_eq!(points.expirable(u128::MAX), 15);
+    }
+
+    #[test]
+    fn test_transferred_points_keep_their_earn_date() {
+        let order = Order::new(2, OrderAction::Transfer { to: 1, points: 30 });
+        let message = Message::CommitOrder(order);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+
+        let batches = vec![EarnedPoints {
+            points: 50,
+            earned_at: 100,
+        }];
+        let points = vec![
+            Arc::new(Mutex::new(Points::new(0, 0))),
+            Arc::new(Mutex::new(Points(50, 0, batches))),
+        ];
+        let mut locked = TransactionPoints::lock(&transaction, &points).unwrap();
+        locked.apply(transaction);
+        drop(locked);
+
+        let recipient = points[0].lock().unwrap();
+        let client = points[1].lock().unwrap();
+        assert_eq!(recipient.expirable(101), 30);
+        assert_eq!(client.expirable(101), 20);
+    }
 }
@@ -9,17 +9,19 @@ use std::{
 
 use super::{
     message::{
-        connect_to, spread_connect_to, sync_with, ConnectRequest, ConnectResponse, SyncRequest,
-        SyncResponse, TIMEOUT,
+        balance_from, connect_to, spread_connect_to, sync_with, ConnectRequest, ConnectResponse,
+        SyncRequest, SyncResponse, TIMEOUT,
     },
+    outcomes::RecentOutcomes,
     pending_transactions::PendingTransactions,
-    point_record::{PointRecord, SafePointRecord},
-    transaction::{Transaction, TransactionState, TxOk},
+    point_record::{PointRecord, Points, SafePointRecord, TransactionPoints},
+    transaction::{generate_timestamp, Transaction, TransactionAction, TransactionState, TxOk},
 };
-use points::Message;
+use points::{Balance, ClientId, Expiration, IdempotencyKey, Message, Response};
+use rayon::prelude::*;
 use tracing::{debug, error, info};
 
-pub type PointMap = HashMap<u16, SafePointRecord>;
+pub type PointMap = HashMap<ClientId, SafePointRecord>;
 
 #[derive(Debug)]
 pub struct PointStorage {
@@ -28,8 +30,15 @@ pub struct PointStorage {
     pub self_address: String,
     pub online: bool,
     pub pending: Arc<PendingTransactions>,
+    pub outcomes: Arc<RecentOutcomes>,
+    /// Time after which added points expire, they never do if there is none.
+    pub expiration: Option<Duration>,
 }
 
+/// Expiry sweeps drop the points earned before a multiple of this interval,
+/// servers take turns to sweep once per interval.
+pub const EXPIRY_SWEEP_INTERVAL: u64 = 1000;
+
 impl PointStorage {
     /// Creates a new point storage.
     /// The point storage is initialized with the given address as self address.
@@ -39,11 +48,16 @@ impl PointStorage {
     ///
     /// * `self_address` - The address of the server.
     /// * `known_server` - An optional address of a known server.
+    /// * `expiration` - An optional time after which added points expire.
     ///
     /// # Returns
     ///
     /// The point storage.
-    pub fn new(self_address: String, known_address: Option<String>) -> Arc<Mutex<Self>> {
+    pub fn new(
+        self_address: String,
+        known_address: Option<String>,
+        expiration: Option<Duration>,
+    ) -> Arc<Mutex<Self>> {
         let mut servers = HashSet::new();
         let mut points = PointMap::new();
 
@@ -60,6 +74,8 @@ impl PointStorage {
             self_address,
             online: true,
             pending: PendingTransactions::new(),
+            outcomes: RecentOutcomes::new(),
+            expiration,
         }));
 
         Self::set_on_connect(res.clone());
@@ -68,7 +84,7 @@ impl PointStorage {
     }
 
     /// Gets the point record for the given id.
-    pub fn get_point_record(&mut self, client_id: u16) -> Arc<Mutex<PointRecord>> {
+    pub fn get_point_record(&mut self, client_id: ClientId) -> Arc<Mutex<PointRecord>> {
         self.points
             .entry(client_id)
             .or_insert_with(SafePointRecord::new)
@@ -182,17 +198,24 @@ impl PointStorage {
         let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
         storage.check_online()?;
 
-        let record = storage.get_point_record(transaction.client_id);
+        let records = storage.get_point_records(&transaction);
+        let outcomes = storage.outcomes.clone();
         drop(storage);
-        let record = record.lock().map_err(|_| "Failed to lock record")?;
-
-        let wait_die = record.wait_die(&transaction);
 
-        let points = record.points.clone();
-        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
-        drop(record);
+        let points = match Self::wait_die(&records, &transaction) {
+            Ok(points) => points,
+            Err(_) => {
+                debug!("Sending ABORT for {:?}.", transaction);
+                coordinator
+                    .write_all(&[TransactionState::Abort as u8])
+                    .map_err(|e| e.to_string())?;
+                return Err("Aborted Transaction".to_string());
+            }
+        };
+        let mut points =
+            TransactionPoints::lock(&transaction, &points).map_err(|_| "Failed to lock points")?;
 
-        let state = if wait_die.is_ok() && points.can_perform(&transaction).is_ok() {
+        let state = if points.can_perform(&transaction).is_ok() {
             debug!("Sending APPROVE for {:?}.", transaction);
             TransactionState::Proceed as u8
         } else {
@@ -201,7 +224,46 @@ impl PointStorage {
         };
         coordinator.write_all(&[state]).map_err(|e| e.to_string())?;
 
-        points.handle_transaction(transaction, coordinator)
+        let key = transaction.key;
+        let action = transaction.action.clone();
+        points.handle_transaction(transaction, coordinator)?;
+        // Records are locked before their points, as everywhere else
+        drop(points);
+        Self::apply_link(&records, &action)?;
+
+        // Retries of the request sent to this server should not apply it again
+        if let Some(key) = key {
+            outcomes.remember(key, Response::Ok);
+        }
+        Ok(())
+    }
+
+    /// Gets the point records of every client of the transaction, in ascending client id order.
+    fn get_point_records(&mut self, transaction: &Transaction) -> Vec<Arc<Mutex<PointRecord>>> {
+        transaction
+            .client_ids()
+            .into_iter()
+            .map(|client_id| self.get_point_record(client_id))
+            .collect()
+    }
+
+    /// Checks wait-die on every record.
+    ///
+    /// # Returns
+    ///
+    /// The points of each record, in the same order.
+    fn wait_die(
+        records: &[Arc<Mutex<PointRecord>>],
+        transaction: &Transaction,
+    ) -> Result<Vec<Arc<Mutex<Points>>>, Response> {
+        records
+            .iter()
+            .map(|record| {
+                let record = record.lock().map_err(|_| Response::InternalError)?;
+                record.wait_die(transaction)?;
+                Ok(record.points.clone())
+            })
+            .collect()
     }
 
     /// Makes the storage go offline.
@@ -229,60 +291,262 @@ impl PointStorage {
         }
     }
 
-    pub fn coordinate_msg(msg: Message, storage: Arc<Mutex<PointStorage>>) -> Result<TxOk, String> {
-        let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
-        let transaction = Transaction::new(storage.self_address.clone(), &msg)?;
+    pub fn coordinate_msg(
+        msg: Message,
+        key: Option<IdempotencyKey>,
+        storage: Arc<Mutex<PointStorage>>,
+    ) -> Result<TxOk, Response> {
+        let transaction = {
+            let storage = storage.lock().map_err(|_| Response::InternalError)?;
+            let transaction = Transaction::new(storage.self_address.clone(), &msg)
+                .map_err(|_| Response::InvalidMessage)?
+                .with_key(key)
+                .with_expiration(storage.expiration.is_some());
+            storage.resolve_accounts(transaction)?
+        };
+
+        Self::coordinate_tx(transaction, storage)
+    }
+
+    pub fn coordinate_tx(
+        transaction: Transaction,
+        storage: Arc<Mutex<PointStorage>>,
+    ) -> Result<TxOk, Response> {
+        let mut storage = storage.lock().map_err(|_| Response::InternalError)?;
 
         let servers = storage.get_other_servers();
         let online = storage.online;
         let pending = storage.pending.clone();
 
-        let record_ref = storage.get_point_record(transaction.client_id);
+        let records = storage.get_point_records(&transaction);
         drop(storage);
-        let record = record_ref.lock().map_err(|_| "Failed to lock record")?;
 
-        record.wait_die(&transaction)?;
-
-        let points = record.points.clone();
-        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
-        drop(record);
+        let points = Self::wait_die(&records, &transaction)?;
+        let mut points = TransactionPoints::lock(&transaction, &points)?;
 
+        let action = transaction.action.clone();
         let result = points.coordinate(transaction, servers, online, pending);
+        // Records are locked before their points, as everywhere else
         drop(points);
+        if let Ok(TxOk::Finalized) = result {
+            Self::apply_link(&records, &action).map_err(|_| Response::InternalError)?;
+        }
 
-        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
-        record.transaction = None;
+        for record in records {
+            let mut record = record.lock().map_err(|_| Response::InternalError)?;
+            record.transaction = None;
+        }
 
         result
     }
 
-    pub fn coordinate_tx(
-        transaction: Transaction,
-        storage: Arc<Mutex<PointStorage>>,
-    ) -> Result<TxOk, String> {
-        let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
+    /// Returns the family account of the given card, or the card itself if it is not linked.
+    fn account_of(&self, client_id: ClientId) -> Result<ClientId, Response> {
+        let record = match self.points.get(&client_id) {
+            Some(record) => record.0.clone(),
+            None => return Ok(client_id),
+        };
+        let record = record.lock().map_err(|_| Response::InternalError)?;
+        Ok(record.account.unwrap_or(client_id))
+    }
 
-        let servers = storage.get_other_servers();
-        let online = storage.online;
-        let pending = storage.pending.clone();
+    /// Makes the transaction change the points of the family accounts of its cards.
+    /// Links are checked instead: accounts can not be linked to another account.
+    fn resolve_accounts(&self, mut transaction: Transaction) -> Result<Transaction, Response> {
+        match transaction.action {
+            TransactionAction::Link {
+                account: Some(account),
+            } => {
+                self.check_link(transaction.client_id, account)?;
+                return Ok(transaction);
+            }
+            TransactionAction::Link { account: None } => return Ok(transaction),
+            TransactionAction::Transfer { to } => {
+                let to = self.account_of(to)?;
+                transaction.action = TransactionAction::Transfer { to };
+            }
+            _ => {}
+        }
+        transaction.client_id = self.account_of(transaction.client_id)?;
 
-        let record_ref = storage.get_point_record(transaction.client_id);
-        drop(storage);
-        let record = record_ref.lock().map_err(|_| "Failed to lock record")?;
+        if let TransactionAction::Transfer { to } = transaction.action {
+            if to == transaction.client_id {
+                debug!("Transfer between cards of the same family");
+                return Err(Response::InvalidMessage);
+            }
+        }
+        Ok(transaction)
+    }
 
-        record.wait_die(&transaction)?;
+    /// Checks that the card can be linked to the account.
+    /// Families only have one level: the account can not be linked, and the card can not have members.
+    fn check_link(&self, card: ClientId, account: ClientId) -> Result<(), Response> {
+        if self.account_of(account)? != account {
+            debug!("Account {} is linked to another account", account);
+            return Err(Response::InvalidMessage);
+        }
 
+        for record in self.points.values() {
+            let record = record.0.lock().map_err(|_| Response::InternalError)?;
+            if record.account == Some(card) {
+                debug!("Card {} is the account of a family", card);
+                return Err(Response::InvalidMessage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the family account of the card once a link transaction is committed.
+    /// The points of the transaction must not be locked anymore.
+    fn apply_link(
+        records: &[Arc<Mutex<PointRecord>>],
+        action: &TransactionAction,
+    ) -> Result<(), String> {
+        if let (TransactionAction::Link { account }, Some(record)) = (action, records.first()) {
+            let mut record = record.lock().map_err(|_| "Failed to lock record")?;
+            record.account = *account;
+            info!("Linked card to account {:?}", account);
+        }
+        Ok(())
+    }
+
+    /// Reads the balance of the given client from this server.
+    /// Cards linked to a family account read the balance of the account.
+    /// Clients without a record have no points.
+    pub fn local_balance(
+        storage: Arc<Mutex<PointStorage>>,
+        client_id: ClientId,
+    ) -> Result<Balance, Response> {
+        let storage = storage.lock().map_err(|_| Response::InternalError)?;
+        let client_id = storage.account_of(client_id)?;
+        let expiration = storage.expiration;
+        let record_ref = match storage.points.get(&client_id) {
+            Some(record) => record.0.clone(),
+            None => return Ok(Balance::default()),
+        };
+        drop(storage);
+
+        let record = record_ref.lock().map_err(|_| Response::InternalError)?;
         let points = record.points.clone();
-        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
         drop(record);
 
-        let result = points.coordinate(transaction, servers, online, pending);
-        drop(points);
+        let points = points.lock().map_err(|_| Response::InternalError)?;
+        let expiring = expiration.and_then(|expiration| {
+            let oldest = points.oldest()?;
+            Some(Expiration {
+                points: oldest.points,
+                at: (oldest.earned_at + expiration.as_millis()) as u64,
+            })
+        });
+        Ok(Balance {
+            available: points.0,
+            locked: points.1,
+            expiring,
+        })
+    }
 
-        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
-        record.transaction = None;
+    /// Reads the balance of the given client from this server and all other servers.
+    /// As with transactions, at least half of the other servers must answer.
+    ///
+    /// # Returns
+    ///
+    /// The balance reported by most servers, preferring the local one on a tie.
+    pub fn quorum_balance(
+        storage: Arc<Mutex<PointStorage>>,
+        client_id: ClientId,
+    ) -> Result<Balance, Response> {
+        let lock = storage.lock().map_err(|_| Response::InternalError)?;
+        let servers = lock.get_other_servers();
+        let online = lock.online;
+        drop(lock);
+
+        if !online {
+            return Err(Response::Offline);
+        }
 
-        result
+        let local = Self::local_balance(storage, client_id)?;
+        if servers.is_empty() {
+            return Ok(local);
+        }
+
+        let balances: Vec<Balance> = servers
+            .par_iter()
+            .filter_map(|server| balance_from(server, client_id).ok())
+            .collect();
+
+        if balances.is_empty() {
+            return Err(Response::Offline);
+        }
+        if balances.len() < servers.len() / 2 {
+            return Err(Response::QuorumNotReached);
+        }
+
+        let mut votes: HashMap<Balance, usize> = HashMap::new();
+        for balance in balances {
+            *votes.entry(balance).or_default() += 1;
+        }
+        let local_votes = votes.get(&local).copied().unwrap_or(0) + 1;
+
+        let balance = votes
+            .into_iter()
+            .filter(|(_, count)| *count > local_votes)
+            .max_by_key(|(_, count)| *count)
+            .map(|(balance, _)| balance)
+            .unwrap_or(local);
+
+        Ok(balance)
+    }
+
+    /// Expires the points earned before the expiration period, if there is one.
+    /// Each client with points to expire gets an expiration transaction,
+    /// so every server drops the same points of the same batches.
+    /// Only one server sweeps each interval, so sweeps do not conflict with each other.
+    /// Failed expirations are retried by the next sweep.
+    pub fn expire_points(storage: Arc<Mutex<PointStorage>>) -> Result<(), Response> {
+        let lock = storage.lock().map_err(|_| Response::InternalError)?;
+        let expiration = match lock.expiration {
+            Some(expiration) if lock.online => expiration,
+            _ => return Ok(()),
+        };
+        let before = expiry_cutoff(generate_timestamp(), expiration);
+        if sweeper_for(&lock.servers, before) != Some(&lock.self_address) {
+            return Ok(());
+        }
+
+        let records: Vec<(ClientId, Arc<Mutex<PointRecord>>)> = lock
+            .points
+            .iter()
+            .map(|(client_id, record)| (*client_id, record.0.clone()))
+            .collect();
+        let self_address = lock.self_address.clone();
+        // Points may stay locked by a transaction for a while, the storage must not wait for them
+        drop(lock);
+
+        let mut expiring = vec![];
+        for (client_id, record) in records {
+            let points = record
+                .lock()
+                .map_err(|_| Response::InternalError)?
+                .points
+                .clone();
+            let points = points
+                .lock()
+                .map_err(|_| Response::InternalError)?
+                .expirable(before);
+            if points > 0 {
+                expiring.push((client_id, points));
+            }
+        }
+
+        for (client_id, points) in expiring {
+            let transaction =
+                Transaction::expiration(self_address.clone(), client_id, before, points);
+            match Self::coordinate_tx(transaction, storage.clone()) {
+                Ok(_) => info!("Expired {} points of client {}", points, client_id),
+                Err(e) => debug!("Could not expire points of client {}: {}", client_id, e),
+            }
+        }
+        Ok(())
     }
 
     pub fn set_on_connect(storage: Arc<Mutex<Self>>) {
@@ -311,10 +575,43 @@ impl PointStorage {
     }
 }
 
-/*#[cfg(test)]
+/// Timestamp before which points earned at the given moment have expired,
+/// rounded down to the sweep interval.
+fn expiry_cutoff(now: u128, expiration: Duration) -> u128 {
+    let interval = EXPIRY_SWEEP_INTERVAL as u128;
+    let cutoff = now.saturating_sub(expiration.as_millis());
+    cutoff - cutoff % interval
+}
+
+/// Server whose turn is to sweep the points expired at the given cutoff.
+fn sweeper_for(servers: &HashSet<String>, before: u128) -> Option<&String> {
+    let mut servers: Vec<&String> = servers.iter().collect();
+    servers.sort();
+    let turn = before / EXPIRY_SWEEP_INTERVAL as u128 % servers.len().max(1) as u128;
+    servers.get(turn as usize).copied()
+}
+
+#[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
-    fn todo() {}
-}*/
+    fn expiry_cutoff_is_shared_within_a_sweep_interval() {
+        let expiration = Duration::from_secs(10);
+        assert_eq!(expiry_cutoff(25_100, expiration), 15_000);
+        assert_eq!(expiry_cutoff(25_900, expiration), 15_000);
+        assert_eq!(expiry_cutoff(26_000, expiration), 16_000);
+        assert_eq!(expiry_cutoff(5_000, expiration), 0);
+    }
+
+    #[test]
+    fn servers_take_turns_to_sweep() {
+        let servers: HashSet<String> = ["localhost:9001", "localhost:9000"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(sweeper_for(&servers, 4_000).unwrap(), "localhost:9000");
+        assert_eq!(sweeper_for(&servers, 5_000).unwrap(), "localhost:9001");
+        assert_eq!(sweeper_for(&HashSet::new(), 5_000), None);
+    }
+}
@@ -4,7 +4,7 @@ use std::{
     time::Duration,
 };
 
-use points::{Message, OrderAction};
+use points::{ClientId, IdempotencyKey, Message, OrderAction};
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
@@ -19,6 +19,7 @@ pub enum TransactionState {
     Abort,
     Proceed,
     Timeout,
+    NoQuorum,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +28,18 @@ pub enum TransactionAction {
     Lock,
     Free,
     Consume,
+    /// Moves available points from the client of the transaction to another one.
+    Transfer {
+        to: ClientId,
+    },
+    /// Links the card of the transaction to a family account, or unlinks it if there is none.
+    Link {
+        account: Option<ClientId>,
+    },
+    /// Drops available points earned before the given timestamp.
+    Expire {
+        before: u128,
+    },
 }
 
 pub enum TxOk {
@@ -38,9 +51,15 @@ pub enum TxOk {
 pub struct Transaction {
     pub coordinator: String,
     pub timestamp: u128,
-    pub client_id: u16,
+    pub client_id: ClientId,
     pub action: TransactionAction,
     pub points: usize,
+    /// Key of the client request that started the transaction, if any.
+    #[serde(default)]
+    pub key: Option<IdempotencyKey>,
+    /// Whether the points added by the transaction expire.
+    #[serde(default)]
+    pub expires: bool,
 }
 
 impl Transaction {
@@ -49,9 +68,27 @@ impl Transaction {
     pub fn new(coordinator: String, msg: &Message) -> Result<Transaction, String> {
         let err = Err("Invalid message for transaction".to_string());
 
+        let membership = match msg {
+            Message::LinkCard(card, account) if card != account => Some((*card, Some(*account))),
+            Message::UnlinkCard(card) => Some((*card, None)),
+            _ => None,
+        };
+        if let Some((card, account)) = membership {
+            debug!(
+                "Transaction request is LINK card {} to account {:?}.",
+                card, account
+            );
+            return Ok(Self::create(
+                coordinator,
+                card,
+                TransactionAction::Link { account },
+                0,
+            ));
+        }
+
         let action = match msg {
             Message::LockOrder(order) => match order.action {
-                OrderAction::FillPoints(_) => err,
+                OrderAction::FillPoints(_) | OrderAction::Transfer { .. } => err,
                 OrderAction::UsePoints(_) => {
                     debug!(
                         "Transaction request is LOCK POINTS {} for client id {}.",
@@ -62,7 +99,7 @@ impl Transaction {
                 }
             },
             Message::FreeOrder(order) => match order.action {
-                OrderAction::FillPoints(_) => err,
+                OrderAction::FillPoints(_) | OrderAction::Transfer { .. } => err,
                 OrderAction::UsePoints(_) => {
                     debug!(
                         "Transaction request is FREE POINTS {} for client id {}.",
@@ -89,25 +126,92 @@ impl Transaction {
                     );
                     Ok(TransactionAction::Consume)
                 }
+                OrderAction::Transfer { to, .. } if to == order.client_id => err,
+                OrderAction::Transfer { to, .. } => {
+                    debug!(
+                        "Transaction request is TRANSFER POINTS {} from client id {} to client id {}.",
+                        order.action.points(),
+                        order.client_id,
+                        to
+                    );
+                    Ok(TransactionAction::Transfer { to })
+                }
             },
+            Message::QueryBalance(..) | Message::LinkCard(..) | Message::UnlinkCard(_) => err,
         }?;
 
-        let order = msg.order();
-        let client_id = order.client_id;
-        let points = order.action.points();
+        let order = msg.order().ok_or("Invalid message for transaction")?;
+        Ok(Self::create(
+            coordinator,
+            order.client_id,
+            action,
+            order.action.points(),
+        ))
+    }
+
+    /// Creates a transaction that expires the given points of the client,
+    /// from the ones earned before the cutoff timestamp.
+    pub fn expiration(
+        coordinator: String,
+        client_id: ClientId,
+        before: u128,
+        points: usize,
+    ) -> Transaction {
+        debug!(
+            "Transaction request is EXPIRE POINTS {} earned before {} for client id {}.",
+            points, before, client_id
+        );
+        Self::create(
+            coordinator,
+            client_id,
+            TransactionAction::Expire { before },
+            points,
+        )
+    }
 
+    fn create(
+        coordinator: String,
+        client_id: ClientId,
+        action: TransactionAction,
+        points: usize,
+    ) -> Transaction {
         let timestamp = generate_timestamp();
         debug!(
             "Coordinator '{}' creating new transaction with timestamp {}.",
             coordinator, timestamp
         );
-        Ok(Transaction {
+        Transaction {
             coordinator,
             timestamp,
             client_id,
             action,
             points,
-        })
+            key: None,
+            expires: false,
+        }
+    }
+
+    /// Returns the clients whose points are changed by the transaction, in ascending order.
+    /// Records must be locked in this order so two transfers can not deadlock.
+    pub fn client_ids(&self) -> Vec<ClientId> {
+        match self.action {
+            TransactionAction::Transfer { to } => {
+                vec![self.client_id.min(to), self.client_id.max(to)]
+            }
+            _ => vec![self.client_id],
+        }
+    }
+
+    /// Sets the key of the client request that started the transaction.
+    pub fn with_key(mut self, key: Option<IdempotencyKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets whether the points added by the transaction expire.
+    pub fn with_expiration(mut self, expires: bool) -> Self {
+        self.expires = expires;
+        self
     }
 
     /// Compares the given transaction's timestamp with this transaction's timestamp.
@@ -146,18 +250,24 @@ impl Transaction {
     /// Sends a transaction state message to the given stream.
     pub fn finalize(stream: &mut TcpStream, state: TransactionState) -> Result<(), String> {
         let addr = stream.local_addr().unwrap();
-        match state {
-            TransactionState::Abort => debug!("Sending message ABORT through socket {}", addr),
-            TransactionState::Proceed => debug!("Sending message COMMIT through socket {}", addr),
+        let state = match state {
+            TransactionState::Abort | TransactionState::NoQuorum => {
+                debug!("Sending message ABORT through socket {}", addr);
+                TransactionState::Abort
+            }
+            TransactionState::Proceed => {
+                debug!("Sending message COMMIT through socket {}", addr);
+                TransactionState::Proceed
+            }
             TransactionState::Timeout => todo!(),
             TransactionState::Disconnected => todo!(),
-        }
+        };
 
         stream.write_all(&[state as u8]).map_err(|e| e.to_string())
     }
 }
 
-fn generate_timestamp() -> u128 {
+pub fn generate_timestamp() -> u128 {
     use std::time::{SystemTime, UNIX_EPOCH};
     let now = SystemTime::now();
     let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
@@ -180,7 +290,34 @@ mod tests {
         let other_transaction =
             Transaction::new("127.0.0.1:9002".to_string(), &other_message).unwrap();
 
-        assert_eq!(true, transaction.older_than(&other_transaction));
+        assert!(transaction.older_than(&other_transaction));
+    }
+
+    #[test]
+    fn test_transfer_client_ids() {
+        let order = Order::new(5, OrderAction::Transfer { to: 2, points: 10 });
+        let transaction =
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::CommitOrder(order)).unwrap();
+        assert_eq!(transaction.client_ids(), vec![2, 5]);
+
+        let order = Order::new(5, OrderAction::Transfer { to: 5, points: 10 });
+        assert!(
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::CommitOrder(order)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_link_transaction() {
+        let message = Message::LinkCard(2, 1);
+        let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        assert_eq!(transaction.client_ids(), vec![2]);
+        assert!(matches!(
+            transaction.action,
+            TransactionAction::Link { account: Some(1) }
+        ));
+
+        let message = Message::LinkCard(2, 2);
+        assert!(Transaction::new("127.0.0.1:9001".to_string(), &message).is_err());
     }
 
     #[test]